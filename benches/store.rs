@@ -0,0 +1,126 @@
+//! Grid state throughput benchmark
+//!
+//! Compares the sharded `Store` against the `RwLock<Vec<_>>` layout that
+//! `AppState` used previously, with several threads issuing a mix of reads
+//! and writes against a pre-populated board.
+//!
+//! Run with `cargo bench --bench store`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use omni_gate_rs::store::Store;
+use std::convert::Infallible;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+const THREADS: usize = 8;
+const OPS_PER_THREAD: u64 = 2_000;
+const PREFILL: u64 = 1_000;
+
+#[derive(Clone)]
+struct Item {
+    id: u64,
+    x: i32,
+    y: i32,
+}
+
+/// Common surface of both layouts so the workload code is shared
+trait Board: Sync {
+    fn read(&self, id: u64) -> Option<i32>;
+    fn write(&self, id: u64);
+}
+
+/// The previous `AppState` layout: one lock around a vector, linear lookups
+struct LockedVec(RwLock<Vec<Item>>);
+
+impl Board for LockedVec {
+    fn read(&self, id: u64) -> Option<i32> {
+        let items = self.0.read().unwrap();
+        items.iter().find(|item| item.id == id).map(|item| item.x)
+    }
+
+    fn write(&self, id: u64) {
+        let mut items = self.0.write().unwrap();
+        if let Some(item) = items.iter_mut().find(|item| item.id == id) {
+            item.y += 1;
+        }
+    }
+}
+
+impl Board for Store<Item> {
+    fn read(&self, id: u64) -> Option<i32> {
+        self.get(id).map(|item| item.x)
+    }
+
+    fn write(&self, id: u64) {
+        self.update(id, |item| {
+            item.y += 1;
+            Ok::<_, Infallible>(())
+        });
+    }
+}
+
+fn locked_vec() -> LockedVec {
+    LockedVec(RwLock::new(
+        (1..=PREFILL).map(|id| Item { id, x: 0, y: 0 }).collect(),
+    ))
+}
+
+fn store() -> Store<Item> {
+    let store = Store::new();
+    for _ in 0..PREFILL {
+        store
+            .insert_with(|id| Ok::<_, Infallible>(Item { id, x: 0, y: 0 }))
+            .unwrap();
+    }
+    store
+}
+
+/// Run `THREADS` workers where one operation in `write_every` is a write
+fn mixed_load(board: &impl Board, write_every: u64) -> Duration {
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        for thread in 0..THREADS as u64 {
+            scope.spawn(move || {
+                for op in 0..OPS_PER_THREAD {
+                    let id = (thread * 7919 + op * 104_729) % PREFILL + 1;
+                    if op % write_every == 0 {
+                        board.write(id);
+                    } else {
+                        std::hint::black_box(board.read(id));
+                    }
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn bench_mixed_load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("grid_state");
+    group.throughput(Throughput::Elements(THREADS as u64 * OPS_PER_THREAD));
+
+    for (label, write_every) in [("read_heavy", 20), ("balanced", 2)] {
+        let baseline = locked_vec();
+        group.bench_with_input(
+            BenchmarkId::new("rwlock_vec", label),
+            &write_every,
+            |b, &write_every| {
+                b.iter_custom(|iters| (0..iters).map(|_| mixed_load(&baseline, write_every)).sum())
+            },
+        );
+
+        let sharded = store();
+        group.bench_with_input(
+            BenchmarkId::new("sharded_store", label),
+            &write_every,
+            |b, &write_every| {
+                b.iter_custom(|iters| (0..iters).map(|_| mixed_load(&sharded, write_every)).sum())
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_mixed_load);
+criterion_main!(benches);
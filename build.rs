@@ -1,7 +1,76 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_prost_build::configure().compile_protos(
-        &["protos/helloworld.proto", "protos/user.proto"],
-        &["protos/"],
-    )?;
+    tonic_prost_build::configure()
+        .type_attribute(
+            "user.User",
+            "#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)] #[serde(default)]",
+        )
+        .type_attribute(
+            "user.CreateUserRequest",
+            "#[derive(serde::Deserialize, utoipa::ToSchema)] #[serde(default)]",
+        )
+        .type_attribute(
+            "user.ListUsersRequest",
+            "#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)] #[serde(default)]",
+        )
+        .type_attribute(
+            "user.UserFilter",
+            "#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)] #[serde(default)]",
+        )
+        .type_attribute(
+            "user.ListUsersResponse",
+            "#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)] #[serde(default)]",
+        )
+        .type_attribute(
+            "user.UserUpdate",
+            "#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)] #[serde(default)]",
+        )
+        .type_attribute(
+            "user.TokenResponse",
+            "#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)] #[serde(default)]",
+        )
+        .type_attribute(
+            "user.SetPasswordResponse",
+            "#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)] #[serde(default)]",
+        )
+        .type_attribute(
+            "user.UnlockLoginResponse",
+            "#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)] #[serde(default)]",
+        )
+        .type_attribute(
+            "user.Session",
+            "#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)] #[serde(default)]",
+        )
+        .type_attribute(
+            "user.ListSessionsResponse",
+            "#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)] #[serde(default)]",
+        )
+        .type_attribute(
+            "user.RevokeSessionsResponse",
+            "#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)] #[serde(default)]",
+        )
+        .type_attribute(
+            "user.ApiKey",
+            "#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)] #[serde(default)]",
+        )
+        .type_attribute(
+            "user.ApiKeySecret",
+            "#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)] #[serde(default)]",
+        )
+        .type_attribute(
+            "user.ListApiKeysResponse",
+            "#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)] #[serde(default)]",
+        )
+        .type_attribute(
+            "user.RevokeApiKeyResponse",
+            "#[derive(serde::Serialize, serde::Deserialize, utoipa::ToSchema)] #[serde(default)]",
+        )
+        .compile_protos(
+            &[
+                "protos/helloworld.proto",
+                "protos/user.proto",
+                "protos/grid.proto",
+            ],
+            &["protos/"],
+        )?;
     Ok(())
 }
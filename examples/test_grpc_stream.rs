@@ -1,6 +1,7 @@
 use omni_gate_rs::protos::user::user_service_client::UserServiceClient;
 use omni_gate_rs::protos::user::SubscribeRequest;
 use tonic::transport::Channel;
+use tonic::Request;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -9,10 +10,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("Testing gRPC streaming subscription...");
 
-    let request = SubscribeRequest {
+    let mut request = Request::new(SubscribeRequest {
         user_id: 1,
         interval_seconds: 2,
-    };
+    });
+    // Subscribing needs an access token from Login
+    if let Ok(token) = std::env::var("ACCESS_TOKEN") {
+        request
+            .metadata_mut()
+            .insert("authorization", format!("Bearer {token}").parse()?);
+    }
 
     let mut stream = client.subscribe_user_updates(request).await?.into_inner();
 
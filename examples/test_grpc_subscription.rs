@@ -13,10 +13,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut client = UserServiceClient::connect("http://[::1]:5000").await?;
 
     println!("Subscribing to user updates via gRPC streaming...");
-    let request = Request::new(SubscribeRequest {
+    let mut request = Request::new(SubscribeRequest {
         user_id: 1,
         interval_seconds: 2,
     });
+    // Subscribing needs an access token from Login
+    if let Ok(token) = std::env::var("ACCESS_TOKEN") {
+        request
+            .metadata_mut()
+            .insert("authorization", format!("Bearer {token}").parse()?);
+    }
 
     let mut stream = client.subscribe_user_updates(request).await?.into_inner();
 
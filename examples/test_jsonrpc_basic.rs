@@ -1,31 +1,42 @@
-use jsonrpsee::core::client::ClientT;
+use jsonrpsee::http_client::HeaderMap;
 use jsonrpsee::ws_client::WsClientBuilder;
-use serde_json::json;
+use omni_gate_rs::rpc_api::{UpdateUserInfoParams, UserRpcClient, VerifyCredentialsParams};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let url = "ws://127.0.0.1:4000";
-    let client = WsClientBuilder::default().build(url).await?;
+    // Methods other than the login ones need an access token from verify_credentials
+    let mut headers = HeaderMap::new();
+    if let Ok(token) = std::env::var("ACCESS_TOKEN") {
+        headers.insert("authorization", format!("Bearer {token}").parse()?);
+    }
+    let client = WsClientBuilder::default()
+        .set_headers(headers)
+        .build(url)
+        .await?;
 
     println!("Testing JSON-RPC methods...");
 
-    let result: serde_json::Value = client.request("get_user_info", ((),)).await?;
-    println!("get_user_info result: {}", result);
+    let result = client.get_user_info().await?;
+    println!("get_user_info result: {:?}", result);
 
-    let params = json!({
-        "name": "Test User",
-        "email": "test@example.com",
-        "age": 25
-    });
-    let result: serde_json::Value = client.request("update_user_info", (params,)).await?;
-    println!("update_user_info result: {}", result);
+    let params = UpdateUserInfoParams {
+        user_id: 1,
+        name: Some("Test User".to_string()),
+        age: Some(25),
+        ..Default::default()
+    };
+    // Needs the users:write permission, which the default user role lacks
+    let result = client.update_user_info(params).await;
+    println!("update_user_info result: {:?}", result);
 
-    let params = json!({
-        "username": "testuser",
-        "password": "testpass"
-    });
-    let result: serde_json::Value = client.request("verify_credentials", (params,)).await?;
-    println!("verify_credentials result: {}", result);
+    // Unknown credentials are rejected with an Unauthorized (1003) error
+    let params = VerifyCredentialsParams {
+        username: "test@example.com".to_string(),
+        password: "testpass".to_string(),
+    };
+    let result = client.verify_credentials(params).await;
+    println!("verify_credentials result: {:?}", result);
 
     println!("Test completed!");
     Ok(())
@@ -1,33 +1,34 @@
-use jsonrpsee::core::client::SubscriptionClientT;
+use jsonrpsee::http_client::HeaderMap;
 use jsonrpsee::ws_client::WsClientBuilder;
-use serde_json::json;
+use omni_gate_rs::rpc_api::{SubscribeUserUpdatesParams, UserRpcClient};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let url = "ws://127.0.0.1:4000";
-    let client = WsClientBuilder::default().build(url).await?;
+    // Methods other than the login ones need an access token from verify_credentials
+    let mut headers = HeaderMap::new();
+    if let Ok(token) = std::env::var("ACCESS_TOKEN") {
+        headers.insert("authorization", format!("Bearer {token}").parse()?);
+    }
+    let client = WsClientBuilder::default()
+        .set_headers(headers)
+        .build(url)
+        .await?;
 
     println!("Testing JSON-RPC subscription...");
 
-    let params = json!({
-        "user_id": 1,
-        "interval_seconds": 2
-    });
-
-    let mut sub: jsonrpsee::core::client::Subscription<serde_json::Value> = client
-        .subscribe(
-            "subscribe_user_updates",
-            (params,),
-            "unsubscribe_user_updates",
-        )
-        .await?;
+    let params = SubscribeUserUpdatesParams {
+        user_id: 1,
+        interval_seconds: Some(2),
+    };
+    let mut sub = client.subscribe_user_updates(Some(params)).await?;
 
     println!("Receiving updates:");
     let mut count = 0;
     while let Some(update) = sub.next().await {
         count += 1;
         match update {
-            Ok(val) => println!("[{}] Update: {}", count, val),
+            Ok(update) => println!("[{}] Update: {:?}", count, update),
             Err(e) => println!("[{}] Error: {}", count, e),
         }
         if count >= 5 {
@@ -6,7 +6,13 @@ use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let url = "ws://127.0.0.1:4000";
-    let request = url.into_client_request()?;
+    let mut request = url.into_client_request()?;
+    // Subscribing needs an access token from verify_credentials
+    if let Ok(token) = std::env::var("ACCESS_TOKEN") {
+        request
+            .headers_mut()
+            .insert("authorization", format!("Bearer {token}").parse()?);
+    }
 
     println!("Connecting to JSON-RPC WebSocket server...");
     let (ws_stream, _) = connect_async(request).await?;
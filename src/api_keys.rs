@@ -0,0 +1,312 @@
+//! API key module
+//!
+//! API keys let service callers such as batch jobs authenticate without a
+//! user's password. A key acts for the user it was issued to, limited to its
+//! scopes, and resolves to the same `Principal` as that user's access tokens.
+//!
+//! Keys look like `ogk_<id>_<secret>`. Only a SHA-256 hash of the whole key is
+//! stored: the secret is random, so a slow password hash would add nothing but
+//! latency to every request. Keys are journaled and survive restarts.
+//!
+//! Like sessions, keys hand out `Revocation` futures, resolving once the key is
+//! rotated or revoked, so connections opened with it close at the same time.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use crate::errors::{AppError, ErrorKind};
+use crate::journal::{Journal, Mutation};
+use crate::protos::user::{ApiKey, ApiKeySecret};
+use crate::sessions::Revocation;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Prefix of every API key, making leaked keys easy to scan for
+pub const KEY_PREFIX: &str = "ogk_";
+
+/// An API key as persisted: its record and the hash of the key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredApiKey {
+    pub api_key: ApiKey,
+    /// Hex-encoded SHA-256 of the full key
+    pub key_hash: String,
+}
+
+/// API keys by id
+#[derive(Clone)]
+pub struct ApiKeyStore {
+    keys: Arc<DashMap<String, StoredApiKey>>,
+    /// Senders behind each key's revocations; dropping one revokes the key's
+    /// current secret
+    revocations: Arc<DashMap<String, watch::Sender<bool>>>,
+    journal: Journal,
+}
+
+impl std::fmt::Debug for ApiKeyStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiKeyStore")
+            .field("keys", &self.keys.len())
+            .finish_non_exhaustive()
+    }
+}
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn key_hash(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Compare two hashes in time independent of where they differ
+fn hashes_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+/// Id of the key in `ogk_<id>_<secret>`
+fn key_id(key: &str) -> Option<&str> {
+    let (id, secret) = key.strip_prefix(KEY_PREFIX)?.split_once('_')?;
+    (!id.is_empty() && !secret.is_empty()).then_some(id)
+}
+
+/// Give `stored` a fresh secret, returning the key that goes with it
+fn new_secret(stored: &mut StoredApiKey) -> String {
+    let key = format!("{KEY_PREFIX}{}_{}", stored.api_key.id, random_hex(32));
+    stored.key_hash = key_hash(&key);
+    key
+}
+
+impl ApiKeyStore {
+    pub fn new(journal: Journal) -> Self {
+        Self {
+            keys: Arc::default(),
+            revocations: Arc::default(),
+            journal,
+        }
+    }
+
+    /// Load keys recovered from the journal
+    pub fn restore(&self, keys: Vec<StoredApiKey>) {
+        for stored in keys {
+            self.keys.insert(stored.api_key.id.clone(), stored);
+        }
+    }
+
+    /// Copy of every key, for journal compaction
+    pub fn snapshot(&self) -> Vec<StoredApiKey> {
+        let mut keys: Vec<_> = self.keys.iter().map(|entry| entry.clone()).collect();
+        keys.sort_unstable_by(|a, b| a.api_key.id.cmp(&b.api_key.id));
+        keys
+    }
+
+    /// Issue a key for `user_id`, returning it alongside its record
+    pub fn create(
+        &self,
+        user_id: i32,
+        name: &str,
+        scopes: Vec<String>,
+    ) -> Result<ApiKeySecret, AppError> {
+        let mut stored = StoredApiKey {
+            api_key: ApiKey {
+                id: random_hex(8),
+                user_id,
+                name: name.to_string(),
+                scopes,
+                created_at: unix_now(),
+                ..Default::default()
+            },
+            key_hash: String::new(),
+        };
+        let key = new_secret(&mut stored);
+        match self.keys.entry(stored.api_key.id.clone()) {
+            // 64 random bits; a clash is not worth retrying
+            Entry::Occupied(_) => Err(ErrorKind::InternalError.into()),
+            Entry::Vacant(entry) => {
+                self.journal.record(Mutation::ApiKeyPut(stored.clone()))?;
+                let api_key = entry.insert(stored).api_key.clone();
+                Ok(ApiKeySecret {
+                    api_key: Some(api_key),
+                    key,
+                })
+            }
+        }
+    }
+
+    pub fn get(&self, key_id: &str) -> Option<ApiKey> {
+        self.keys.get(key_id).map(|stored| stored.api_key.clone())
+    }
+
+    /// Keys of a user, oldest first
+    pub fn list(&self, user_id: i32) -> Vec<ApiKey> {
+        let mut keys: Vec<_> = self
+            .keys
+            .iter()
+            .filter(|stored| stored.api_key.user_id == user_id)
+            .map(|stored| stored.api_key.clone())
+            .collect();
+        keys.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        keys
+    }
+
+    /// Replace the secret of a key; the old key stops working at once
+    pub fn rotate(&self, key_id: &str) -> Result<ApiKeySecret, AppError> {
+        let mut stored = self.keys.get_mut(key_id).ok_or(ErrorKind::NotFound)?;
+        let mut rotated = stored.clone();
+        let key = new_secret(&mut rotated);
+        rotated.api_key.rotated_at = unix_now();
+        self.journal.record(Mutation::ApiKeyPut(rotated.clone()))?;
+        *stored = rotated;
+        self.revocations.remove(key_id);
+        Ok(ApiKeySecret {
+            api_key: Some(stored.api_key.clone()),
+            key,
+        })
+    }
+
+    /// Delete a key, returning its record
+    pub fn revoke(&self, key_id: &str) -> Result<ApiKey, AppError> {
+        match self.keys.entry(key_id.to_string()) {
+            Entry::Vacant(_) => Err(ErrorKind::NotFound.into()),
+            Entry::Occupied(entry) => {
+                self.journal
+                    .record(Mutation::ApiKeyDelete(key_id.to_string()))?;
+                let api_key = entry.remove().api_key;
+                self.revocations.remove(key_id);
+                Ok(api_key)
+            }
+        }
+    }
+
+    /// Delete every key of a user, returning how many there were
+    pub fn revoke_user(&self, user_id: i32) -> usize {
+        self.list(user_id)
+            .iter()
+            .filter(|api_key| {
+                self.revoke(&api_key.id)
+                    .inspect_err(|_| tracing::error!("Failed to revoke API key {}", api_key.id))
+                    .is_ok()
+            })
+            .count()
+    }
+
+    /// Future resolving when the key is rotated or revoked; at once if it
+    /// already has been revoked
+    pub fn revocation(&self, key_id: &str) -> Revocation {
+        let receiver = self
+            .revocations
+            .entry(key_id.to_string())
+            .or_insert_with(|| watch::channel(false).0)
+            .subscribe();
+        // Checked after subscribing, so a concurrent `revoke` is not missed
+        if !self.keys.contains_key(key_id) {
+            self.revocations.remove(key_id);
+        }
+        Revocation::watching(receiver)
+    }
+
+    /// Record of the key presented by a caller, noting that it was used
+    pub fn verify(&self, key: &str) -> Option<ApiKey> {
+        let mut stored = self.keys.get_mut(key_id(key)?)?;
+        if !hashes_match(&stored.key_hash, &key_hash(key)) {
+            return None;
+        }
+        // Kept in memory; compaction persists it
+        stored.api_key.last_used_at = unix_now();
+        Some(stored.api_key.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_verify_until_rotated_or_revoked() {
+        let store = ApiKeyStore::new(Journal::default());
+        let issued = store
+            .create(1, "nightly export", vec!["users:read".to_string()])
+            .unwrap();
+        let api_key = issued.api_key.unwrap();
+        assert!(issued.key.starts_with(KEY_PREFIX));
+        assert_ne!(store.snapshot()[0].key_hash, issued.key);
+
+        let verified = store.verify(&issued.key).unwrap();
+        assert_eq!(verified.user_id, 1);
+        assert!(verified.last_used_at > 0);
+        let forged = format!("{KEY_PREFIX}{}_{}", api_key.id, "0".repeat(64));
+        for key in [forged.as_str(), "ogk_", "garbage", ""] {
+            assert_eq!(store.verify(key), None);
+        }
+
+        let rotated = store.rotate(&api_key.id).unwrap();
+        assert_eq!(store.verify(&issued.key), None);
+        assert_eq!(store.verify(&rotated.key).unwrap().id, api_key.id);
+
+        store.create(1, "backup", Vec::new()).unwrap();
+        store.create(2, "other", Vec::new()).unwrap();
+        assert_eq!(store.list(1).len(), 2);
+        store.revoke(&api_key.id).unwrap();
+        assert_eq!(store.verify(&rotated.key), None);
+        assert!(matches!(
+            store.revoke(&api_key.id).map_err(AppError::into_kind),
+            Err(ErrorKind::NotFound)
+        ));
+        assert_eq!(store.revoke_user(1), 1);
+        assert_eq!(store.list(2).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rotation_and_revocation_wake_waiters() {
+        use std::time::Duration;
+
+        let store = ApiKeyStore::new(Journal::default());
+        let issued = store.create(1, "stream", Vec::new()).unwrap();
+        let key_id = issued.api_key.unwrap().id;
+
+        let waiter = tokio::spawn(store.revocation(&key_id).revoked());
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+        store.rotate(&key_id).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("rotation resolves the revocation")
+            .unwrap();
+
+        // The new secret gets a revocation of its own
+        let waiter = tokio::spawn(store.revocation(&key_id).revoked());
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+        assert_eq!(store.revoke_user(1), 1);
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("revocation resolves")
+            .unwrap();
+
+        // Waiting on a key that is already gone returns at once
+        tokio::time::timeout(Duration::from_secs(1), store.revocation(&key_id).revoked())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,511 @@
+//! Authentication module
+//!
+//! Identifies the caller behind a request. Callers present an access token
+//! from `verify_credentials` (JSON-RPC) or `Login` (gRPC) as
+//! `Authorization: Bearer <token>`; the token's subject is the user id. The
+//! token's session must still be live, and each request is recorded on it.
+//!
+//! Service callers present an API key as `X-Api-Key: <key>` instead. A key
+//! acts for the user it was issued to, with that user's permissions narrowed
+//! to the key's scopes.
+//!
+//! Deployments behind an authentication proxy can instead trust the identity
+//! it asserts through the `X-Auth-Subject` and `X-Auth-Groups` headers by
+//! enabling `auth.trust_identity_headers`. Those headers are ignored otherwise.
+//!
+//! The same headers authenticate JSON-RPC requests and, as metadata, gRPC calls.
+//!
+//! A token holder's roles and permissions are read from their user record on
+//! every request, so changes apply at once; a proxy-asserted caller's groups
+//! are their roles. Subjects listed in `auth.admin_subjects` get the `admin`
+//! role. What the roles allow is decided in `rbac`.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use crate::api_keys::ApiKeyStore;
+use crate::config::AuthConfig;
+use crate::errors::{AppError, ErrorKind, FieldViolation};
+use crate::handlers::users::UserDirectory;
+use crate::rbac::{Permission, Roles};
+use crate::sessions::{ClientInfo, Protocol, Revocation, SessionStore};
+use crate::tokens::{TokenService, TokenUse};
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, OptionalFromRequestParts, Request, State},
+    http::{header::AUTHORIZATION, request::Parts, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use std::collections::{BTreeSet, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Header carrying an API key
+pub const API_KEY_HEADER: &str = "x-api-key";
+/// Header carrying the authenticated user name
+pub const SUBJECT_HEADER: &str = "x-auth-subject";
+/// Header carrying the comma-separated groups (roles) of the authenticated user
+pub const GROUPS_HEADER: &str = "x-auth-groups";
+/// Role given to the subjects in `auth.admin_subjects`
+pub const ADMIN_ROLE: &str = "admin";
+
+/// Authenticated caller
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    pub subject: String,
+    /// Roles held, `rbac.default_roles` included
+    pub roles: Vec<String>,
+    /// Permissions granted by the roles or directly
+    pub permissions: BTreeSet<Permission>,
+    /// Login session of a token holder; proxy-asserted callers have none
+    pub session_id: Option<String>,
+    /// API key the caller presented, if any
+    pub api_key_id: Option<String>,
+}
+
+impl Principal {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+
+    pub fn can(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    /// Id of the user behind a token or API key; `None` for proxy-asserted callers
+    pub fn user_id(&self) -> Option<i32> {
+        if self.session_id.is_none() && self.api_key_id.is_none() {
+            return None;
+        }
+        self.subject.parse().ok()
+    }
+
+    /// Future resolving once the session or API key the caller presented is
+    /// revoked; long-lived streams select on it to close promptly
+    pub fn revocation(&self, sessions: &SessionStore, api_keys: &ApiKeyStore) -> Revocation {
+        match (&self.session_id, &self.api_key_id) {
+            (Some(session_id), _) => sessions.revocation(session_id),
+            (None, Some(key_id)) => api_keys.revocation(key_id),
+            (None, None) => Revocation::never(),
+        }
+    }
+
+    /// User a call acts on: `user_id`, or the caller's own when left out
+    ///
+    /// Acting on another user takes `users:admin`.
+    pub fn target_user(&self, user_id: Option<i32>) -> Result<i32, AppError> {
+        let own = self.user_id();
+        match user_id {
+            Some(user_id) if Some(user_id) == own => Ok(user_id),
+            Some(user_id) => {
+                Principal::require(Some(self), Permission::UsersAdmin).map(|_| user_id)
+            }
+            // Proxy-asserted callers have no user of their own
+            None => own.ok_or_else(|| {
+                ErrorKind::InvalidFields(vec![FieldViolation::new(
+                    "user_id",
+                    "is required for callers without a user of their own",
+                )])
+                .into()
+            }),
+        }
+    }
+
+    /// Require an authenticated caller holding `permission`
+    pub fn require(
+        principal: Option<&Principal>,
+        permission: Permission,
+    ) -> Result<&Principal, AppError> {
+        let principal = principal.ok_or(ErrorKind::Unauthorized)?;
+        if !principal.can(permission) {
+            return Err(ErrorKind::Forbidden.into());
+        }
+        Ok(principal)
+    }
+}
+
+/// A caller as identified, before roles are resolved
+struct Identity {
+    principal: Principal,
+    /// Permissions granted to the user directly
+    granted: Vec<String>,
+    /// Permissions an API key is limited to; `None` is unlimited
+    scopes: Option<BTreeSet<Permission>>,
+}
+
+/// Resolves the caller of a request from its headers
+#[derive(Debug, Clone)]
+pub struct Authenticator {
+    tokens: TokenService,
+    sessions: SessionStore,
+    api_keys: ApiKeyStore,
+    directory: UserDirectory,
+    roles: Roles,
+    trust_identity_headers: bool,
+    admin_subjects: Arc<HashSet<String>>,
+}
+
+impl Authenticator {
+    pub fn new(
+        tokens: TokenService,
+        sessions: SessionStore,
+        api_keys: ApiKeyStore,
+        directory: UserDirectory,
+        roles: Roles,
+        config: &AuthConfig,
+    ) -> Self {
+        Self {
+            tokens,
+            sessions,
+            api_keys,
+            directory,
+            roles,
+            trust_identity_headers: config.trust_identity_headers,
+            admin_subjects: Arc::new(config.admin_subjects.iter().cloned().collect()),
+        }
+    }
+
+    /// Identify the caller, or `None` for an anonymous request
+    ///
+    /// A bearer token that does not verify, or whose session has ended, is an
+    /// error rather than anonymous, and so is an unknown API key.
+    pub fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        client: &ClientInfo,
+    ) -> Result<Option<Principal>, AppError> {
+        let Some(Identity {
+            mut principal,
+            granted,
+            scopes,
+        }) = self.identify(headers, client)?
+        else {
+            return Ok(None);
+        };
+        if self.admin_subjects.contains(&principal.subject) && !principal.has_role(ADMIN_ROLE) {
+            principal.roles.push(ADMIN_ROLE.to_string());
+        }
+        principal.roles = self.roles.with_defaults(principal.roles);
+        principal.permissions = self.roles.permissions(&principal.roles, &granted);
+        if let Some(scopes) = scopes {
+            principal
+                .permissions
+                .retain(|permission| scopes.contains(permission));
+        }
+        Ok(Some(principal))
+    }
+
+    pub fn sessions(&self) -> &SessionStore {
+        &self.sessions
+    }
+
+    pub fn api_keys(&self) -> &ApiKeyStore {
+        &self.api_keys
+    }
+
+    fn identify(
+        &self,
+        headers: &HeaderMap,
+        client: &ClientInfo,
+    ) -> Result<Option<Identity>, AppError> {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .unwrap_or("")
+        };
+
+        let authorization = header(AUTHORIZATION.as_str());
+        if !authorization.is_empty() {
+            // Auth schemes are case-insensitive (RFC 9110, section 11.1)
+            let (_, token) = authorization
+                .split_once(' ')
+                .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+                .ok_or(ErrorKind::Unauthorized)?;
+            let claims = self.tokens.verify(token.trim(), TokenUse::Access)?;
+            self.sessions.touch(&claims.sid, client)?;
+            let user = claims
+                .sub
+                .parse()
+                .ok()
+                .and_then(|user_id| self.directory.get(user_id).ok())
+                .ok_or(ErrorKind::Unauthorized)?;
+            let principal = Principal {
+                subject: claims.sub,
+                roles: user.roles,
+                permissions: BTreeSet::new(),
+                session_id: Some(claims.sid),
+                api_key_id: None,
+            };
+            return Ok(Some(Identity {
+                principal,
+                granted: user.permissions,
+                scopes: None,
+            }));
+        }
+
+        let api_key = header(API_KEY_HEADER);
+        if !api_key.is_empty() {
+            let api_key = self
+                .api_keys
+                .verify(api_key)
+                .ok_or(ErrorKind::Unauthorized)?;
+            let user = self
+                .directory
+                .get(api_key.user_id)
+                .map_err(|_| ErrorKind::Unauthorized)?;
+            let scopes = (!api_key.scopes.iter().any(|scope| scope == "*")).then(|| {
+                api_key
+                    .scopes
+                    .iter()
+                    .filter_map(|scope| scope.parse().ok())
+                    .collect()
+            });
+            let principal = Principal {
+                subject: user.id.to_string(),
+                roles: user.roles,
+                permissions: BTreeSet::new(),
+                session_id: None,
+                api_key_id: Some(api_key.id),
+            };
+            return Ok(Some(Identity {
+                principal,
+                granted: user.permissions,
+                scopes,
+            }));
+        }
+
+        let subject = header(SUBJECT_HEADER);
+        if !self.trust_identity_headers || subject.is_empty() {
+            return Ok(None);
+        }
+        let roles = header(GROUPS_HEADER)
+            .split(',')
+            .map(str::trim)
+            .filter(|group| !group.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        let principal = Principal {
+            subject: subject.to_string(),
+            roles,
+            permissions: BTreeSet::new(),
+            session_id: None,
+            api_key_id: None,
+        };
+        Ok(Some(Identity {
+            principal,
+            granted: Vec::new(),
+            scopes: None,
+        }))
+    }
+}
+
+/// Middleware attaching the caller's `Principal` to the request, if there is one
+pub async fn authenticate(
+    State(authenticator): State<Authenticator>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let addr = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let client = ClientInfo::new(addr, Protocol::Rest);
+    if let Some(principal) = authenticator.authenticate(request.headers(), &client)? {
+        request.extensions_mut().insert(principal);
+    }
+    Ok(next.run(request).await)
+}
+
+impl<S> FromRequestParts<S> for Principal
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Principal>()
+            .cloned()
+            .ok_or(AppError::from(ErrorKind::Unauthorized))
+    }
+}
+
+/// The caller of a route open to anonymous requests
+impl<S> OptionalFromRequestParts<S> for Principal
+where
+    S: Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        Ok(parts.extensions.get::<Principal>().cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RbacConfig;
+    use crate::journal::Journal;
+    use crate::protos::user::{CreateUserRequest, TokenResponse};
+    use axum::http::HeaderValue;
+
+    fn authenticator(trust_identity_headers: bool) -> Authenticator {
+        let config = AuthConfig {
+            jwt_secret: "test-secret".to_string(),
+            trust_identity_headers,
+            admin_subjects: vec!["1".to_string()],
+            ..AuthConfig::default()
+        };
+        let directory = UserDirectory::new(Arc::default(), Journal::default());
+        Authenticator::new(
+            TokenService::new(&config),
+            SessionStore::new(3600),
+            ApiKeyStore::new(Journal::default()),
+            directory,
+            Roles::new(&RbacConfig::default()),
+            &config,
+        )
+    }
+
+    fn client() -> ClientInfo {
+        ClientInfo::new(None, Protocol::Rest)
+    }
+
+    /// Create a user and log them in, returning the token pair
+    fn login(authenticator: &Authenticator, email: &str, permissions: &[&str]) -> TokenResponse {
+        let request = CreateUserRequest {
+            name: "Alice".to_string(),
+            email: email.to_string(),
+            age: 30,
+            permissions: permissions.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        };
+        let user = authenticator.directory.create(request, None).unwrap();
+        let session = authenticator.sessions.create(user.id, &client());
+        authenticator
+            .tokens
+            .issue(&user.id.to_string(), &session.id)
+            .unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn bearer(authenticator: &Authenticator, token: &str) -> Result<Option<Principal>, AppError> {
+        let value = format!("Bearer {token}");
+        authenticator.authenticate(&headers(&[("authorization", &value)]), &client())
+    }
+
+    #[test]
+    fn test_bearer_tokens_identify_the_user() {
+        let authenticator = authenticator(false);
+        let admin = login(&authenticator, "root@example.com", &[]);
+        let pair = login(&authenticator, "alice@example.com", &["users:write"]);
+
+        let principal = bearer(&authenticator, &pair.access_token).unwrap().unwrap();
+        assert_eq!(principal.subject, "2");
+        assert_eq!(principal.roles, ["user"]);
+        assert!(principal.can(Permission::UsersWrite));
+        assert!(Principal::require(Some(&principal), Permission::UsersAdmin).is_err());
+
+        let principal = bearer(&authenticator, &admin.access_token).unwrap();
+        assert!(Principal::require(principal.as_ref(), Permission::UsersAdmin).is_ok());
+
+        for scheme in ["bearer", "BEARER"] {
+            let value = format!("{scheme}  {}", pair.access_token);
+            let principal = authenticator
+                .authenticate(&headers(&[("authorization", &value)]), &client())
+                .unwrap();
+            assert_eq!(principal.unwrap().subject, "2");
+        }
+
+        for value in [
+            format!("Bearer {}", pair.refresh_token),
+            "Bearer garbage".to_string(),
+            format!("Basic {}", pair.access_token),
+            format!("Bearer{}", pair.access_token),
+        ] {
+            assert!(matches!(
+                authenticator
+                    .authenticate(&headers(&[("authorization", &value)]), &client())
+                    .map_err(AppError::into_kind),
+                Err(ErrorKind::Unauthorized)
+            ));
+        }
+
+        // Tokens of deleted users stop working at once
+        authenticator.directory.delete(2).unwrap();
+        assert!(bearer(&authenticator, &pair.access_token).is_err());
+    }
+
+    #[test]
+    fn test_api_keys_are_limited_to_their_scopes() {
+        let authenticator = authenticator(false);
+        login(&authenticator, "root@example.com", &[]);
+        let issue = |scopes: &[&str]| {
+            let scopes = scopes.iter().map(|s| s.to_string()).collect();
+            authenticator
+                .api_keys
+                .create(1, "batch", scopes)
+                .unwrap()
+                .key
+        };
+        let api_key =
+            |key: &str| authenticator.authenticate(&headers(&[(API_KEY_HEADER, key)]), &client());
+
+        let principal = api_key(&issue(&["users:read", "users:admin"]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(principal.subject, "1");
+        assert_eq!(principal.user_id(), Some(1));
+        assert!(principal.api_key_id.is_some());
+        assert_eq!(
+            principal.permissions,
+            BTreeSet::from([Permission::UsersRead, Permission::UsersAdmin])
+        );
+
+        let principal = api_key(&issue(&["*"])).unwrap().unwrap();
+        assert_eq!(principal.permissions.len(), Permission::ALL.len());
+
+        assert!(matches!(
+            api_key("ogk_nope_nope").map_err(AppError::into_kind),
+            Err(ErrorKind::Unauthorized)
+        ));
+        authenticator.directory.delete(1).unwrap();
+        assert!(api_key(&issue(&["*"])).is_err());
+    }
+
+    #[test]
+    fn test_identity_headers_are_only_trusted_when_enabled() {
+        let request = headers(&[(SUBJECT_HEADER, "alice"), (GROUPS_HEADER, "ops, admin,")]);
+
+        assert_eq!(
+            authenticator(false)
+                .authenticate(&request, &client())
+                .unwrap(),
+            None
+        );
+
+        let principal = authenticator(true)
+            .authenticate(&request, &client())
+            .unwrap()
+            .unwrap();
+        assert_eq!(principal.subject, "alice");
+        assert_eq!(principal.roles, ["ops", "admin", "user"]);
+        assert!(principal.can(Permission::UsersAdmin));
+    }
+}
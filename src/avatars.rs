@@ -0,0 +1,254 @@
+//! User avatar module
+//!
+//! Uploaded pictures are stored on disk once per distinct content, named by
+//! the SHA-256 of their bytes, so re-uploads and users sharing a picture cost
+//! nothing extra. A user points at their picture through `avatar_url`, whose
+//! `v` parameter carries the hash; the URL thus changes with the picture and
+//! can be cached indefinitely. A picture nobody points at any more is deleted
+//! once it has been replaced.
+//!
+//! Only PNG, JPEG and WebP are accepted, recognized by their leading bytes
+//! rather than the content type the client claims.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use crate::config::AvatarConfig;
+use crate::errors::{AppError, ErrorKind};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard};
+
+/// Numbers the files being written, so concurrent uploads never share one
+static PARTIALS: AtomicU64 = AtomicU64::new(0);
+
+/// Accepted picture formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl ImageFormat {
+    /// Recognize a picture by its signature
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Self::Png)
+        } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+            Some(Self::Jpeg)
+        } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+            Some(Self::Webp)
+        } else {
+            None
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::Webp => "image/webp",
+        }
+    }
+}
+
+/// `avatar_url` of a user whose picture hashes to `digest`
+pub fn avatar_url(user_id: i32, digest: &str) -> String {
+    format!("/users/{user_id}/avatar?v={digest}")
+}
+
+/// Hash of the picture an `avatar_url` points at
+pub fn digest_of(avatar_url: &str) -> Option<&str> {
+    let (_, digest) = avatar_url.rsplit_once("?v=")?;
+    is_digest(digest).then_some(digest)
+}
+
+fn is_digest(digest: &str) -> bool {
+    digest.len() == 64
+        && digest
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Content-addressed picture files
+#[derive(Debug, Clone)]
+pub struct AvatarStore {
+    dir: PathBuf,
+    max_bytes: usize,
+    cache_max_age_secs: u64,
+    /// Held while a user is pointed at a new picture and the old one deleted
+    replacing: Arc<Mutex<()>>,
+}
+
+impl AvatarStore {
+    pub fn new(config: &AvatarConfig) -> Self {
+        Self {
+            dir: PathBuf::from(&config.dir),
+            max_bytes: config.max_bytes,
+            cache_max_age_secs: config.cache_max_age_secs,
+            replacing: Arc::default(),
+        }
+    }
+
+    /// Wait for other pictures to be replaced first
+    ///
+    /// Hold the guard from `save` until the replaced picture has been
+    /// `remove`d, so that no upload takes up a picture as it is deleted.
+    pub async fn replacing(&self) -> MutexGuard<'_, ()> {
+        self.replacing.lock().await
+    }
+
+    /// Largest accepted upload, in bytes
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// How long clients may cache an avatar fetched without its hash
+    pub fn cache_max_age_secs(&self) -> u64 {
+        self.cache_max_age_secs
+    }
+
+    /// Files are spread over subdirectories named by the first byte of the hash
+    fn path(&self, digest: &str) -> PathBuf {
+        self.dir.join(&digest[..2]).join(digest)
+    }
+
+    /// Store a picture unless an identical one is already stored, returning its hash
+    ///
+    /// Fails with `UnsupportedMediaType` for anything but PNG, JPEG and WebP.
+    pub async fn save(&self, bytes: &[u8]) -> Result<String, AppError> {
+        if bytes.len() > self.max_bytes {
+            return Err(ErrorKind::PayloadTooLarge.into());
+        }
+        ImageFormat::sniff(bytes).ok_or(ErrorKind::UnsupportedMediaType)?;
+
+        let digest: String = Sha256::digest(bytes)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let path = self.path(&digest);
+        let failed = |err| AppError::internal(err).with_context("path", path.display());
+        if tokio::fs::try_exists(&path).await.map_err(failed)? {
+            return Ok(digest);
+        }
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await.map_err(failed)?;
+        }
+        // Write aside and rename so readers never see a partial file
+        let partial = path.with_extension(format!(
+            "{}.{}.partial",
+            std::process::id(),
+            PARTIALS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = match tokio::fs::write(&partial, bytes).await {
+            Ok(()) => tokio::fs::rename(&partial, &path).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = written {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(failed(err));
+        }
+        Ok(digest)
+    }
+
+    /// Delete the picture stored under `digest`, if any
+    pub async fn remove(&self, digest: &str) -> Result<(), AppError> {
+        if !is_digest(digest) {
+            return Ok(());
+        }
+        let path = self.path(digest);
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(AppError::internal(err).with_context("path", path.display())),
+        }
+    }
+
+    /// Picture stored under `digest`, if any
+    pub async fn load(&self, digest: &str) -> Result<Option<Vec<u8>>, AppError> {
+        if !is_digest(digest) {
+            return Ok(None);
+        }
+        let path = self.path(digest);
+        match tokio::fs::read(&path).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(AppError::internal(err).with_context("path", path.display())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    #[tokio::test]
+    async fn test_avatars_are_stored_once_by_content() {
+        let dir = std::env::temp_dir().join(format!("omni-gate-avatars-{}", std::process::id()));
+        let store = AvatarStore::new(&AvatarConfig {
+            dir: dir.to_string_lossy().into_owned(),
+            max_bytes: 64,
+            ..AvatarConfig::default()
+        });
+
+        let digest = store.save(PNG).await.unwrap();
+        assert_eq!(store.save(PNG).await.unwrap(), digest);
+        assert_eq!(store.load(&digest).await.unwrap().as_deref(), Some(PNG));
+        assert_eq!(
+            std::fs::read_dir(dir.join(&digest[..2])).unwrap().count(),
+            1
+        );
+        assert_eq!(digest_of(&avatar_url(7, &digest)), Some(digest.as_str()));
+
+        assert!(matches!(
+            store.save(b"GIF89a").await.map_err(AppError::into_kind),
+            Err(ErrorKind::UnsupportedMediaType)
+        ));
+        assert!(matches!(
+            store
+                .save(&[PNG, &[0; 64]].concat())
+                .await
+                .map_err(AppError::into_kind),
+            Err(ErrorKind::PayloadTooLarge)
+        ));
+        assert_eq!(store.load("../../etc/passwd").await.unwrap(), None);
+        assert_eq!(store.load(&"0".repeat(64)).await.unwrap(), None);
+
+        // Concurrent uploads of one new picture each write their own file
+        let other = [PNG, b"IEND"].concat();
+        let uploads: Vec<_> = (0..8).map(|_| store.save(&other)).collect();
+        let digests = futures_util::future::try_join_all(uploads).await.unwrap();
+        assert!(digests.iter().all(|other| *other == digests[0]));
+        assert_eq!(
+            store.load(&digests[0]).await.unwrap().as_deref(),
+            Some(&other[..])
+        );
+
+        store.remove(&digest).await.unwrap();
+        store.remove(&digest).await.unwrap();
+        assert_eq!(store.load(&digest).await.unwrap(), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_formats_are_sniffed_from_content() {
+        assert_eq!(ImageFormat::sniff(PNG), Some(ImageFormat::Png));
+        assert_eq!(
+            ImageFormat::sniff(&[0xff, 0xd8, 0xff, 0xe0]),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(
+            ImageFormat::sniff(b"RIFF\x24\0\0\0WEBPVP8 "),
+            Some(ImageFormat::Webp)
+        );
+        for bytes in [&b"RIFF\x24\0\0\0WAVE"[..], b"<svg/>", b""] {
+            assert_eq!(ImageFormat::sniff(bytes), None);
+        }
+    }
+}
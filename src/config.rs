@@ -7,8 +7,76 @@
 //! Author: imshike@gmail.com
 
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 
+/// Body format of REST error responses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorFormat {
+    /// `{"success": false, "error": {...}}`
+    #[default]
+    Envelope,
+    /// RFC 9457 `application/problem+json`
+    Problem,
+    /// Problem details for requests whose `Accept` header prefers them,
+    /// the envelope otherwise
+    Negotiate,
+}
+
+/// Error response configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ErrorConfig {
+    pub format: ErrorFormat,
+}
+
+/// OpenAPI document and Swagger UI configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiDocsConfig {
+    /// Serve the REST API's OpenAPI document at `/api-docs/openapi.json`
+    pub openapi: bool,
+    /// Serve Swagger UI at `/swagger-ui`; it needs the document, so is off
+    /// whenever `openapi` is
+    pub swagger_ui: bool,
+}
+
+impl Default for ApiDocsConfig {
+    fn default() -> Self {
+        Self {
+            openapi: true,
+            swagger_ui: true,
+        }
+    }
+}
+
+/// Where the JSON-RPC or gRPC API is served
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServeMode {
+    /// Its own listener, on `jsonrpc_host:jsonrpc_port` or `grpc_host:grpc_port`
+    #[default]
+    Standalone,
+    /// The REST listener: JSON-RPC at `POST /rpc` and WebSocket `GET /rpc/ws`,
+    /// gRPC for requests with an `application/grpc` content type
+    Mounted,
+    /// Both of the above
+    Both,
+}
+
+impl ServeMode {
+    /// Whether the API's own listener runs
+    pub fn standalone(self) -> bool {
+        matches!(self, ServeMode::Standalone | ServeMode::Both)
+    }
+
+    /// Whether the REST listener serves the API
+    pub fn mounted(self) -> bool {
+        matches!(self, ServeMode::Mounted | ServeMode::Both)
+    }
+}
+
 /// Server configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
@@ -16,8 +84,12 @@ pub struct ServerConfig {
     pub rest_port: u16,
     pub grpc_host: String,
     pub grpc_port: u16,
+    #[serde(default)]
+    pub grpc_mode: ServeMode,
     pub jsonrpc_host: String,
     pub jsonrpc_port: u16,
+    #[serde(default)]
+    pub jsonrpc_mode: ServeMode,
 }
 
 /// Logging configuration
@@ -26,11 +98,191 @@ pub struct LoggingConfig {
     pub level: String,
 }
 
+/// Storage configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// Persist state to a write-ahead log; when disabled state lives in memory only
+    pub enabled: bool,
+    /// Directory holding the write-ahead log and snapshot
+    pub data_dir: String,
+    /// Sync every log record to disk before acknowledging the mutation
+    pub sync_writes: bool,
+    /// Interval between log compactions into a snapshot
+    pub compaction_interval_secs: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            data_dir: "data".to_string(),
+            sync_writes: true,
+            compaction_interval_secs: 300,
+        }
+    }
+}
+
+/// Grid configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GridConfig {
+    /// Interval between sweeps deleting expired grid items
+    pub reaper_interval_secs: u64,
+}
+
+impl Default for GridConfig {
+    fn default() -> Self {
+        Self {
+            reaper_interval_secs: 5,
+        }
+    }
+}
+
+/// Authentication configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// `iss` claim of issued tokens, required on presented ones
+    pub jwt_issuer: String,
+    /// `aud` claim of issued tokens, required on presented ones
+    pub jwt_audience: String,
+    /// HMAC-SHA256 signing key; when empty a random key is generated at startup
+    /// and tokens do not survive a restart
+    pub jwt_secret: String,
+    /// Lifetime of access tokens
+    pub access_token_ttl_secs: u64,
+    /// Lifetime of refresh tokens
+    pub refresh_token_ttl_secs: u64,
+    /// Accept the `X-Auth-Subject`/`X-Auth-Groups` headers set by a trusted
+    /// authenticating proxy in place of a bearer token
+    pub trust_identity_headers: bool,
+    /// Subjects (user ids, for token holders) granted the `admin` role
+    pub admin_subjects: Vec<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            jwt_issuer: "omni-gate".to_string(),
+            jwt_audience: "omni-gate".to_string(),
+            jwt_secret: String::new(),
+            access_token_ttl_secs: 900,
+            refresh_token_ttl_secs: 14 * 24 * 60 * 60,
+            trust_identity_headers: false,
+            admin_subjects: Vec::new(),
+        }
+    }
+}
+
+/// Login throttling configuration
+///
+/// Failed logins are counted per username and per client address. Past
+/// `backoff_after_failures`, each further failure blocks logins for twice as
+/// long as the previous one; reaching a lockout threshold blocks them for
+/// `lockout_secs`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LockoutConfig {
+    /// Failures tolerated before backoff starts
+    pub backoff_after_failures: u32,
+    /// First backoff delay
+    pub backoff_base_ms: u64,
+    /// Longest backoff delay
+    pub max_backoff_secs: u64,
+    /// Failures for one username that lock it
+    pub max_failures_per_user: u32,
+    /// Failures from one client address that lock it
+    pub max_failures_per_ip: u32,
+    /// Length of a lockout
+    pub lockout_secs: u64,
+    /// Failures are forgotten after this long without another one
+    pub failure_window_secs: u64,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            backoff_after_failures: 3,
+            backoff_base_ms: 1000,
+            max_backoff_secs: 60,
+            max_failures_per_user: 10,
+            max_failures_per_ip: 50,
+            lockout_secs: 900,
+            failure_window_secs: 900,
+        }
+    }
+}
+
+/// Role-based access control configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RbacConfig {
+    /// Roles every authenticated caller holds in addition to their own
+    pub default_roles: Vec<String>,
+    /// Permissions granted by each role; `*` grants all of them
+    pub roles: BTreeMap<String, Vec<String>>,
+}
+
+impl Default for RbacConfig {
+    fn default() -> Self {
+        let role = |permissions: &[&str]| permissions.iter().map(|p| p.to_string()).collect();
+        Self {
+            default_roles: vec!["user".to_string()],
+            roles: BTreeMap::from([
+                ("admin".to_string(), role(&["*"])),
+                (
+                    "user".to_string(),
+                    role(&["grid:read", "grid:write", "users:read"]),
+                ),
+            ]),
+        }
+    }
+}
+
+/// User avatar configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AvatarConfig {
+    /// Directory holding uploaded avatars, named by the SHA-256 of their content
+    pub dir: String,
+    /// Largest accepted upload, in bytes
+    pub max_bytes: usize,
+    /// How long clients may cache `GET /users/{id}/avatar` without revalidating
+    pub cache_max_age_secs: u64,
+}
+
+impl Default for AvatarConfig {
+    fn default() -> Self {
+        Self {
+            dir: "data/avatars".to_string(),
+            max_bytes: 1024 * 1024,
+            cache_max_age_secs: 300,
+        }
+    }
+}
+
 /// Application configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
     pub logging: LoggingConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub grid: GridConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub lockout: LockoutConfig,
+    #[serde(default)]
+    pub rbac: RbacConfig,
+    #[serde(default)]
+    pub avatars: AvatarConfig,
+    #[serde(default)]
+    pub errors: ErrorConfig,
+    #[serde(default)]
+    pub api_docs: ApiDocsConfig,
 }
 
 impl Config {
@@ -73,12 +325,22 @@ impl Default for Config {
                 rest_port: 3000,
                 grpc_host: "[::1]".to_string(),
                 grpc_port: 5000,
+                grpc_mode: ServeMode::default(),
                 jsonrpc_host: "127.0.0.1".to_string(),
                 jsonrpc_port: 4000,
+                jsonrpc_mode: ServeMode::default(),
             },
             logging: LoggingConfig {
                 level: "debug".to_string(),
             },
+            storage: StorageConfig::default(),
+            grid: GridConfig::default(),
+            auth: AuthConfig::default(),
+            lockout: LockoutConfig::default(),
+            rbac: RbacConfig::default(),
+            avatars: AvatarConfig::default(),
+            errors: ErrorConfig::default(),
+            api_docs: ApiDocsConfig::default(),
         }
     }
 }
@@ -95,6 +357,14 @@ mod tests {
         assert_eq!(config.server.grpc_host, "[::1]");
         assert_eq!(config.server.grpc_port, 5000);
         assert_eq!(config.logging.level, "debug");
+        assert!(config.storage.enabled);
+        assert_eq!(config.storage.data_dir, "data");
+        assert!(!config.auth.trust_identity_headers);
+        assert!(config.auth.admin_subjects.is_empty());
+        assert_eq!(config.lockout.max_failures_per_user, 10);
+        assert_eq!(config.rbac.default_roles, ["user"]);
+        assert_eq!(config.rbac.roles["admin"], ["*"]);
+        assert_eq!(config.avatars.max_bytes, 1024 * 1024);
     }
 
     #[test]
@@ -2,16 +2,49 @@
 //!
 //! Contains unified error type definitions and error response formats.
 //!
+//! An `AppError` converts into an HTTP response, a JSON-RPC error object and a
+//! gRPC status alike, each carrying the same numeric `ErrorCode`: `error.code`
+//! over REST, `data.code` over JSON-RPC and the `code` metadata of the
+//! `google.rpc.ErrorInfo` detail over gRPC.
+//!
+//! Besides its `ErrorKind`, an `AppError` may hold the error that caused it and
+//! context fields naming what was being done. Those are logged, together with
+//! the request id, where the error leaves the server; clients only get the
+//! kind's message and the request id to quote when reporting the problem.
+//!
+//! REST error bodies are the `ErrorResponse` envelope or RFC 9457 problem
+//! details, as `errors.format` in the configuration says; see
+//! `error_format`.
+//!
 //! Copyright © 2025 imshike@gmail.com
 //! SPDX-License-Identifier: Apache-2.0
 //! Author: imshike@gmail.com
 
+use crate::config::ErrorFormat;
+use crate::request_id;
 use axum::{
-    http::StatusCode,
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
     response::{IntoResponse, Response},
     Json,
 };
+use jsonrpsee::types::error::{
+    ErrorObjectOwned, INTERNAL_ERROR_CODE, INVALID_PARAMS_CODE, METHOD_NOT_FOUND_CODE,
+    PARSE_ERROR_CODE,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::time::Duration;
+use tonic::Code;
+use tonic_types::{ErrorDetails, StatusExt};
+
+/// `domain` of the `google.rpc.ErrorInfo` detail on gRPC errors
+pub const ERROR_DOMAIN: &str = "omni-gate";
 
 /// Unified error response structure
 #[derive(Serialize, Deserialize, Debug)]
@@ -25,6 +58,55 @@ pub struct ErrorResponse {
 pub struct ErrorInfo {
     pub code: i32,
     pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub field_violations: Vec<FieldViolation>,
+    /// Correlation id of the failed request, also sent as `x-request-id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Media type of RFC 9457 problem details
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// RFC 9457 problem details, the alternative REST error body
+///
+/// `code`, `field_violations`, `retry_after_secs` and `request_id` are
+/// extension members matching the fields of `ErrorInfo`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProblemDetails {
+    /// `urn:omni-gate:error:` followed by the error's reason, such as `user-not-found`
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// Path of the failed request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    pub code: i32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub field_violations: Vec<FieldViolation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// A single invalid request field and why it was rejected
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FieldViolation {
+    pub field: String,
+    pub description: String,
+}
+
+impl FieldViolation {
+    pub fn new(field: impl Into<String>, description: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            description: description.into(),
+        }
+    }
 }
 
 /// Unified error code definition
@@ -36,11 +118,17 @@ pub enum ErrorCode {
     NotFound = 1002,
     Unauthorized = 1003,
     Forbidden = 1004,
+    NotAcceptable = 1005,
+    UnsupportedMediaType = 1006,
+    AlreadyExists = 1007,
+    LoginLocked = 1008,
+    PayloadTooLarge = 1009,
 
     // 业务错误 2000-2999
     GridItemNotFound = 2001,
     GridItemCreationFailed = 2002,
     GridItemUpdateFailed = 2003,
+    UserNotFound = 2004,
 
     // JSON-RPC 错误 3000-3999
     JsonRpcParseError = 3001,
@@ -60,96 +148,635 @@ impl ErrorCode {
             ErrorCode::NotFound => "Resource not found",
             ErrorCode::Unauthorized => "Unauthorized",
             ErrorCode::Forbidden => "Forbidden",
+            ErrorCode::NotAcceptable => "Requested response format is not acceptable",
+            ErrorCode::UnsupportedMediaType => "Unsupported request content type",
+            ErrorCode::AlreadyExists => "Resource already exists",
+            ErrorCode::LoginLocked => "Too many failed login attempts, try again later",
+            ErrorCode::PayloadTooLarge => "Request body is too large",
             ErrorCode::GridItemNotFound => "Grid item not found",
             ErrorCode::GridItemCreationFailed => "Grid item creation failed",
             ErrorCode::GridItemUpdateFailed => "Grid item update failed",
+            ErrorCode::UserNotFound => "User not found",
             ErrorCode::JsonRpcParseError => "JSON-RPC parse error",
             ErrorCode::JsonRpcMethodNotFound => "JSON-RPC method not found",
             ErrorCode::JsonRpcInvalidParams => "JSON-RPC invalid params",
         }
     }
+
+    /// Stable name of the code, the `reason` of gRPC `ErrorInfo` details
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ErrorCode::InternalError => "INTERNAL_ERROR",
+            ErrorCode::ValidationError => "VALIDATION_ERROR",
+            ErrorCode::NotFound => "NOT_FOUND",
+            ErrorCode::Unauthorized => "UNAUTHORIZED",
+            ErrorCode::Forbidden => "FORBIDDEN",
+            ErrorCode::NotAcceptable => "NOT_ACCEPTABLE",
+            ErrorCode::UnsupportedMediaType => "UNSUPPORTED_MEDIA_TYPE",
+            ErrorCode::AlreadyExists => "ALREADY_EXISTS",
+            ErrorCode::LoginLocked => "LOGIN_LOCKED",
+            ErrorCode::PayloadTooLarge => "PAYLOAD_TOO_LARGE",
+            ErrorCode::GridItemNotFound => "GRID_ITEM_NOT_FOUND",
+            ErrorCode::GridItemCreationFailed => "GRID_ITEM_CREATION_FAILED",
+            ErrorCode::GridItemUpdateFailed => "GRID_ITEM_UPDATE_FAILED",
+            ErrorCode::UserNotFound => "USER_NOT_FOUND",
+            ErrorCode::JsonRpcParseError => "JSON_RPC_PARSE_ERROR",
+            ErrorCode::JsonRpcMethodNotFound => "JSON_RPC_METHOD_NOT_FOUND",
+            ErrorCode::JsonRpcInvalidParams => "JSON_RPC_INVALID_PARAMS",
+        }
+    }
 }
 
-/// 应用程序自定义错误类型
+/// 应用程序错误种类
 #[derive(Debug)]
 #[allow(dead_code)]
-pub enum AppError {
+pub enum ErrorKind {
     InternalError,
     ValidationError,
     NotFound,
     Unauthorized,
     Forbidden,
+    NotAcceptable,
+    UnsupportedMediaType,
+    /// Request fields failed validation
+    InvalidFields(Vec<FieldViolation>),
+    /// Request fields collide with an existing resource
+    AlreadyExists(Vec<FieldViolation>),
+    /// Logins are blocked after repeated failures
+    LoginLocked {
+        retry_after_secs: u64,
+    },
+    /// Request body exceeds a configured limit
+    PayloadTooLarge,
     GridItemNotFound,
     GridItemCreationFailed,
     GridItemUpdateFailed,
+    UserNotFound,
     JsonRpcParseError,
     JsonRpcMethodNotFound,
     JsonRpcInvalidParams,
 }
 
-impl AppError {
+impl ErrorKind {
     /// 转换为错误码
     pub fn error_code(&self) -> ErrorCode {
         match self {
-            AppError::InternalError => ErrorCode::InternalError,
-            AppError::ValidationError => ErrorCode::ValidationError,
-            AppError::NotFound => ErrorCode::NotFound,
-            AppError::Unauthorized => ErrorCode::Unauthorized,
-            AppError::Forbidden => ErrorCode::Forbidden,
-            AppError::GridItemNotFound => ErrorCode::GridItemNotFound,
-            AppError::GridItemCreationFailed => ErrorCode::GridItemCreationFailed,
-            AppError::GridItemUpdateFailed => ErrorCode::GridItemUpdateFailed,
-            AppError::JsonRpcParseError => ErrorCode::JsonRpcParseError,
-            AppError::JsonRpcMethodNotFound => ErrorCode::JsonRpcMethodNotFound,
-            AppError::JsonRpcInvalidParams => ErrorCode::JsonRpcInvalidParams,
+            ErrorKind::InternalError => ErrorCode::InternalError,
+            ErrorKind::ValidationError => ErrorCode::ValidationError,
+            ErrorKind::NotFound => ErrorCode::NotFound,
+            ErrorKind::Unauthorized => ErrorCode::Unauthorized,
+            ErrorKind::Forbidden => ErrorCode::Forbidden,
+            ErrorKind::NotAcceptable => ErrorCode::NotAcceptable,
+            ErrorKind::UnsupportedMediaType => ErrorCode::UnsupportedMediaType,
+            ErrorKind::InvalidFields(_) => ErrorCode::ValidationError,
+            ErrorKind::AlreadyExists(_) => ErrorCode::AlreadyExists,
+            ErrorKind::LoginLocked { .. } => ErrorCode::LoginLocked,
+            ErrorKind::PayloadTooLarge => ErrorCode::PayloadTooLarge,
+            ErrorKind::GridItemNotFound => ErrorCode::GridItemNotFound,
+            ErrorKind::GridItemCreationFailed => ErrorCode::GridItemCreationFailed,
+            ErrorKind::GridItemUpdateFailed => ErrorCode::GridItemUpdateFailed,
+            ErrorKind::UserNotFound => ErrorCode::UserNotFound,
+            ErrorKind::JsonRpcParseError => ErrorCode::JsonRpcParseError,
+            ErrorKind::JsonRpcMethodNotFound => ErrorCode::JsonRpcMethodNotFound,
+            ErrorKind::JsonRpcInvalidParams => ErrorCode::JsonRpcInvalidParams,
+        }
+    }
+
+    /// Offending request fields, if the error carries any
+    pub fn field_violations(&self) -> &[FieldViolation] {
+        match self {
+            ErrorKind::InvalidFields(violations) | ErrorKind::AlreadyExists(violations) => {
+                violations
+            }
+            _ => &[],
+        }
+    }
+
+    /// Seconds the client should wait before retrying, if the error says
+    pub fn retry_after_secs(&self) -> Option<u64> {
+        match self {
+            ErrorKind::LoginLocked { retry_after_secs } => Some(*retry_after_secs),
+            _ => None,
+        }
+    }
+
+    /// HTTP status sent for the error
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ErrorKind::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorKind::ValidationError => StatusCode::BAD_REQUEST,
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorKind::Forbidden => StatusCode::FORBIDDEN,
+            ErrorKind::NotAcceptable => StatusCode::NOT_ACCEPTABLE,
+            ErrorKind::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorKind::InvalidFields(_) => StatusCode::BAD_REQUEST,
+            ErrorKind::AlreadyExists(_) => StatusCode::CONFLICT,
+            ErrorKind::LoginLocked { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ErrorKind::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorKind::GridItemNotFound => StatusCode::NOT_FOUND,
+            ErrorKind::GridItemCreationFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorKind::GridItemUpdateFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorKind::UserNotFound => StatusCode::NOT_FOUND,
+            ErrorKind::JsonRpcParseError => StatusCode::BAD_REQUEST,
+            ErrorKind::JsonRpcMethodNotFound => StatusCode::NOT_FOUND,
+            ErrorKind::JsonRpcInvalidParams => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    /// Whether the server is at fault rather than the request
+    pub fn is_server_error(&self) -> bool {
+        matches!(
+            self,
+            ErrorKind::InternalError
+                | ErrorKind::GridItemCreationFailed
+                | ErrorKind::GridItemUpdateFailed
+        )
+    }
+}
+
+/// 应用程序自定义错误类型
+///
+/// Clients see the kind and the request id; the source and context only reach
+/// the server log.
+#[derive(Debug)]
+pub struct AppError {
+    kind: ErrorKind,
+    source: Option<Box<dyn StdError + Send + Sync>>,
+    context: Vec<(&'static str, String)>,
+    request_id: Option<String>,
+}
+
+impl AppError {
+    pub fn new(kind: ErrorKind) -> Self {
+        Self {
+            kind,
+            source: None,
+            context: Vec::new(),
+            request_id: request_id::current(),
+        }
+    }
+
+    /// Internal error caused by `source`
+    pub fn internal(source: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        Self::new(ErrorKind::InternalError).with_source(source)
+    }
+
+    /// Record the error that caused this one
+    pub fn with_source(mut self, source: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Record what was being done, such as the id of the user involved
+    pub fn with_context(mut self, key: &'static str, value: impl fmt::Display) -> Self {
+        self.context.push((key, value.to_string()));
+        self
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    pub fn into_kind(self) -> ErrorKind {
+        self.kind
+    }
+
+    /// 转换为错误码
+    pub fn error_code(&self) -> ErrorCode {
+        self.kind.error_code()
+    }
+
+    /// Offending request fields, if the error carries any
+    pub fn field_violations(&self) -> &[FieldViolation] {
+        self.kind.field_violations()
+    }
+
+    /// Seconds the client should wait before retrying, if the error says
+    pub fn retry_after_secs(&self) -> Option<u64> {
+        self.kind.retry_after_secs()
+    }
+
+    /// What went wrong this time, beyond the kind's message
+    fn detail(&self) -> Option<String> {
+        if let Some(secs) = self.retry_after_secs() {
+            return Some(format!("Try again in {secs} seconds"));
+        }
+        let violations = self.field_violations();
+        (!violations.is_empty()).then(|| {
+            violations
+                .iter()
+                .map(|violation| format!("{}: {}", violation.field, violation.description))
+                .collect::<Vec<_>>()
+                .join("; ")
+        })
+    }
+
+    /// Id of the request that failed: the one being handled when the error was
+    /// raised, or else when it is sent back
+    pub fn request_id(&self) -> Option<String> {
+        self.request_id.clone().or_else(request_id::current)
+    }
+
+    /// Log the error with its context and source chain, once it is sent back
+    ///
+    /// Server faults are logged as errors; rejected requests only at debug
+    /// level, as they are the client's business.
+    fn log(&self, request_id: Option<&str>) {
+        let request_id = request_id.unwrap_or("-");
+        if self.kind.is_server_error() {
+            tracing::error!("Request {} failed: {}", request_id, self.report());
+        } else {
+            tracing::debug!("Request {} rejected: {}", request_id, self.report());
+        }
+    }
+
+    /// The error, its context fields and every error in its source chain
+    fn report(&self) -> String {
+        let mut report = format!("{} ({})", self, self.error_code().code());
+        for (key, value) in &self.context {
+            report.push_str(&format!(" {key}={value}"));
         }
+        let mut source = self.source();
+        while let Some(err) = source {
+            report.push_str(&format!(": {err}"));
+            source = err.source();
+        }
+        report
+    }
+}
+
+impl From<ErrorKind> for AppError {
+    fn from(kind: ErrorKind) -> Self {
+        Self::new(kind)
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.error_code().message())
+    }
+}
+
+impl StdError for AppError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.source
+            .as_deref()
+            .map(|source| source as &(dyn StdError + 'static))
     }
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         let error_code = self.error_code();
-        let status_code = match self {
-            AppError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
-            AppError::ValidationError => StatusCode::BAD_REQUEST,
-            AppError::NotFound => StatusCode::NOT_FOUND,
-            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
-            AppError::Forbidden => StatusCode::FORBIDDEN,
-            AppError::GridItemNotFound => StatusCode::NOT_FOUND,
-            AppError::GridItemCreationFailed => StatusCode::INTERNAL_SERVER_ERROR,
-            AppError::GridItemUpdateFailed => StatusCode::INTERNAL_SERVER_ERROR,
-            AppError::JsonRpcParseError => StatusCode::BAD_REQUEST,
-            AppError::JsonRpcMethodNotFound => StatusCode::NOT_FOUND,
-            AppError::JsonRpcInvalidParams => StatusCode::BAD_REQUEST,
-        };
+        let request_id = self.request_id();
+        self.log(request_id.as_deref());
+        let status_code = self.kind.status_code();
 
+        let problem = ProblemDetails {
+            problem_type: format!(
+                "urn:omni-gate:error:{}",
+                error_code.reason().to_ascii_lowercase().replace('_', "-")
+            ),
+            title: error_code.message().to_string(),
+            status: status_code.as_u16(),
+            detail: self.detail(),
+            instance: None,
+            code: error_code.code(),
+            field_violations: self.field_violations().to_vec(),
+            retry_after_secs: self.retry_after_secs(),
+            request_id: request_id.clone(),
+        };
         let error_response = ErrorResponse {
             success: false,
             error: ErrorInfo {
                 code: error_code.code(),
                 message: error_code.message().to_string(),
+                field_violations: self.field_violations().to_vec(),
+                request_id,
             },
         };
 
-        (status_code, Json(error_response)).into_response()
+        let mut response = (status_code, Json(error_response)).into_response();
+        if let Some(secs) = self.retry_after_secs() {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, secs.into());
+        }
+        // Picked up by `error_format` when problem details are wanted
+        response.extensions_mut().insert(problem);
+        response
+    }
+}
+
+/// Middleware writing REST errors in the configured `ErrorFormat`
+///
+/// Error responses leave `into_response` as envelopes carrying their problem
+/// details on the side; this swaps the body where problem details are wanted
+/// and fills in `instance`.
+pub async fn error_format(
+    State(format): State<ErrorFormat>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let wants_problem = match format {
+        ErrorFormat::Envelope => false,
+        ErrorFormat::Problem => true,
+        ErrorFormat::Negotiate => prefers_problem(
+            request
+                .headers()
+                .get(header::ACCEPT)
+                .and_then(|value| value.to_str().ok()),
+        ),
+    };
+    let instance = request.uri().path().to_string();
+    let mut response = next.run(request).await;
+    if !wants_problem {
+        return response;
+    }
+    let Some(mut problem) = response.extensions_mut().remove::<ProblemDetails>() else {
+        return response;
+    };
+    problem.instance = Some(instance);
+
+    let (mut parts, _) = response.into_parts();
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let body = serde_json::to_vec(&problem).unwrap_or_default();
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Whether an `Accept` header ranks problem details at least as high as JSON
+///
+/// Only an explicit `application/problem+json` counts; wildcards keep the envelope.
+fn prefers_problem(accept: Option<&str>) -> bool {
+    let (mut problem, mut json) = (0.0_f32, 0.0_f32);
+    for range in accept.unwrap_or_default().split(',') {
+        let mut parts = range.split(';');
+        let mime = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match mime.as_str() {
+            PROBLEM_JSON => problem = problem.max(quality),
+            "application/json" => json = json.max(quality),
+            _ => {}
+        }
+    }
+    problem > 0.0 && problem >= json
+}
+
+/// JSON-RPC error object
+///
+/// Request problems use the standard JSON-RPC codes (`-32602` and friends) and
+/// server faults `-32603`; every other error uses its `ErrorCode` as the code.
+/// `data` always holds the `ErrorCode`, plus `field_violations`,
+/// `retry_after_secs` and `request_id` when known.
+impl From<AppError> for ErrorObjectOwned {
+    fn from(err: AppError) -> Self {
+        let error_code = err.error_code();
+        let request_id = err.request_id();
+        err.log(request_id.as_deref());
+        let code = match err.kind {
+            ErrorKind::ValidationError
+            | ErrorKind::InvalidFields(_)
+            | ErrorKind::AlreadyExists(_)
+            | ErrorKind::JsonRpcInvalidParams => INVALID_PARAMS_CODE,
+            ErrorKind::JsonRpcParseError => PARSE_ERROR_CODE,
+            ErrorKind::JsonRpcMethodNotFound => METHOD_NOT_FOUND_CODE,
+            ErrorKind::InternalError
+            | ErrorKind::GridItemCreationFailed
+            | ErrorKind::GridItemUpdateFailed => INTERNAL_ERROR_CODE,
+            _ => error_code.code(),
+        };
+
+        let mut data = json!({ "code": error_code.code() });
+        if !err.field_violations().is_empty() {
+            data["field_violations"] = json!(err.field_violations());
+        }
+        if let Some(secs) = err.retry_after_secs() {
+            data["retry_after_secs"] = json!(secs);
+        }
+        if let Some(request_id) = request_id {
+            data["request_id"] = json!(request_id);
+        }
+        ErrorObjectOwned::owned(code, error_code.message(), Some(data))
+    }
+}
+
+/// gRPC status
+///
+/// Details carry a `google.rpc.ErrorInfo` naming the `ErrorCode`, plus
+/// `BadRequest` field violations, `RetryInfo` and `RequestInfo` when known.
+impl From<AppError> for tonic::Status {
+    fn from(err: AppError) -> Self {
+        let error_code = err.error_code();
+        let request_id = err.request_id();
+        err.log(request_id.as_deref());
+        let code = match err.kind {
+            ErrorKind::ValidationError
+            | ErrorKind::InvalidFields(_)
+            | ErrorKind::NotAcceptable
+            | ErrorKind::UnsupportedMediaType
+            | ErrorKind::JsonRpcParseError
+            | ErrorKind::JsonRpcInvalidParams => Code::InvalidArgument,
+            ErrorKind::AlreadyExists(_) => Code::AlreadyExists,
+            ErrorKind::NotFound | ErrorKind::GridItemNotFound | ErrorKind::UserNotFound => {
+                Code::NotFound
+            }
+            ErrorKind::Unauthorized => Code::Unauthenticated,
+            ErrorKind::Forbidden => Code::PermissionDenied,
+            ErrorKind::LoginLocked { .. } | ErrorKind::PayloadTooLarge => Code::ResourceExhausted,
+            ErrorKind::JsonRpcMethodNotFound => Code::Unimplemented,
+            ErrorKind::InternalError
+            | ErrorKind::GridItemCreationFailed
+            | ErrorKind::GridItemUpdateFailed => Code::Internal,
+        };
+
+        let mut details = ErrorDetails::new();
+        details.set_error_info(
+            error_code.reason(),
+            ERROR_DOMAIN,
+            HashMap::from([("code".to_string(), error_code.code().to_string())]),
+        );
+        if !err.field_violations().is_empty() {
+            details.set_bad_request(
+                err.field_violations()
+                    .iter()
+                    .map(|violation| {
+                        tonic_types::FieldViolation::new(&violation.field, &violation.description)
+                    })
+                    .collect::<Vec<_>>(),
+            );
+        }
+        if let Some(secs) = err.retry_after_secs() {
+            details.set_retry_info(Some(Duration::from_secs(secs)));
+        }
+        if let Some(request_id) = request_id {
+            details.set_request_info(request_id, "");
+        }
+        tonic::Status::with_error_details(code, error_code.message(), details)
     }
 }
 
-/// 将标准错误转换为 AppError
+impl From<ErrorKind> for ErrorObjectOwned {
+    fn from(kind: ErrorKind) -> Self {
+        AppError::from(kind).into()
+    }
+}
+
+impl From<ErrorKind> for tonic::Status {
+    fn from(kind: ErrorKind) -> Self {
+        AppError::from(kind).into()
+    }
+}
+
+/// 将标准错误转换为 AppError, keeping it as the source
 impl From<serde_json::Error> for AppError {
-    fn from(_err: serde_json::Error) -> Self {
-        AppError::InternalError
+    fn from(err: serde_json::Error) -> Self {
+        AppError::internal(err)
     }
 }
 
 impl From<std::io::Error> for AppError {
-    fn from(_err: std::io::Error) -> Self {
-        AppError::InternalError
+    fn from(err: std::io::Error) -> Self {
+        AppError::internal(err)
     }
 }
 
 impl From<config::ConfigError> for AppError {
-    fn from(_err: config::ConfigError) -> Self {
-        AppError::InternalError
+    fn from(err: config::ConfigError) -> Self {
+        AppError::internal(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors_keep_their_code_across_protocols() {
+        let err = || {
+            AppError::from(ErrorKind::InvalidFields(vec![FieldViolation::new(
+                "email", "is taken",
+            )]))
+        };
+
+        let rpc = ErrorObjectOwned::from(err());
+        assert_eq!(rpc.code(), INVALID_PARAMS_CODE);
+        let data: serde_json::Value = serde_json::from_str(rpc.data().unwrap().get()).unwrap();
+        assert_eq!(data["code"], 1001);
+        assert_eq!(data["field_violations"][0]["field"], "email");
+
+        let status = tonic::Status::from(err());
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let details = status.get_error_details();
+        let info = details.error_info().unwrap();
+        assert_eq!(info.reason, "VALIDATION_ERROR");
+        assert_eq!(info.metadata["code"], "1001");
+        assert_eq!(
+            details.bad_request().unwrap().field_violations[0].field,
+            "email"
+        );
+
+        let locked = || {
+            AppError::from(ErrorKind::LoginLocked {
+                retry_after_secs: 30,
+            })
+        };
+        let rpc = ErrorObjectOwned::from(locked());
+        assert_eq!(rpc.code(), 1008);
+        assert!(rpc
+            .data()
+            .unwrap()
+            .get()
+            .contains(r#""retry_after_secs":30"#));
+        let status = tonic::Status::from(locked());
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert_eq!(
+            status.get_error_details().error_info().unwrap().metadata["code"],
+            "1008"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_internal_errors_log_their_cause_but_only_show_a_request_id() {
+        let io = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "disk says no");
+        let err = AppError::from(io).with_context("path", "data/wal.log");
+        assert_eq!(
+            err.report(),
+            "Internal server error (1000) path=data/wal.log: disk says no"
+        );
+
+        let response = crate::request_id::tests::in_request("req-1", || err.into_response());
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["message"], "Internal server error");
+        assert_eq!(body["error"]["request_id"], "req-1");
+        assert!(!body.to_string().contains("disk"));
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_problem_details_where_configured_or_preferred() {
+        use tower::ServiceExt;
+
+        let app = |format| {
+            axum::Router::new()
+                .route(
+                    "/users/{id}",
+                    axum::routing::get(|| async {
+                        Err::<(), _>(AppError::from(ErrorKind::InvalidFields(vec![
+                            FieldViolation::new("age", "must be between 0 and 150"),
+                        ])))
+                    }),
+                )
+                .layer(axum::middleware::from_fn_with_state(format, error_format))
+        };
+        let call = |format, accept: &'static str| async move {
+            let request = axum::http::Request::get("/users/7")
+                .header(header::ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap();
+            let response = app(format).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let content_type = response.headers()[header::CONTENT_TYPE].clone();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            (content_type, body)
+        };
+
+        let (content_type, body) = call(ErrorFormat::Problem, "application/json").await;
+        assert_eq!(content_type, PROBLEM_JSON);
+        assert_eq!(body["type"], "urn:omni-gate:error:validation-error");
+        assert_eq!(body["title"], "Validation error");
+        assert_eq!(body["status"], 400);
+        assert_eq!(body["detail"], "age: must be between 0 and 150");
+        assert_eq!(body["instance"], "/users/7");
+        assert_eq!(body["code"], 1001);
+        assert_eq!(body["field_violations"][0]["field"], "age");
+
+        let (content_type, body) = call(ErrorFormat::Envelope, PROBLEM_JSON).await;
+        assert_eq!(content_type, "application/json");
+        assert_eq!(body["error"]["code"], 1001);
+
+        let accept = "application/json;q=0.5, application/problem+json";
+        assert_eq!(call(ErrorFormat::Negotiate, accept).await.0, PROBLEM_JSON);
+        let accept = "application/json, application/problem+json;q=0.5";
+        assert_eq!(
+            call(ErrorFormat::Negotiate, accept).await.0,
+            "application/json"
+        );
+        assert_eq!(
+            call(ErrorFormat::Negotiate, "*/*").await.0,
+            "application/json"
+        );
     }
 }
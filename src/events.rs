@@ -0,0 +1,48 @@
+//! Domain event module
+//!
+//! In-process publish/subscribe channel used to push state changes to
+//! streaming clients as they happen.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use tokio::sync::broadcast;
+
+/// Default number of events buffered for slow subscribers before they lag
+const DEFAULT_CAPACITY: usize = 256;
+
+/// Broadcast bus delivering every published event to all current subscribers
+#[derive(Debug, Clone)]
+pub struct EventBus<E> {
+    sender: broadcast::Sender<E>,
+}
+
+impl<E: Clone> Default for EventBus<E> {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl<E: Clone> EventBus<E> {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    /// Publish an event; it is dropped when nobody is subscribed
+    pub fn publish(&self, event: E) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Receive every event published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<E> {
+        self.sender.subscribe()
+    }
+
+    /// Number of live subscriptions
+    #[cfg(test)]
+    pub fn subscriber_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+}
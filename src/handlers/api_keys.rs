@@ -0,0 +1,356 @@
+//! API key management handler
+//!
+//! Creates, lists, rotates and revokes API keys on behalf of a caller, shared
+//! by the REST, JSON-RPC and gRPC APIs. Users manage their own keys; callers
+//! with the `users:admin` permission manage anyone's. Leaving out the user id
+//! means the caller's own keys.
+//!
+//! A key can only be scoped to permissions its creator holds, so issuing one
+//! never widens what the creator can do. Only callers signed in without a key
+//! may ask for `*`, all of the user's permissions.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use crate::api_keys::ApiKeyStore;
+use crate::auth::Principal;
+use crate::errors::{AppError, ErrorKind, FieldViolation};
+use crate::handlers::grid::AppState;
+use crate::handlers::users::UserDirectory;
+use crate::protos::user::{ApiKey, ApiKeySecret, ListApiKeysResponse, RevokeApiKeyResponse};
+use crate::rbac::Permission;
+use crate::rpc_api::{ApiKeyQuery, CreateApiKeyParams};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+
+/// Longest accepted key name, in characters
+const MAX_NAME_LEN: usize = 64;
+
+/// Check requested scopes, failing with `Forbidden` for permissions `principal` lacks
+fn check_scopes(principal: &Principal, scopes: &[String]) -> Result<(), AppError> {
+    let mut violations = Vec::new();
+    if scopes.is_empty() {
+        violations.push(FieldViolation::new("scopes", "must not be empty"));
+    }
+    let mut missing = false;
+    for scope in scopes {
+        // `*` grants all of the user's permissions, more than a scoped key holds
+        if scope == "*" {
+            missing |= principal.api_key_id.is_some();
+            continue;
+        }
+        match scope.parse::<Permission>() {
+            Ok(permission) => missing |= !principal.can(permission),
+            Err(message) => violations.push(FieldViolation::new("scopes", message)),
+        }
+    }
+    if !violations.is_empty() {
+        return Err(ErrorKind::InvalidFields(violations).into());
+    }
+    if missing {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    Ok(())
+}
+
+/// Key `key_id` if `principal` may manage it
+///
+/// Other users' keys look missing to callers who may not manage them.
+fn manageable(
+    api_keys: &ApiKeyStore,
+    principal: &Principal,
+    key_id: &str,
+) -> Result<ApiKey, AppError> {
+    let api_key = api_keys.get(key_id).ok_or(ErrorKind::NotFound)?;
+    if Some(api_key.user_id) != principal.user_id() && !principal.can(Permission::UsersAdmin) {
+        return Err(ErrorKind::NotFound.into());
+    }
+    Ok(api_key)
+}
+
+/// Issue a key for `params.user_id`, or for the caller
+pub fn create(
+    api_keys: &ApiKeyStore,
+    directory: &UserDirectory,
+    principal: &Principal,
+    params: CreateApiKeyParams,
+) -> Result<ApiKeySecret, AppError> {
+    let user_id = principal.target_user(params.user_id)?;
+    let name = params.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(ErrorKind::InvalidFields(vec![FieldViolation::new(
+            "name",
+            format!("must be 1 to {MAX_NAME_LEN} characters"),
+        )])
+        .into());
+    }
+    check_scopes(principal, &params.scopes)?;
+    directory.get(user_id)?;
+
+    let issued = api_keys.create(user_id, name, params.scopes)?;
+    tracing::info!(
+        "API key {} of user {} created by {}",
+        issued.api_key.as_ref().map_or("", |api_key| &api_key.id),
+        user_id,
+        principal.subject
+    );
+    Ok(issued)
+}
+
+/// Keys of `user_id`, or of the caller, oldest first
+pub fn list(
+    api_keys: &ApiKeyStore,
+    principal: &Principal,
+    user_id: Option<i32>,
+) -> Result<Vec<ApiKey>, AppError> {
+    let user_id = principal.target_user(user_id)?;
+    Ok(api_keys.list(user_id))
+}
+
+/// Give a key a new secret, returning it
+pub fn rotate(
+    api_keys: &ApiKeyStore,
+    principal: &Principal,
+    key_id: &str,
+) -> Result<ApiKeySecret, AppError> {
+    let api_key = manageable(api_keys, principal, key_id)?;
+    let rotated = api_keys.rotate(key_id)?;
+    tracing::info!(
+        "API key {} of user {} rotated by {}",
+        key_id,
+        api_key.user_id,
+        principal.subject
+    );
+    Ok(rotated)
+}
+
+/// Revoke a key, returning it
+pub fn revoke(
+    api_keys: &ApiKeyStore,
+    principal: &Principal,
+    key_id: &str,
+) -> Result<ApiKey, AppError> {
+    manageable(api_keys, principal, key_id)?;
+    let api_key = api_keys.revoke(key_id)?;
+    tracing::info!(
+        "API key {} of user {} revoked by {}",
+        key_id,
+        api_key.user_id,
+        principal.subject
+    );
+    Ok(api_key)
+}
+
+/// `GET /api-keys`
+#[utoipa::path(
+    get,
+    path = "/api-keys",
+    params(("user_id" = Option<i32>, Query, description = "Owner of the keys; the caller if left out")),
+    responses(
+        (status = 200, description = "The user's keys, without their secrets", body = ListApiKeysResponse),
+        (status = 403, description = "Another user's keys without `users:admin`"),
+    )
+)]
+pub async fn list_api_keys(
+    principal: Principal,
+    Query(query): Query<ApiKeyQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<ListApiKeysResponse>, AppError> {
+    let api_keys = list(&state.api_keys, &principal, query.user_id)?;
+    Ok(Json(ListApiKeysResponse { api_keys }))
+}
+
+/// `POST /api-keys`
+#[utoipa::path(
+    post,
+    path = "/api-keys",
+    request_body = CreateApiKeyParams,
+    responses(
+        (status = 201, description = "The new key and its secret, shown only once", body = ApiKeySecret),
+        (status = 400, description = "Invalid name or scopes"),
+        (status = 403, description = "Scopes beyond the caller's permissions"),
+    )
+)]
+pub async fn create_api_key(
+    principal: Principal,
+    State(state): State<AppState>,
+    Json(params): Json<CreateApiKeyParams>,
+) -> Result<(StatusCode, Json<ApiKeySecret>), AppError> {
+    let issued = create(&state.api_keys, &state.directory, &principal, params)?;
+    Ok((StatusCode::CREATED, Json(issued)))
+}
+
+/// `POST /api-keys/{id}/rotate`
+#[utoipa::path(
+    post,
+    path = "/api-keys/{id}/rotate",
+    params(("id" = String, Path, description = "API key id")),
+    responses(
+        (status = 200, description = "The key and its new secret", body = ApiKeySecret),
+        (status = 404, description = "No such key"),
+    )
+)]
+pub async fn rotate_api_key(
+    principal: Principal,
+    Path(key_id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<ApiKeySecret>, AppError> {
+    Ok(Json(rotate(&state.api_keys, &principal, &key_id)?))
+}
+
+/// `DELETE /api-keys/{id}`
+#[utoipa::path(
+    delete,
+    path = "/api-keys/{id}",
+    params(("id" = String, Path, description = "API key id")),
+    responses(
+        (status = 200, description = "The revoked key", body = RevokeApiKeyResponse),
+        (status = 404, description = "No such key"),
+    )
+)]
+pub async fn revoke_api_key(
+    principal: Principal,
+    Path(key_id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<RevokeApiKeyResponse>, AppError> {
+    let api_key = revoke(&state.api_keys, &principal, &key_id)?;
+    Ok(Json(RevokeApiKeyResponse {
+        api_key: Some(api_key),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::Journal;
+    use crate::protos::user::CreateUserRequest;
+    use std::sync::Arc;
+
+    fn user(directory: &UserDirectory, email: &str) -> i32 {
+        let request = CreateUserRequest {
+            name: "Alice".to_string(),
+            email: email.to_string(),
+            age: 30,
+            ..Default::default()
+        };
+        directory.create(request, None).unwrap().id
+    }
+
+    fn caller(user_id: i32, permissions: &[Permission]) -> Principal {
+        Principal {
+            subject: user_id.to_string(),
+            roles: Vec::new(),
+            permissions: permissions.iter().copied().collect(),
+            session_id: Some("session".to_string()),
+            api_key_id: None,
+        }
+    }
+
+    fn params(user_id: Option<i32>, scopes: &[&str]) -> CreateApiKeyParams {
+        CreateApiKeyParams {
+            user_id,
+            name: "batch".to_string(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_keys_are_scoped_to_what_their_creator_may_do() {
+        let directory = UserDirectory::new(Arc::default(), Journal::default());
+        let api_keys = ApiKeyStore::new(Journal::default());
+        let alice = user(&directory, "alice@example.com");
+        let bob = user(&directory, "bob@example.com");
+        let principal = caller(alice, &[Permission::UsersRead]);
+
+        let issued = create(
+            &api_keys,
+            &directory,
+            &principal,
+            params(None, &["users:read"]),
+        )
+        .unwrap();
+        assert_eq!(issued.api_key.unwrap().user_id, alice);
+        create(&api_keys, &directory, &principal, params(None, &["*"])).unwrap();
+
+        // A narrow key cannot mint an unrestricted one
+        let scoped = Principal {
+            api_key_id: Some("key".to_string()),
+            ..principal.clone()
+        };
+        assert!(matches!(
+            create(&api_keys, &directory, &scoped, params(None, &["*"]))
+                .map_err(AppError::into_kind),
+            Err(ErrorKind::Forbidden)
+        ));
+        create(
+            &api_keys,
+            &directory,
+            &scoped,
+            params(None, &["users:read"]),
+        )
+        .unwrap();
+
+        for (params, field) in [
+            (params(None, &[]), "scopes"),
+            (params(None, &["users:everything"]), "scopes"),
+            (
+                CreateApiKeyParams {
+                    name: " ".to_string(),
+                    ..params(None, &["users:read"])
+                },
+                "name",
+            ),
+        ] {
+            let err = create(&api_keys, &directory, &principal, params).unwrap_err();
+            assert_eq!(err.field_violations()[0].field, field);
+        }
+        for params in [params(None, &["users:write"]), params(Some(bob), &["*"])] {
+            assert!(matches!(
+                create(&api_keys, &directory, &principal, params).map_err(AppError::into_kind),
+                Err(ErrorKind::Forbidden)
+            ));
+        }
+
+        let admin = caller(bob, &[Permission::UsersAdmin]);
+        assert!(matches!(
+            create(&api_keys, &directory, &admin, params(Some(99), &["*"]))
+                .map_err(AppError::into_kind),
+            Err(ErrorKind::UserNotFound)
+        ));
+        create(&api_keys, &directory, &admin, params(Some(alice), &["*"])).unwrap();
+        assert_eq!(list(&api_keys, &admin, Some(alice)).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_users_manage_only_their_own_keys() {
+        let directory = UserDirectory::new(Arc::default(), Journal::default());
+        let api_keys = ApiKeyStore::new(Journal::default());
+        let alice = caller(user(&directory, "alice@example.com"), &[]);
+        let admin = caller(
+            user(&directory, "root@example.com"),
+            &[Permission::UsersAdmin],
+        );
+
+        let issued = create(&api_keys, &directory, &alice, params(None, &["*"])).unwrap();
+        let key_id = issued.api_key.unwrap().id;
+        assert!(matches!(
+            rotate(&api_keys, &admin, "missing").map_err(AppError::into_kind),
+            Err(ErrorKind::NotFound)
+        ));
+        let other = create(&api_keys, &directory, &admin, params(None, &["*"])).unwrap();
+        let other_id = other.api_key.unwrap().id;
+        assert!(matches!(
+            revoke(&api_keys, &alice, &other_id).map_err(AppError::into_kind),
+            Err(ErrorKind::NotFound)
+        ));
+
+        let rotated = rotate(&api_keys, &alice, &key_id).unwrap();
+        assert_ne!(rotated.key, issued.key);
+        revoke(&api_keys, &admin, &key_id).unwrap();
+        assert!(list(&api_keys, &alice, None).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,301 @@
+//! REST content negotiation module
+//!
+//! Lets REST handlers read and write JSON, MessagePack, CBOR or Protobuf bodies
+//! based on the `Content-Type` and `Accept` request headers. JSON stays the default.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use crate::errors::{AppError, ErrorKind};
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, FromRequestParts, Request},
+    http::{header, request::Parts, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use prost::Message;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Body formats supported by the REST API
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentFormat {
+    Json,
+    MsgPack,
+    Cbor,
+    Protobuf,
+}
+
+impl ContentFormat {
+    /// Canonical media type written to the `Content-Type` response header
+    pub fn mime(&self) -> &'static str {
+        match self {
+            ContentFormat::Json => "application/json",
+            ContentFormat::MsgPack => "application/msgpack",
+            ContentFormat::Cbor => "application/cbor",
+            ContentFormat::Protobuf => "application/x-protobuf",
+        }
+    }
+
+    /// Look up a format by media type, ignoring parameters such as `charset`
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        let essence = mime.split(';').next().unwrap_or("").trim();
+        match essence.to_ascii_lowercase().as_str() {
+            "application/json" => Some(ContentFormat::Json),
+            "application/msgpack" | "application/x-msgpack" => Some(ContentFormat::MsgPack),
+            "application/cbor" => Some(ContentFormat::Cbor),
+            "application/x-protobuf" | "application/protobuf" => Some(ContentFormat::Protobuf),
+            _ => None,
+        }
+    }
+
+    /// Pick the response format for an `Accept` header value
+    ///
+    /// A missing header or a wildcard selects JSON. Among the supported media
+    /// ranges the one with the highest quality wins, ties going to the first listed.
+    pub fn negotiate(accept: Option<&str>) -> Result<Self, AppError> {
+        let accept = match accept.map(str::trim) {
+            None | Some("") => return Ok(ContentFormat::Json),
+            Some(accept) => accept,
+        };
+
+        let mut best: Option<(ContentFormat, f32)> = None;
+        for range in accept.split(',') {
+            let mut parts = range.split(';');
+            let mime = parts.next().unwrap_or("").trim();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if quality <= 0.0 {
+                continue;
+            }
+
+            let format = match mime {
+                "*/*" | "application/*" => Some(ContentFormat::Json),
+                _ => ContentFormat::from_mime(mime),
+            };
+            if let Some(format) = format {
+                if best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                    best = Some((format, quality));
+                }
+            }
+        }
+
+        best.map(|(format, _)| format)
+            .ok_or(AppError::from(ErrorKind::NotAcceptable))
+    }
+}
+
+/// Conversion of a REST body type into its prost wire message
+pub trait ToProto {
+    type Message: Message;
+
+    fn to_proto(&self) -> Self::Message;
+}
+
+/// Conversion of a prost wire message into a REST body type
+pub trait FromProto: Sized {
+    type Message: Message + Default;
+
+    fn from_proto(message: Self::Message) -> Result<Self, AppError>;
+}
+
+/// Extractor for the response format requested through the `Accept` header
+#[derive(Debug, Clone, Copy)]
+pub struct Accept(pub ContentFormat);
+
+impl<S> FromRequestParts<S> for Accept
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let accept = parts
+            .headers
+            .get(header::ACCEPT)
+            .map(|value| value.to_str().map_err(|_| ErrorKind::NotAcceptable))
+            .transpose()?;
+
+        ContentFormat::negotiate(accept).map(Accept)
+    }
+}
+
+/// Request body extractor that decodes according to the `Content-Type` header
+pub struct Payload<T>(pub T);
+
+impl<S, T> FromRequest<S> for Payload<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned + FromProto,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let format = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(ContentFormat::from_mime)
+            .ok_or(ErrorKind::UnsupportedMediaType)?;
+
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(|_| ErrorKind::ValidationError)?;
+
+        decode(format, &body).map(Payload)
+    }
+}
+
+/// Response body encoded in the format chosen by [`Accept`]
+pub struct Negotiated<T> {
+    pub format: ContentFormat,
+    pub body: T,
+}
+
+impl<T> Negotiated<T> {
+    pub fn new(Accept(format): Accept, body: T) -> Self {
+        Self { format, body }
+    }
+}
+
+impl<T> IntoResponse for Negotiated<T>
+where
+    T: Serialize + ToProto,
+{
+    fn into_response(self) -> Response {
+        match encode(self.format, &self.body) {
+            Ok(bytes) => (
+                [(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(self.format.mime()),
+                )],
+                bytes,
+            )
+                .into_response(),
+            Err(err) => err.into_response(),
+        }
+    }
+}
+
+fn decode<T>(format: ContentFormat, body: &[u8]) -> Result<T, AppError>
+where
+    T: DeserializeOwned + FromProto,
+{
+    match format {
+        ContentFormat::Json => serde_json::from_slice(body).map_err(invalid_body),
+        ContentFormat::MsgPack => rmp_serde::from_slice(body).map_err(invalid_body),
+        ContentFormat::Cbor => ciborium::from_reader(body).map_err(invalid_body),
+        ContentFormat::Protobuf => T::Message::decode(body)
+            .map_err(invalid_body)
+            .and_then(T::from_proto),
+    }
+}
+
+/// Undecodable request body, keeping the decoder's complaint for the log
+fn invalid_body(err: impl std::error::Error + Send + Sync + 'static) -> AppError {
+    AppError::new(ErrorKind::ValidationError).with_source(err)
+}
+
+fn encode<T>(format: ContentFormat, body: &T) -> Result<Vec<u8>, AppError>
+where
+    T: Serialize + ToProto,
+{
+    match format {
+        ContentFormat::Json => Ok(serde_json::to_vec(body)?),
+        ContentFormat::MsgPack => rmp_serde::to_vec_named(body).map_err(AppError::internal),
+        ContentFormat::Cbor => {
+            let mut bytes = Vec::new();
+            ciborium::into_writer(body, &mut bytes).map_err(AppError::internal)?;
+            Ok(bytes)
+        }
+        ContentFormat::Protobuf => Ok(body.to_proto().encode_to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::grid::{ApiResponse, CreateGridItem, GridItemResponse};
+
+    fn sample() -> ApiResponse<GridItemResponse> {
+        ApiResponse {
+            success: true,
+            data: Some(GridItemResponse {
+                id: 7,
+                name: "marker".to_string(),
+                description: "temporary".to_string(),
+                x: 3,
+                y: -4,
+                expires_at: None,
+                owner: "alice".to_string(),
+                grants: Vec::new(),
+            }),
+            message: "ok".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_negotiate_defaults_to_json() {
+        assert_eq!(ContentFormat::negotiate(None).unwrap(), ContentFormat::Json);
+        assert_eq!(
+            ContentFormat::negotiate(Some("*/*")).unwrap(),
+            ContentFormat::Json
+        );
+    }
+
+    #[test]
+    fn test_negotiate_honors_quality() {
+        let format =
+            ContentFormat::negotiate(Some("application/json;q=0.5, application/cbor")).unwrap();
+        assert_eq!(format, ContentFormat::Cbor);
+
+        let format = ContentFormat::negotiate(Some(
+            "application/x-protobuf;q=0, application/msgpack;q=0.1",
+        ))
+        .unwrap();
+        assert_eq!(format, ContentFormat::MsgPack);
+    }
+
+    #[test]
+    fn test_negotiate_rejects_unsupported() {
+        assert!(matches!(
+            ContentFormat::negotiate(Some("text/html, application/xml"))
+                .map_err(AppError::into_kind),
+            Err(ErrorKind::NotAcceptable)
+        ));
+    }
+
+    #[test]
+    fn test_encode_round_trips() {
+        let body = sample();
+
+        let bytes = encode(ContentFormat::MsgPack, &body).unwrap();
+        let value: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(value["data"]["name"], "marker");
+
+        let bytes = encode(ContentFormat::Cbor, &body).unwrap();
+        let value: serde_json::Value = ciborium::from_reader(&bytes[..]).unwrap();
+        assert_eq!(value["data"]["y"], -4);
+
+        let bytes = encode(ContentFormat::Protobuf, &body).unwrap();
+        let reply = crate::protos::grid::GridItemReply::decode(&bytes[..]).unwrap();
+        assert_eq!(reply.data.unwrap().id, 7);
+    }
+
+    #[test]
+    fn test_decode_protobuf_payload() {
+        let message = crate::protos::grid::CreateGridItem {
+            name: "marker".to_string(),
+            description: String::new(),
+            x: 1,
+            y: 2,
+            ..Default::default()
+        };
+        let item: CreateGridItem =
+            decode(ContentFormat::Protobuf, &message.encode_to_vec()).unwrap();
+        assert_eq!(item.name, "marker");
+        assert_eq!((item.x, item.y), (1, 2));
+    }
+}
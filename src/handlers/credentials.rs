@@ -0,0 +1,478 @@
+//! Credentials module
+//!
+//! Password login, token refresh and password changes, shared by the gRPC and
+//! JSON-RPC user APIs, and deleting users, shared by gRPC and REST.
+//!
+//! Each login opens a session in the `SessionStore`; refreshing keeps it alive.
+//! Changing a password ends the user's sessions, and deleting a user also
+//! revokes their API keys, before the call returns.
+//!
+//! Failed logins are throttled by a `LoginGuard`; blocked attempts fail with
+//! `ErrorKind::LoginLocked` without the password being checked.
+//!
+//! Passwords are stored as argon2id hashes. Hashing is deliberately slow, so
+//! it runs on the blocking thread pool rather than on the async workers.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use crate::api_keys::ApiKeyStore;
+use crate::auth::Principal;
+use crate::errors::{AppError, ErrorKind};
+use crate::handlers::users::{password_violation, UserDirectory};
+use crate::lockout::LoginGuard;
+use crate::protos::user::{CreateUserRequest, TokenResponse, User};
+use crate::rbac::Permission;
+use crate::sessions::{ClientInfo, SessionStore};
+use crate::tokens::{TokenService, TokenUse};
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, SaltString};
+use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+/// Hash `password` into a PHC string with a fresh salt
+pub fn hash_password(password: &str) -> Result<String, AppError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| AppError::internal(err.to_string()))
+}
+
+/// Check `password` against a PHC string produced by `hash_password`
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash).is_ok_and(|parsed| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok()
+    })
+}
+
+/// Hash checked when the user is unknown, so failed logins take the same time
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash_password("dummy password").unwrap_or_default())
+}
+
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, AppError> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(AppError::internal)
+}
+
+/// Current password to check when `principal` changes the password of `user_id`
+///
+/// Users change their own password by giving the current one; callers with
+/// `users:admin` reset anyone else's without it.
+pub fn current_password_check<'a>(
+    principal: &Principal,
+    user_id: i32,
+    current_password: &'a str,
+) -> Result<Option<&'a str>, AppError> {
+    if principal.user_id() == Some(user_id) {
+        return Ok(Some(current_password));
+    }
+    Principal::require(Some(principal), Permission::UsersAdmin)?;
+    Ok(None)
+}
+
+/// Authenticates users and manages their passwords
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    directory: UserDirectory,
+    tokens: TokenService,
+    sessions: SessionStore,
+    api_keys: ApiKeyStore,
+    guard: LoginGuard,
+}
+
+impl Credentials {
+    pub fn new(
+        directory: UserDirectory,
+        tokens: TokenService,
+        sessions: SessionStore,
+        api_keys: ApiKeyStore,
+        guard: LoginGuard,
+    ) -> Self {
+        Self {
+            directory,
+            tokens,
+            sessions,
+            api_keys,
+            guard,
+        }
+    }
+
+    /// Create a user, hashing the password it was given, if any
+    pub async fn create_user(&self, request: CreateUserRequest) -> Result<User, AppError> {
+        // Reject bad records before paying for the hash
+        self.directory.validate(&request)?;
+        let password_hash = match request.password.as_str() {
+            "" => None,
+            password => {
+                let password = password.to_string();
+                Some(blocking(move || hash_password(&password)).await??)
+            }
+        };
+        self.directory.create(request, password_hash)
+    }
+
+    /// Exchange an email and password for a token pair in a new session
+    pub async fn login(
+        &self,
+        username: &str,
+        password: &str,
+        client: &ClientInfo,
+    ) -> Result<TokenResponse, AppError> {
+        let source = client.addr;
+        let attempt = self.guard.begin(username, source)?;
+
+        let user = self.directory.find_by_email(username);
+        let stored = user
+            .as_ref()
+            .and_then(|user| self.directory.password_hash(user.id));
+
+        let password = password.to_string();
+        let verified = blocking(move || {
+            let password_hash = stored.as_deref().unwrap_or_else(|| dummy_hash());
+            verify_password(&password, password_hash) && stored.is_some()
+        })
+        .await?;
+
+        match user {
+            Some(user) if verified => {
+                attempt.succeeded();
+                let session = self.sessions.create(user.id, client);
+                self.tokens.issue(&user.id.to_string(), &session.id)
+            }
+            _ => {
+                attempt.failed();
+                Err(ErrorKind::Unauthorized.into())
+            }
+        }
+    }
+
+    /// Exchange a refresh token for a new token pair in the same session
+    pub async fn refresh(
+        &self,
+        refresh_token: &str,
+        client: &ClientInfo,
+    ) -> Result<TokenResponse, AppError> {
+        let claims = self.tokens.verify(refresh_token, TokenUse::Refresh)?;
+        // Tokens of deleted users stop working even before they expire
+        let user_id = claims.sub.parse().map_err(|_| ErrorKind::Unauthorized)?;
+        self.directory
+            .get(user_id)
+            .map_err(|_| ErrorKind::Unauthorized)?;
+        self.sessions.refresh(&claims.sid, client)?;
+        self.tokens.issue(&claims.sub, &claims.sid)
+    }
+
+    /// Lift the login throttling of a username and/or client address
+    pub fn unlock_login(&self, username: Option<&str>, source: Option<IpAddr>) -> bool {
+        self.guard.unlock(username, source)
+    }
+
+    /// Delete a user, signing them out everywhere and revoking their API keys
+    pub fn delete_user(&self, user_id: i32) -> Result<User, AppError> {
+        let user = self.directory.delete(user_id)?;
+        self.sessions.revoke_user(user_id);
+        self.api_keys.revoke_user(user_id);
+        Ok(user)
+    }
+
+    /// Change a user's password after checking the current one, ending all of
+    /// the user's sessions
+    ///
+    /// Admins resetting someone else's password pass no current password.
+    /// Users without a password cannot set one this way.
+    pub async fn set_password(
+        &self,
+        user_id: i32,
+        current_password: Option<&str>,
+        new_password: &str,
+    ) -> Result<(), AppError> {
+        self.directory.get(user_id)?;
+        if let Some(violation) = password_violation("new_password", new_password) {
+            return Err(ErrorKind::InvalidFields(vec![violation]).into());
+        }
+        let stored = self
+            .directory
+            .password_hash(user_id)
+            .ok_or(ErrorKind::Forbidden)?;
+
+        let current_password = current_password.map(str::to_string);
+        let new_password = new_password.to_string();
+        let password_hash = blocking(move || {
+            if current_password.is_some_and(|current| !verify_password(&current, &stored)) {
+                return Err(ErrorKind::Unauthorized.into());
+            }
+            hash_password(&new_password)
+        })
+        .await??;
+
+        self.directory.set_password_hash(user_id, password_hash)?;
+        self.sessions.revoke_user(user_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AuthConfig, LockoutConfig};
+    use crate::journal::Journal;
+    use crate::sessions::Protocol;
+    use std::sync::Arc;
+
+    fn credentials() -> Credentials {
+        let directory = UserDirectory::new(Arc::default(), Journal::default());
+        let tokens = TokenService::new(&AuthConfig {
+            jwt_secret: "test-secret".to_string(),
+            ..AuthConfig::default()
+        });
+        let guard = LoginGuard::new(LockoutConfig {
+            backoff_after_failures: 5,
+            max_failures_per_user: 5,
+            ..LockoutConfig::default()
+        });
+        Credentials::new(
+            directory,
+            tokens,
+            SessionStore::new(3600),
+            ApiKeyStore::new(Journal::default()),
+            guard,
+        )
+    }
+
+    fn client() -> ClientInfo {
+        ClientInfo::new(None, Protocol::JsonRpc)
+    }
+
+    fn new_user(email: &str, password: &str) -> CreateUserRequest {
+        CreateUserRequest {
+            name: "Alice".to_string(),
+            email: email.to_string(),
+            age: 30,
+            password: password.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_password_hashes_are_salted() {
+        let first = hash_password("correct horse").unwrap();
+        let second = hash_password("correct horse").unwrap();
+
+        assert_ne!(first, second);
+        assert!(first.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse", &first));
+        assert!(!verify_password("wrong horse", &first));
+        assert!(!verify_password("correct horse", "not a hash"));
+    }
+
+    #[tokio::test]
+    async fn test_login_issues_tokens_for_valid_credentials_only() {
+        let credentials = credentials();
+        let user = credentials
+            .create_user(new_user("alice@example.com", "correct horse"))
+            .await
+            .unwrap();
+
+        let pair = credentials
+            .login("ALICE@example.com", "correct horse", &client())
+            .await
+            .unwrap();
+        let claims = credentials
+            .tokens
+            .verify(&pair.access_token, TokenUse::Access)
+            .unwrap();
+        assert_eq!(claims.sub, user.id.to_string());
+
+        for (username, password) in [
+            ("alice@example.com", "wrong horse"),
+            ("nobody@example.com", "correct horse"),
+        ] {
+            assert!(matches!(
+                credentials
+                    .login(username, password, &client())
+                    .await
+                    .map_err(AppError::into_kind),
+                Err(ErrorKind::Unauthorized)
+            ));
+        }
+
+        // Users created without a password cannot log in
+        credentials
+            .create_user(new_user("bob@example.com", ""))
+            .await
+            .unwrap();
+        assert!(credentials
+            .login("bob@example.com", "", &client())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_repeated_failures_lock_the_username() {
+        let credentials = credentials();
+        credentials
+            .create_user(new_user("alice@example.com", "correct horse"))
+            .await
+            .unwrap();
+
+        for _ in 0..5 {
+            let err = credentials
+                .login("alice@example.com", "wrong horse", &client())
+                .await
+                .unwrap_err();
+            assert!(matches!(err.kind(), ErrorKind::Unauthorized));
+        }
+        let err = credentials
+            .login("alice@example.com", "correct horse", &client())
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::LoginLocked { .. }));
+
+        assert!(credentials.unlock_login(Some("alice@example.com"), None));
+        assert!(credentials
+            .login("alice@example.com", "correct horse", &client())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_requires_a_live_session_and_user() {
+        let credentials = credentials();
+        let user = credentials
+            .create_user(new_user("alice@example.com", "correct horse"))
+            .await
+            .unwrap();
+        let pair = credentials
+            .login("alice@example.com", "correct horse", &client())
+            .await
+            .unwrap();
+
+        let refreshed = credentials
+            .refresh(&pair.refresh_token, &client())
+            .await
+            .unwrap();
+        assert!(credentials
+            .refresh(&pair.access_token, &client())
+            .await
+            .is_err());
+
+        // Both pairs belong to the one session; revoking it ends them together
+        let claims = credentials
+            .tokens
+            .verify(&refreshed.refresh_token, TokenUse::Refresh)
+            .unwrap();
+        assert_eq!(credentials.sessions.list(user.id).len(), 1);
+        credentials.sessions.revoke(&claims.sid);
+        for token in [&pair.refresh_token, &refreshed.refresh_token] {
+            assert!(credentials.refresh(token, &client()).await.is_err());
+        }
+
+        let pair = credentials
+            .login("alice@example.com", "correct horse", &client())
+            .await
+            .unwrap();
+
+        credentials.directory.delete(user.id).unwrap();
+        assert!(matches!(
+            credentials
+                .refresh(&pair.refresh_token, &client())
+                .await
+                .map_err(AppError::into_kind),
+            Err(ErrorKind::Unauthorized)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_set_password_checks_the_current_password() {
+        let credentials = credentials();
+        let user = credentials
+            .create_user(new_user("alice@example.com", "correct horse"))
+            .await
+            .unwrap();
+
+        let err = credentials
+            .set_password(user.id, Some("wrong horse"), "battery staple")
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Unauthorized));
+
+        let err = credentials
+            .set_password(user.id, Some("correct horse"), "short")
+            .await
+            .unwrap_err();
+        assert_eq!(err.field_violations()[0].field, "new_password");
+
+        credentials
+            .set_password(user.id, Some("correct horse"), "battery staple")
+            .await
+            .unwrap();
+        assert!(credentials
+            .login("alice@example.com", "battery staple", &client())
+            .await
+            .is_ok());
+        assert!(credentials
+            .login("alice@example.com", "correct horse", &client())
+            .await
+            .is_err());
+
+        // Admin resets skip the check
+        credentials
+            .set_password(user.id, None, "tr0ub4dor")
+            .await
+            .unwrap();
+        assert!(credentials
+            .login("alice@example.com", "tr0ub4dor", &client())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_password_changes_and_deletion_end_sessions_at_once() {
+        let credentials = credentials();
+        let user = credentials
+            .create_user(new_user("alice@example.com", "correct horse"))
+            .await
+            .unwrap();
+        let pair = credentials
+            .login("alice@example.com", "correct horse", &client())
+            .await
+            .unwrap();
+
+        credentials
+            .set_password(user.id, Some("correct horse"), "battery staple")
+            .await
+            .unwrap();
+        assert!(credentials.sessions.list(user.id).is_empty());
+        assert!(credentials
+            .refresh(&pair.refresh_token, &client())
+            .await
+            .is_err());
+
+        credentials
+            .login("alice@example.com", "battery staple", &client())
+            .await
+            .unwrap();
+        credentials
+            .api_keys
+            .create(user.id, "batch", vec!["*".to_string()])
+            .unwrap();
+        credentials.delete_user(user.id).unwrap();
+        assert!(credentials.sessions.list(user.id).is_empty());
+        assert!(credentials.api_keys.list(user.id).is_empty());
+        assert!(matches!(
+            credentials
+                .delete_user(user.id)
+                .map_err(AppError::into_kind),
+            Err(ErrorKind::UserNotFound)
+        ));
+    }
+}
@@ -2,233 +2,1077 @@
 //!
 //! Handles business logic for grid item CRUD operations.
 //!
+//! Every item records the principal that created it as its owner. The owner
+//! always has admin access; anyone else needs a grant to a user or group.
+//! Items created before owners were recorded have none and belong to the
+//! `admin` role instead.
+//! Read access lists and fetches an item, write access edits it, and admin
+//! access deletes it and manages its grants.
+//!
 //! Copyright © 2025 imshike@gmail.com
 //! SPDX-License-Identifier: Apache-2.0
 //! Author: imshike@gmail.com
 
+use crate::api_keys::ApiKeyStore;
+use crate::auth::{Principal, ADMIN_ROLE};
+use crate::avatars::AvatarStore;
+use crate::errors::{AppError, ErrorKind, FieldViolation};
+use crate::events::EventBus;
+use crate::handlers::content::{Accept, FromProto, Negotiated, Payload, ToProto};
+use crate::handlers::credentials::Credentials;
+use crate::handlers::users::UserDirectory;
+use crate::journal::{Journal, Mutation};
+use crate::protos::grid as proto;
+use crate::sessions::SessionStore;
+use crate::store::Store;
 use axum::{
     extract::{Path, State},
     http::StatusCode,
-    response::Json,
+    response::sse::{Event, KeepAlive, Sse},
 };
-use serde::{Deserialize, Serialize};
-use std::sync::{Arc, RwLock};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
 use utoipa::ToSchema;
 
-#[derive(Clone, Debug, ToSchema)]
+/// Access level on a grid item, each level implying the ones below it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    Read,
+    Write,
+    Admin,
+}
+
+/// Recipient of a grant
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Grantee {
+    User(String),
+    Group(String),
+}
+
+/// Access shared with a user or group
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Grant {
+    pub grantee: Grantee,
+    pub access: Access,
+}
+
+impl Grant {
+    fn applies_to(&self, principal: &Principal) -> bool {
+        match &self.grantee {
+            Grantee::User(subject) => *subject == principal.subject,
+            Grantee::Group(group) => principal.has_role(group),
+        }
+    }
+}
+
+/// Effective access of `principal` on an item with the given owner and grants
+fn access_level(owner: &str, grants: &[Grant], principal: &Principal) -> Option<Access> {
+    let owns = if owner.is_empty() {
+        principal.has_role(ADMIN_ROLE)
+    } else {
+        owner == principal.subject
+    };
+    if owns {
+        return Some(Access::Admin);
+    }
+    grants
+        .iter()
+        .filter(|grant| grant.applies_to(principal))
+        .map(|grant| grant.access)
+        .max()
+}
+
+fn require(access: Option<Access>, needed: Access) -> Result<(), AppError> {
+    if access >= Some(needed) {
+        Ok(())
+    } else {
+        Err(ErrorKind::Forbidden.into())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
 pub struct GridItem {
     pub id: u64,
     pub name: String,
     pub description: String,
     pub x: i32,
     pub y: i32,
+    /// Unix timestamp (seconds) after which the item is removed
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// Subject of the principal that created the item
+    #[serde(default)]
+    pub owner: String,
+    #[serde(default)]
+    pub grants: Vec<Grant>,
 }
 
-#[derive(Serialize, ToSchema)]
+impl GridItem {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn access_for(&self, principal: &Principal) -> Option<Access> {
+        access_level(&self.owner, &self.grants, principal)
+    }
+}
+
+#[derive(Clone, Serialize, ToSchema)]
 pub struct GridItemResponse {
     pub id: u64,
     pub name: String,
     pub description: String,
     pub x: i32,
     pub y: i32,
+    pub expires_at: Option<u64>,
+    pub owner: String,
+    pub grants: Vec<Grant>,
+}
+
+impl GridItemResponse {
+    fn access_for(&self, principal: &Principal) -> Option<Access> {
+        access_level(&self.owner, &self.grants, principal)
+    }
 }
 
+impl From<&GridItem> for GridItemResponse {
+    fn from(item: &GridItem) -> Self {
+        Self {
+            id: item.id,
+            name: item.name.clone(),
+            description: item.description.clone(),
+            x: item.x,
+            y: item.y,
+            expires_at: item.expires_at,
+            owner: item.owner.clone(),
+            grants: item.grants.clone(),
+        }
+    }
+}
+
+/// Item creation payload; `ttl_seconds` and `expires_at` are mutually exclusive
 #[derive(Deserialize, ToSchema)]
 pub struct CreateGridItem {
     pub name: String,
     pub description: String,
     pub x: i32,
     pub y: i32,
+    /// Remove the item this many seconds after creation
+    #[serde(default)]
+    pub ttl_seconds: Option<u64>,
+    /// Remove the item at this Unix timestamp (seconds)
+    #[serde(default)]
+    pub expires_at: Option<u64>,
 }
 
+/// Item update payload; `ttl_seconds` and `expires_at` reset the expiry, and
+/// an explicit `"expires_at": null` clears it
 #[derive(Deserialize, ToSchema)]
 pub struct UpdateGridItem {
     pub name: Option<String>,
     pub description: Option<String>,
     pub x: Option<i32>,
     pub y: Option<i32>,
+    #[serde(default)]
+    pub ttl_seconds: Option<u64>,
+    /// Absent leaves the expiry alone, `null` removes it
+    #[serde(default, deserialize_with = "present")]
+    #[schema(value_type = Option<u64>, nullable)]
+    pub expires_at: Option<Option<u64>>,
+}
+
+/// Deserialize a field that was given, even as `null`, into `Some`
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// Replacement set of grants for an item
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateGrants {
+    pub grants: Vec<Grant>,
+}
+
+/// Why a grid item disappeared
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletionReason {
+    Deleted,
+    Expired,
+}
+
+/// Change notification published for every grid mutation
+#[derive(Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GridEvent {
+    Created {
+        item: GridItemResponse,
+    },
+    Updated {
+        item: GridItemResponse,
+    },
+    Deleted {
+        item: GridItemResponse,
+        reason: DeletionReason,
+    },
+}
+
+impl GridEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            GridEvent::Created { .. } => "created",
+            GridEvent::Updated { .. } => "updated",
+            GridEvent::Deleted { .. } => "deleted",
+        }
+    }
+
+    fn item(&self) -> &GridItemResponse {
+        match self {
+            GridEvent::Created { item }
+            | GridEvent::Updated { item }
+            | GridEvent::Deleted { item, .. } => item,
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    pub grid_items: Arc<RwLock<Vec<GridItem>>>,
+    pub grid_items: Arc<Store<GridItem>>,
+    pub journal: Journal,
+    pub events: EventBus<GridEvent>,
+    pub sessions: SessionStore,
+    pub directory: UserDirectory,
+    pub api_keys: ApiKeyStore,
+    pub credentials: Credentials,
+    pub avatars: AvatarStore,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct ApiResponse<T> {
     pub success: bool,
     pub data: Option<T>,
     pub message: String,
 }
 
-pub async fn list(State(state): State<AppState>) -> Json<ApiResponse<Vec<GridItemResponse>>> {
-    let items = state
+impl From<Access> for proto::Access {
+    fn from(access: Access) -> Self {
+        match access {
+            Access::Read => proto::Access::Read,
+            Access::Write => proto::Access::Write,
+            Access::Admin => proto::Access::Admin,
+        }
+    }
+}
+
+impl ToProto for Grant {
+    type Message = proto::Grant;
+
+    fn to_proto(&self) -> Self::Message {
+        let grantee = match &self.grantee {
+            Grantee::User(subject) => proto::grant::Grantee::User(subject.clone()),
+            Grantee::Group(group) => proto::grant::Grantee::Group(group.clone()),
+        };
+        proto::Grant {
+            grantee: Some(grantee),
+            access: proto::Access::from(self.access).into(),
+        }
+    }
+}
+
+impl FromProto for Grant {
+    type Message = proto::Grant;
+
+    fn from_proto(message: Self::Message) -> Result<Self, AppError> {
+        let grantee = match message.grantee {
+            Some(proto::grant::Grantee::User(subject)) => Grantee::User(subject),
+            Some(proto::grant::Grantee::Group(group)) => Grantee::Group(group),
+            None => return Err(ErrorKind::ValidationError.into()),
+        };
+        let access = match proto::Access::try_from(message.access) {
+            Ok(proto::Access::Read) => Access::Read,
+            Ok(proto::Access::Write) => Access::Write,
+            Ok(proto::Access::Admin) => Access::Admin,
+            _ => return Err(ErrorKind::ValidationError.into()),
+        };
+        Ok(Self { grantee, access })
+    }
+}
+
+impl ToProto for GridItemResponse {
+    type Message = proto::GridItem;
+
+    fn to_proto(&self) -> Self::Message {
+        proto::GridItem {
+            id: self.id,
+            name: self.name.clone(),
+            description: self.description.clone(),
+            x: self.x,
+            y: self.y,
+            expires_at: self.expires_at,
+            owner: self.owner.clone(),
+            grants: self.grants.iter().map(ToProto::to_proto).collect(),
+        }
+    }
+}
+
+impl ToProto for ApiResponse<GridItemResponse> {
+    type Message = proto::GridItemReply;
+
+    fn to_proto(&self) -> Self::Message {
+        proto::GridItemReply {
+            success: self.success,
+            data: self.data.as_ref().map(ToProto::to_proto),
+            message: self.message.clone(),
+        }
+    }
+}
+
+impl ToProto for ApiResponse<Vec<GridItemResponse>> {
+    type Message = proto::GridItemListReply;
+
+    fn to_proto(&self) -> Self::Message {
+        proto::GridItemListReply {
+            success: self.success,
+            data: self.data.iter().flatten().map(ToProto::to_proto).collect(),
+            message: self.message.clone(),
+        }
+    }
+}
+
+impl ToProto for ApiResponse<()> {
+    type Message = proto::EmptyReply;
+
+    fn to_proto(&self) -> Self::Message {
+        proto::EmptyReply {
+            success: self.success,
+            message: self.message.clone(),
+        }
+    }
+}
+
+impl FromProto for CreateGridItem {
+    type Message = proto::CreateGridItem;
+
+    fn from_proto(message: Self::Message) -> Result<Self, AppError> {
+        Ok(Self {
+            name: message.name,
+            description: message.description,
+            x: message.x,
+            y: message.y,
+            ttl_seconds: message.ttl_seconds,
+            expires_at: message.expires_at,
+        })
+    }
+}
+
+impl FromProto for UpdateGridItem {
+    type Message = proto::UpdateGridItem;
+
+    fn from_proto(message: Self::Message) -> Result<Self, AppError> {
+        let expires_at = match (message.expires_at, message.clear_expiry) {
+            (Some(_), true) => return Err(ErrorKind::ValidationError.into()),
+            (None, true) => Some(None),
+            (expires_at, false) => expires_at.map(Some),
+        };
+        Ok(Self {
+            name: message.name,
+            description: message.description,
+            x: message.x,
+            y: message.y,
+            ttl_seconds: message.ttl_seconds,
+            expires_at,
+        })
+    }
+}
+
+impl FromProto for UpdateGrants {
+    type Message = proto::UpdateGrants;
+
+    fn from_proto(message: Self::Message) -> Result<Self, AppError> {
+        let grants = message
+            .grants
+            .into_iter()
+            .map(Grant::from_proto)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { grants })
+    }
+}
+
+/// Current Unix time in seconds
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Resolve the absolute expiry requested through `ttl_seconds` or `expires_at`
+fn requested_expiry(
+    ttl_seconds: Option<u64>,
+    expires_at: Option<u64>,
+    now: u64,
+) -> Result<Option<u64>, AppError> {
+    match (ttl_seconds, expires_at) {
+        (Some(_), Some(_)) => Err(ErrorKind::ValidationError.into()),
+        (Some(ttl_seconds), None) => Ok(Some(now.saturating_add(ttl_seconds))),
+        (None, Some(expires_at)) if expires_at <= now => {
+            Err(ErrorKind::InvalidFields(vec![FieldViolation::new(
+                "expires_at",
+                "must be in the future",
+            )])
+            .into())
+        }
+        (None, expires_at) => Ok(expires_at),
+    }
+}
+
+/// Resolve an update's expiry change: `None` keeps it, `Some(None)` clears it
+fn updated_expiry(
+    ttl_seconds: Option<u64>,
+    expires_at: Option<Option<u64>>,
+    now: u64,
+) -> Result<Option<Option<u64>>, AppError> {
+    match (ttl_seconds, expires_at) {
+        (Some(_), Some(None)) => Err(ErrorKind::ValidationError.into()),
+        (None, Some(None)) => Ok(Some(None)),
+        (ttl_seconds, expires_at) => {
+            Ok(requested_expiry(ttl_seconds, expires_at.flatten(), now)?.map(Some))
+        }
+    }
+}
+
+/// Grid items the caller can read
+#[utoipa::path(
+    get,
+    path = "/grid",
+    responses(
+        (status = 200, description = "Unexpired items the caller can read", body = ApiResponse<Vec<GridItemResponse>>),
+    )
+)]
+pub async fn list(
+    accept: Accept,
+    principal: Principal,
+    State(state): State<AppState>,
+) -> Negotiated<ApiResponse<Vec<GridItemResponse>>> {
+    let now = unix_now();
+    let response_items: Vec<GridItemResponse> = state
         .grid_items
-        .read()
-        .expect("Failed to acquire read lock on grid_items");
-    let response_items: Vec<GridItemResponse> = items
+        .snapshot()
         .iter()
-        .map(|item| GridItemResponse {
-            id: item.id,
-            name: item.name.clone(),
-            description: item.description.clone(),
-            x: item.x,
-            y: item.y,
-        })
+        .filter(|item| !item.is_expired(now))
+        .filter(|item| item.access_for(&principal).is_some())
+        .map(GridItemResponse::from)
         .collect();
 
-    Json(ApiResponse {
-        success: true,
-        data: Some(response_items),
-        message: "Successfully retrieved grid item list".to_string(),
-    })
+    Negotiated::new(
+        accept,
+        ApiResponse {
+            success: true,
+            data: Some(response_items),
+            message: "Successfully retrieved grid item list".to_string(),
+        },
+    )
 }
 
+/// One grid item
+#[utoipa::path(
+    get,
+    path = "/grid/{id}",
+    params(("id" = u64, Path, description = "Grid item id")),
+    responses(
+        (status = 200, description = "The item, or `success: false` if there is none", body = ApiResponse<GridItemResponse>),
+        (status = 403, description = "No read access to the item"),
+    )
+)]
 pub async fn get_by_id(
+    accept: Accept,
+    principal: Principal,
     Path(id): Path<u64>,
     State(state): State<AppState>,
-) -> Json<ApiResponse<GridItemResponse>> {
-    let items = state
+) -> Result<Negotiated<ApiResponse<GridItemResponse>>, AppError> {
+    let item = state
         .grid_items
-        .read()
-        .expect("Failed to acquire read lock on grid_items");
-    let item = items.iter().find(|item| item.id == id);
+        .get(id)
+        .filter(|item| !item.is_expired(unix_now()));
 
-    match item {
-        Some(item) => Json(ApiResponse {
-            success: true,
-            data: Some(GridItemResponse {
-                id: item.id,
-                name: item.name.clone(),
-                description: item.description.clone(),
-                x: item.x,
-                y: item.y,
-            }),
-            message: "Successfully retrieved grid item".to_string(),
-        }),
-        None => Json(ApiResponse {
+    let response = match item {
+        Some(item) => {
+            require(item.access_for(&principal), Access::Read)?;
+            ApiResponse {
+                success: true,
+                data: Some(GridItemResponse::from(&item)),
+                message: "Successfully retrieved grid item".to_string(),
+            }
+        }
+        None => ApiResponse {
             success: false,
             data: None,
             message: "Specified grid item not found".to_string(),
-        }),
-    }
+        },
+    };
+
+    Ok(Negotiated::new(accept, response))
 }
 
+/// Add a grid item owned by the caller
+#[utoipa::path(
+    post,
+    path = "/grid",
+    request_body = CreateGridItem,
+    responses(
+        (status = 201, description = "The new item", body = ApiResponse<GridItemResponse>),
+        (status = 400, description = "Both `ttl_seconds` and `expires_at` given, or `expires_at` in the past"),
+    )
+)]
 pub async fn create(
+    accept: Accept,
+    principal: Principal,
     State(state): State<AppState>,
-    Json(payload): Json<CreateGridItem>,
-) -> (StatusCode, Json<ApiResponse<GridItemResponse>>) {
-    let mut items = state
-        .grid_items
-        .write()
-        .expect("Failed to acquire write lock on grid_items");
+    Payload(payload): Payload<CreateGridItem>,
+) -> Result<(StatusCode, Negotiated<ApiResponse<GridItemResponse>>), AppError> {
+    let expires_at = requested_expiry(payload.ttl_seconds, payload.expires_at, unix_now())?;
 
-    let new_id = items.iter().map(|item| item.id).max().unwrap_or(0) + 1;
+    let new_item = state.grid_items.insert_with(|id| {
+        let item = GridItem {
+            id,
+            name: payload.name,
+            description: payload.description,
+            x: payload.x,
+            y: payload.y,
+            expires_at,
+            owner: principal.subject,
+            grants: Vec::new(),
+        };
+        state.journal.record(Mutation::GridItemPut(item.clone()))?;
+        Ok::<_, AppError>(item)
+    })?;
+    let response_item = GridItemResponse::from(&new_item);
+    state.events.publish(GridEvent::Created {
+        item: response_item.clone(),
+    });
 
-    let new_item = GridItem {
-        id: new_id,
-        name: payload.name,
-        description: payload.description,
-        x: payload.x,
-        y: payload.y,
-    };
+    Ok((
+        StatusCode::CREATED,
+        Negotiated::new(
+            accept,
+            ApiResponse {
+                success: true,
+                data: Some(response_item),
+                message: "Successfully created grid item".to_string(),
+            },
+        ),
+    ))
+}
 
-    let response_item = GridItemResponse {
-        id: new_item.id,
-        name: new_item.name.clone(),
-        description: new_item.description.clone(),
-        x: new_item.x,
-        y: new_item.y,
-    };
+/// Change the given fields of a grid item; requires write access
+#[utoipa::path(
+    put,
+    path = "/grid/{id}",
+    params(("id" = u64, Path, description = "Grid item id")),
+    request_body = UpdateGridItem,
+    responses(
+        (status = 200, description = "The updated item", body = ApiResponse<GridItemResponse>),
+        (status = 400, description = "Both `ttl_seconds` and `expires_at` given, or `expires_at` in the past"),
+        (status = 403, description = "No write access to the item"),
+        (status = 404, description = "No such item", body = ApiResponse<GridItemResponse>),
+    )
+)]
+pub async fn update(
+    accept: Accept,
+    principal: Principal,
+    Path(id): Path<u64>,
+    State(state): State<AppState>,
+    Payload(payload): Payload<UpdateGridItem>,
+) -> Result<(StatusCode, Negotiated<ApiResponse<GridItemResponse>>), AppError> {
+    let now = unix_now();
+    let expires_at = updated_expiry(payload.ttl_seconds, payload.expires_at, now)?;
 
-    items.push(new_item);
+    let updated = state.grid_items.update(id, |item| {
+        if item.is_expired(now) {
+            return Ok(None);
+        }
+        require(item.access_for(&principal), Access::Write)?;
+        if let Some(name) = payload.name {
+            item.name = name;
+        }
+        if let Some(description) = payload.description {
+            item.description = description;
+        }
+        if let Some(x) = payload.x {
+            item.x = x;
+        }
+        if let Some(y) = payload.y {
+            item.y = y;
+        }
+        if let Some(expires_at) = expires_at {
+            item.expires_at = expires_at;
+        }
+        state.journal.record(Mutation::GridItemPut(item.clone()))?;
+        Ok::<_, AppError>(Some(GridItemResponse::from(&*item)))
+    });
 
-    (
-        StatusCode::CREATED,
-        Json(ApiResponse {
-            success: true,
-            data: Some(response_item),
-            message: "Successfully created grid item".to_string(),
-        }),
-    )
+    Ok(updated_response(
+        &state,
+        accept,
+        updated.transpose()?.flatten(),
+        "Successfully updated grid item",
+    ))
 }
 
-pub async fn update(
+/// Replace the grants on an item; requires admin access
+#[utoipa::path(
+    put,
+    path = "/grid/{id}/grants",
+    params(("id" = u64, Path, description = "Grid item id")),
+    request_body = UpdateGrants,
+    responses(
+        (status = 200, description = "The item with its new grants", body = ApiResponse<GridItemResponse>),
+        (status = 400, description = "A blank grantee"),
+        (status = 403, description = "No admin access to the item"),
+        (status = 404, description = "No such item", body = ApiResponse<GridItemResponse>),
+    )
+)]
+pub async fn set_grants(
+    accept: Accept,
+    principal: Principal,
     Path(id): Path<u64>,
     State(state): State<AppState>,
-    Json(payload): Json<UpdateGridItem>,
-) -> (StatusCode, Json<ApiResponse<GridItemResponse>>) {
-    let mut items = state
-        .grid_items
-        .write()
-        .expect("Failed to acquire write lock on grid_items");
-    let item = items.iter_mut().find(|item| item.id == id);
+    Payload(payload): Payload<UpdateGrants>,
+) -> Result<(StatusCode, Negotiated<ApiResponse<GridItemResponse>>), AppError> {
+    let has_blank_grantee = payload.grants.iter().any(|grant| match &grant.grantee {
+        Grantee::User(name) | Grantee::Group(name) => name.trim().is_empty(),
+    });
+    if has_blank_grantee {
+        return Err(ErrorKind::ValidationError.into());
+    }
 
-    match item {
-        Some(item) => {
-            if let Some(name) = payload.name {
-                item.name = name;
-            }
-            if let Some(description) = payload.description {
-                item.description = description;
-            }
-            if let Some(x) = payload.x {
-                item.x = x;
-            }
-            if let Some(y) = payload.y {
-                item.y = y;
-            }
+    let now = unix_now();
+    let updated = state.grid_items.update(id, |item| {
+        if item.is_expired(now) {
+            return Ok(None);
+        }
+        require(item.access_for(&principal), Access::Admin)?;
+        item.grants = payload.grants;
+        state.journal.record(Mutation::GridItemPut(item.clone()))?;
+        Ok::<_, AppError>(Some(GridItemResponse::from(&*item)))
+    });
 
-            let response_item = GridItemResponse {
-                id: item.id,
-                name: item.name.clone(),
-                description: item.description.clone(),
-                x: item.x,
-                y: item.y,
-            };
+    Ok(updated_response(
+        &state,
+        accept,
+        updated.transpose()?.flatten(),
+        "Successfully updated grid item grants",
+    ))
+}
 
+/// Publish an update and build the response, or a not-found response for `None`
+fn updated_response(
+    state: &AppState,
+    accept: Accept,
+    updated: Option<GridItemResponse>,
+    message: &str,
+) -> (StatusCode, Negotiated<ApiResponse<GridItemResponse>>) {
+    match updated {
+        Some(response_item) => {
+            state.events.publish(GridEvent::Updated {
+                item: response_item.clone(),
+            });
             (
                 StatusCode::OK,
-                Json(ApiResponse {
-                    success: true,
-                    data: Some(response_item),
-                    message: "Successfully updated grid item".to_string(),
-                }),
+                Negotiated::new(
+                    accept,
+                    ApiResponse {
+                        success: true,
+                        data: Some(response_item),
+                        message: message.to_string(),
+                    },
+                ),
             )
         }
         None => (
             StatusCode::NOT_FOUND,
-            Json(ApiResponse {
-                success: false,
-                data: None,
-                message: "Specified grid item not found".to_string(),
-            }),
+            Negotiated::new(
+                accept,
+                ApiResponse {
+                    success: false,
+                    data: None,
+                    message: "Specified grid item not found".to_string(),
+                },
+            ),
         ),
     }
 }
 
+/// Delete a grid item; requires admin access
+#[utoipa::path(
+    delete,
+    path = "/grid/{id}",
+    params(("id" = u64, Path, description = "Grid item id")),
+    responses(
+        (status = 200, description = "`success` tells whether the item existed"),
+        (status = 403, description = "No admin access to the item"),
+    )
+)]
 pub async fn delete_by_id(
+    accept: Accept,
+    principal: Principal,
     Path(id): Path<u64>,
     State(state): State<AppState>,
-) -> Json<ApiResponse<()>> {
-    let mut items = state
-        .grid_items
-        .write()
-        .expect("Failed to acquire write lock on grid_items");
-    let initial_len = items.len();
-    items.retain(|item| item.id != id);
+) -> Result<Negotiated<ApiResponse<()>>, AppError> {
+    let removed = state.grid_items.remove(id, |item| {
+        require(item.access_for(&principal), Access::Admin)?;
+        state.journal.record(Mutation::GridItemDelete(id))?;
+        Ok::<_, AppError>(())
+    });
 
-    if items.len() < initial_len {
-        Json(ApiResponse {
-            success: true,
-            data: Some(()),
-            message: "Successfully deleted grid item".to_string(),
-        })
-    } else {
-        Json(ApiResponse {
+    let response = match removed.transpose()? {
+        Some(item) => {
+            state.events.publish(GridEvent::Deleted {
+                item: GridItemResponse::from(&item),
+                reason: DeletionReason::Deleted,
+            });
+            ApiResponse {
+                success: true,
+                data: Some(()),
+                message: "Successfully deleted grid item".to_string(),
+            }
+        }
+        None => ApiResponse {
             success: false,
             data: None,
             message: "Specified grid item not found".to_string(),
-        })
+        },
+    };
+
+    Ok(Negotiated::new(accept, response))
+}
+
+/// Server-sent event stream of changes to the grid items the caller can read
+///
+/// The stream ends when the caller's session is revoked.
+#[utoipa::path(
+    get,
+    path = "/grid/events",
+    responses(
+        (status = 200, description = "`created`, `updated` and `deleted` events carrying the item", content_type = "text/event-stream"),
+    )
+)]
+pub async fn events(
+    principal: Principal,
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let revocation = principal.revocation(&state.sessions, &state.api_keys);
+    let stream = BroadcastStream::new(state.events.subscribe()).filter_map(move |event| {
+        // Subscribers that fall behind skip the events they missed
+        let event = event.ok()?;
+        event.item().access_for(&principal)?;
+        Event::default()
+            .event(event.name())
+            .json_data(&event)
+            .ok()
+            .map(Ok)
+    });
+    let stream = futures_util::StreamExt::take_until(stream, revocation.revoked());
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Delete every expired item, returning how many were removed
+///
+/// Each removal is journaled and published as a `deleted` event with reason `expired`.
+pub fn reap_expired(state: &AppState) -> usize {
+    let now = unix_now();
+    let mut reaped = 0;
+
+    for item in state.grid_items.snapshot() {
+        if !item.is_expired(now) {
+            continue;
+        }
+
+        // Re-check under the lock: the expiry may have been extended meanwhile.
+        // `Err(None)` skips such items, `Err(Some(_))` reports a journal failure.
+        let removed = state.grid_items.remove(item.id, |current| {
+            if !current.is_expired(now) {
+                return Err(None);
+            }
+            state
+                .journal
+                .record(Mutation::GridItemDelete(current.id))
+                .map_err(Some)
+        });
+
+        match removed {
+            Some(Ok(item)) => {
+                reaped += 1;
+                state.events.publish(GridEvent::Deleted {
+                    item: GridItemResponse::from(&item),
+                    reason: DeletionReason::Expired,
+                });
+            }
+            Some(Err(Some(e))) => {
+                tracing::warn!("Failed to reap expired grid item {}: {}", item.id, e);
+            }
+            _ => {}
+        }
+    }
+
+    reaped
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::{AuthConfig, AvatarConfig, LockoutConfig};
+    use crate::handlers::content::ContentFormat;
+    use crate::lockout::LoginGuard;
+    use crate::tokens::TokenService;
+
+    pub(crate) fn state_with(items: Vec<GridItem>) -> AppState {
+        let grid_items = Arc::new(Store::new());
+        for item in items {
+            grid_items.insert(item.id, item);
+        }
+        let directory = UserDirectory::new(Arc::default(), Journal::default());
+        let sessions = SessionStore::new(3600);
+        let tokens = TokenService::new(&AuthConfig {
+            jwt_secret: "test-secret".to_string(),
+            ..AuthConfig::default()
+        });
+        let api_keys = ApiKeyStore::new(Journal::default());
+        let credentials = Credentials::new(
+            directory.clone(),
+            tokens,
+            sessions.clone(),
+            api_keys.clone(),
+            LoginGuard::new(LockoutConfig::default()),
+        );
+        AppState {
+            grid_items,
+            journal: Journal::default(),
+            events: EventBus::default(),
+            sessions,
+            directory,
+            api_keys,
+            credentials,
+            avatars: AvatarStore::new(&AvatarConfig::default()),
+        }
+    }
+
+    fn item(id: u64, expires_at: Option<u64>) -> GridItem {
+        GridItem {
+            id,
+            name: format!("item {id}"),
+            description: String::new(),
+            x: 0,
+            y: 0,
+            expires_at,
+            owner: "alice".to_string(),
+            grants: Vec::new(),
+        }
+    }
+
+    fn principal(subject: &str, groups: &[&str]) -> Principal {
+        Principal {
+            subject: subject.to_string(),
+            roles: groups.iter().map(|g| g.to_string()).collect(),
+            permissions: Default::default(),
+            session_id: None,
+            api_key_id: None,
+        }
+    }
+
+    fn json() -> Accept {
+        Accept(ContentFormat::Json)
+    }
+
+    #[test]
+    fn test_requested_expiry() {
+        assert_eq!(requested_expiry(Some(60), None, 1000).unwrap(), Some(1060));
+        assert_eq!(
+            requested_expiry(None, Some(5000), 1000).unwrap(),
+            Some(5000)
+        );
+        assert_eq!(requested_expiry(None, None, 1000).unwrap(), None);
+        assert!(matches!(
+            requested_expiry(Some(60), Some(5000), 1000).map_err(AppError::into_kind),
+            Err(ErrorKind::ValidationError)
+        ));
+        for past in [5, 1000] {
+            let err = requested_expiry(None, Some(past), 1000).unwrap_err();
+            assert_eq!(err.field_violations()[0].field, "expires_at");
+        }
+
+        assert_eq!(updated_expiry(None, None, 1000).unwrap(), None);
+        assert_eq!(updated_expiry(None, Some(None), 1000).unwrap(), Some(None));
+        assert_eq!(
+            updated_expiry(Some(60), None, 1000).unwrap(),
+            Some(Some(1060))
+        );
+        assert!(updated_expiry(None, Some(Some(5)), 1000).is_err());
+        assert!(updated_expiry(Some(60), Some(None), 1000).is_err());
+    }
+
+    #[test]
+    fn test_update_tells_a_null_expiry_from_a_missing_one() {
+        let update: UpdateGridItem = serde_json::from_str(r#"{"expires_at": null}"#).unwrap();
+        assert_eq!(update.expires_at, Some(None));
+        let update: UpdateGridItem = serde_json::from_str(r#"{"expires_at": 5}"#).unwrap();
+        assert_eq!(update.expires_at, Some(Some(5)));
+        let update: UpdateGridItem = serde_json::from_str(r#"{"name": "a"}"#).unwrap();
+        assert_eq!(update.expires_at, None);
+
+        let message = proto::UpdateGridItem {
+            clear_expiry: true,
+            ..Default::default()
+        };
+        let update = UpdateGridItem::from_proto(message).unwrap();
+        assert_eq!(update.expires_at, Some(None));
+        let message = proto::UpdateGridItem {
+            expires_at: Some(5),
+            clear_expiry: true,
+            ..Default::default()
+        };
+        assert!(UpdateGridItem::from_proto(message).is_err());
+    }
+
+    #[test]
+    fn test_access_levels() {
+        let mut shared = item(1, None);
+        shared.grants = vec![
+            Grant {
+                grantee: Grantee::Group("ops".to_string()),
+                access: Access::Read,
+            },
+            Grant {
+                grantee: Grantee::User("bob".to_string()),
+                access: Access::Write,
+            },
+        ];
+
+        assert_eq!(
+            shared.access_for(&principal("alice", &[])),
+            Some(Access::Admin)
+        );
+        assert_eq!(
+            shared.access_for(&principal("bob", &["ops"])),
+            Some(Access::Write)
+        );
+        assert_eq!(
+            shared.access_for(&principal("carol", &["ops"])),
+            Some(Access::Read)
+        );
+        assert_eq!(shared.access_for(&principal("dave", &["dev"])), None);
+
+        // Items from before owners were recorded belong to the admins
+        shared.owner = String::new();
+        assert_eq!(shared.access_for(&principal("alice", &[])), None);
+        assert_eq!(
+            shared.access_for(&principal("erin", &[ADMIN_ROLE])),
+            Some(Access::Admin)
+        );
+        assert_eq!(
+            shared.access_for(&principal("bob", &["ops"])),
+            Some(Access::Write)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reaper_removes_expired_items_and_emits_events() {
+        let state = state_with(vec![
+            item(1, Some(1)),
+            item(2, None),
+            item(3, Some(u64::MAX)),
+        ]);
+        let mut events = state.events.subscribe();
+
+        assert_eq!(reap_expired(&state), 1);
+        assert!(state.grid_items.get(1).is_none());
+        assert_eq!(state.grid_items.snapshot().len(), 2);
+
+        match events.try_recv().unwrap() {
+            GridEvent::Deleted { item, reason } => {
+                assert_eq!((item.id, reason), (1, DeletionReason::Expired));
+            }
+            _ => panic!("expected a deletion event"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_hides_expired_and_unshared_items() {
+        let mut foreign = item(3, None);
+        foreign.owner = "bob".to_string();
+        let state = state_with(vec![item(1, Some(1)), item(2, None), foreign]);
+
+        let response = list(json(), principal("alice", &[]), State(state)).await;
+        let ids: Vec<u64> = response.body.data.unwrap().iter().map(|i| i.id).collect();
+        assert_eq!(ids, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn test_update_and_delete_enforce_grants() {
+        let mut shared = item(1, None);
+        shared.grants = vec![Grant {
+            grantee: Grantee::User("bob".to_string()),
+            access: Access::Write,
+        }];
+        let state = state_with(vec![shared]);
+        let rename = || UpdateGridItem {
+            name: Some("renamed".to_string()),
+            description: None,
+            x: None,
+            y: None,
+            ttl_seconds: None,
+            expires_at: None,
+        };
+
+        let denied = update(
+            json(),
+            principal("carol", &[]),
+            Path(1),
+            State(state.clone()),
+            Payload(rename()),
+        )
+        .await;
+        assert!(matches!(
+            denied.map_err(AppError::into_kind),
+            Err(ErrorKind::Forbidden)
+        ));
+
+        let (status, _) = update(
+            json(),
+            principal("bob", &[]),
+            Path(1),
+            State(state.clone()),
+            Payload(rename()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(state.grid_items.get(1).unwrap().name, "renamed");
+
+        let denied =
+            delete_by_id(json(), principal("bob", &[]), Path(1), State(state.clone())).await;
+        assert!(matches!(
+            denied.map_err(AppError::into_kind),
+            Err(ErrorKind::Forbidden)
+        ));
+        assert!(state.grid_items.get(1).is_some());
+
+        delete_by_id(
+            json(),
+            principal("alice", &[]),
+            Path(1),
+            State(state.clone()),
+        )
+        .await
+        .unwrap();
+        assert!(state.grid_items.get(1).is_none());
     }
 }
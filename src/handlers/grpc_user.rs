@@ -6,21 +6,129 @@
 //! SPDX-License-Identifier: Apache-2.0
 //! Author: imshike@gmail.com
 
+use crate::api_keys::ApiKeyStore;
+use crate::auth::Principal;
+use crate::errors::{self, ErrorKind};
+use crate::handlers::api_keys;
+use crate::handlers::credentials::{current_password_check, Credentials};
+use crate::handlers::sessions;
+use crate::handlers::users::{grants_access, UserDirectory, UserPatch};
 use crate::protos::user::{user_service_server::UserService, *};
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
+use crate::rbac::Permission;
+use crate::rpc_api::CreateApiKeyParams;
+use crate::sessions::{ClientInfo, Protocol, Revocation, SessionStore};
+use futures_util::{Stream, TryStreamExt};
+use std::collections::HashSet;
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::{Request, Response, Status};
+use tonic::{Request, Response, Status, Streaming};
 
-#[derive(Debug, Default)]
+/// Metadata key that turns `ImportUsers` into a validation-only run
+const DRY_RUN_METADATA: &str = "x-dry-run";
+/// Per-record errors reported in an import summary; further failures are only counted
+const MAX_REPORTED_IMPORT_ERRORS: usize = 100;
+
+#[derive(Debug, Clone)]
 pub struct UserServiceImpl {
-    next_id: Arc<AtomicU32>,
+    directory: UserDirectory,
+    credentials: Credentials,
+    sessions: SessionStore,
+    api_keys: ApiKeyStore,
+}
+
+impl UserServiceImpl {
+    pub fn new(
+        directory: UserDirectory,
+        credentials: Credentials,
+        sessions: SessionStore,
+        api_keys: ApiKeyStore,
+    ) -> Self {
+        Self {
+            directory,
+            credentials,
+            sessions,
+            api_keys,
+        }
+    }
+
+    /// Create, or on a dry run only validate, each user record of `stream`
+    async fn import(
+        &self,
+        mut stream: impl Stream<Item = Result<CreateUserRequest, Status>> + Unpin,
+        dry_run: bool,
+        may_grant_access: bool,
+    ) -> Result<ImportSummary, Status> {
+        // Records are handled as they arrive, so memory use does not grow with
+        // the import beyond the emails a dry run has to remember
+        let mut summary = ImportSummary {
+            dry_run,
+            ..Default::default()
+        };
+        let mut emails = HashSet::new();
+        while let Some(record) = stream.try_next().await? {
+            let index = summary.received;
+            summary.received += 1;
+
+            let result = if grants_access(&record) && !may_grant_access {
+                Err(ErrorKind::Forbidden.into())
+            } else if dry_run {
+                self.directory.validate_batch(&record, &mut emails)
+            } else {
+                self.credentials.create_user(record).await.map(|_| ())
+            };
+            match result {
+                Ok(()) => summary.imported += 1,
+                Err(err) => {
+                    summary.failed += 1;
+                    if summary.errors.len() < MAX_REPORTED_IMPORT_ERRORS {
+                        let field_violations = err
+                            .field_violations()
+                            .iter()
+                            .map(|violation| FieldViolation {
+                                field: violation.field.clone(),
+                                description: violation.description.clone(),
+                            })
+                            .collect();
+                        summary.errors.push(ImportError {
+                            index,
+                            message: err.error_code().message().to_string(),
+                            field_violations,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(summary)
+    }
+}
+
+/// Where a gRPC call came from
+fn client<T>(request: &Request<T>) -> ClientInfo {
+    ClientInfo::new(request.remote_addr().map(|addr| addr.ip()), Protocol::Grpc)
+}
+
+/// The authenticated caller of a call
+fn caller<T>(request: &Request<T>) -> Result<Principal, Status> {
+    request
+        .extensions()
+        .get::<Principal>()
+        .cloned()
+        .ok_or_else(|| ErrorKind::Unauthorized.into())
+}
+
+/// Require `users:admin` of callers that give out roles or permissions
+fn check_access_change<T>(request: &Request<T>, changes_access: bool) -> Result<(), Status> {
+    if changes_access {
+        Principal::require(request.extensions().get(), Permission::UsersAdmin)?;
+    }
+    Ok(())
 }
 
 #[tonic::async_trait]
 impl UserService for UserServiceImpl {
+    type ExportUsersStream = ReceiverStream<Result<User, Status>>;
     type SubscribeUserUpdatesStream = ReceiverStream<Result<UserUpdate, Status>>;
 
     async fn get_user(
@@ -29,55 +137,32 @@ impl UserService for UserServiceImpl {
     ) -> Result<Response<GetUserResponse>, Status> {
         let user_id = request.into_inner().user_id;
 
-        if user_id == 0 {
-            return Err(Status::not_found("User not found"));
-        }
+        let user = self.directory.get(user_id)?;
 
-        Ok(Response::new(GetUserResponse {
-            user: Some(User {
-                id: user_id,
-                name: "John Doe".to_string(),
-                email: "john@example.com".to_string(),
-                age: 30,
-            }),
-        }))
+        Ok(Response::new(GetUserResponse { user: Some(user) }))
     }
 
     async fn create_user(
         &self,
         request: Request<CreateUserRequest>,
     ) -> Result<Response<CreateUserResponse>, Status> {
-        let req = request.into_inner();
-        let user_id = self.next_id.fetch_add(1, Ordering::SeqCst) as i32 + 1;
-
-        Ok(Response::new(CreateUserResponse {
-            user: Some(User {
-                id: user_id,
-                name: req.name,
-                email: req.email,
-                age: req.age,
-            }),
-        }))
+        check_access_change(&request, grants_access(request.get_ref()))?;
+        let user = self.credentials.create_user(request.into_inner()).await?;
+
+        Ok(Response::new(CreateUserResponse { user: Some(user) }))
     }
 
     async fn update_user(
         &self,
         request: Request<UpdateUserRequest>,
     ) -> Result<Response<UpdateUserResponse>, Status> {
+        let patch = UserPatch::from_update_request(request.get_ref())?;
+        check_access_change(&request, patch.changes_access())?;
         let req = request.into_inner();
 
-        if req.user_id == 0 {
-            return Err(Status::not_found("User not found"));
-        }
+        let user = self.directory.update(req.user_id, patch)?;
 
-        Ok(Response::new(UpdateUserResponse {
-            user: Some(User {
-                id: req.user_id,
-                name: req.name,
-                email: req.email,
-                age: req.age,
-            }),
-        }))
+        Ok(Response::new(UpdateUserResponse { user: Some(user) }))
     }
 
     async fn delete_user(
@@ -86,9 +171,7 @@ impl UserService for UserServiceImpl {
     ) -> Result<Response<DeleteUserResponse>, Status> {
         let user_id = request.into_inner().user_id;
 
-        if user_id == 0 {
-            return Err(Status::not_found("User not found"));
-        }
+        self.credentials.delete_user(user_id)?;
 
         Ok(Response::new(DeleteUserResponse {
             success: true,
@@ -96,48 +179,258 @@ impl UserService for UserServiceImpl {
         }))
     }
 
+    async fn set_password(
+        &self,
+        request: Request<SetPasswordRequest>,
+    ) -> Result<Response<SetPasswordResponse>, Status> {
+        let principal = caller(&request)?;
+        let req = request.into_inner();
+
+        let current_password =
+            current_password_check(&principal, req.user_id, &req.current_password)?;
+        self.credentials
+            .set_password(req.user_id, current_password, &req.new_password)
+            .await?;
+
+        Ok(Response::new(SetPasswordResponse {
+            success: true,
+            message: format!("Password of user {} changed", req.user_id),
+        }))
+    }
+
+    async fn login(
+        &self,
+        request: Request<LoginRequest>,
+    ) -> Result<Response<TokenResponse>, Status> {
+        let client = client(&request);
+        let req = request.into_inner();
+
+        let tokens = self
+            .credentials
+            .login(&req.username, &req.password, &client)
+            .await?;
+
+        Ok(Response::new(tokens))
+    }
+
+    async fn refresh_token(
+        &self,
+        request: Request<RefreshTokenRequest>,
+    ) -> Result<Response<TokenResponse>, Status> {
+        let client = client(&request);
+        let tokens = self
+            .credentials
+            .refresh(&request.into_inner().refresh_token, &client)
+            .await?;
+
+        Ok(Response::new(tokens))
+    }
+
+    async fn unlock_login(
+        &self,
+        request: Request<UnlockLoginRequest>,
+    ) -> Result<Response<UnlockLoginResponse>, Status> {
+        let req = request.into_inner();
+
+        let username = Some(req.username.as_str()).filter(|username| !username.is_empty());
+        let ip = match req.ip.as_str() {
+            "" => None,
+            ip => Some(ip.parse().map_err(|_| {
+                ErrorKind::InvalidFields(vec![errors::FieldViolation::new(
+                    "ip",
+                    "must be an IP address",
+                )])
+            })?),
+        };
+        if username.is_none() && ip.is_none() {
+            return Err(ErrorKind::InvalidFields(vec![errors::FieldViolation::new(
+                "username",
+                "username or ip is required",
+            )])
+            .into());
+        }
+
+        let unlocked = self.credentials.unlock_login(username, ip);
+
+        Ok(Response::new(UnlockLoginResponse { unlocked }))
+    }
+
+    async fn list_sessions(
+        &self,
+        request: Request<ListSessionsRequest>,
+    ) -> Result<Response<ListSessionsResponse>, Status> {
+        let principal = caller(&request)?;
+        let user_id = Some(request.into_inner().user_id).filter(|id| *id != 0);
+
+        let sessions = sessions::list(&self.sessions, &principal, user_id)?;
+
+        Ok(Response::new(ListSessionsResponse { sessions }))
+    }
+
+    async fn revoke_session(
+        &self,
+        request: Request<RevokeSessionRequest>,
+    ) -> Result<Response<RevokeSessionsResponse>, Status> {
+        let principal = caller(&request)?;
+        let session_id = request.into_inner().session_id;
+
+        sessions::revoke(&self.sessions, &principal, &session_id)?;
+
+        Ok(Response::new(RevokeSessionsResponse { revoked: 1 }))
+    }
+
+    async fn revoke_sessions(
+        &self,
+        request: Request<RevokeSessionsRequest>,
+    ) -> Result<Response<RevokeSessionsResponse>, Status> {
+        let principal = caller(&request)?;
+        let user_id = Some(request.into_inner().user_id).filter(|id| *id != 0);
+
+        let revoked = sessions::revoke_all(&self.sessions, &principal, user_id)?;
+
+        Ok(Response::new(RevokeSessionsResponse { revoked }))
+    }
+
+    async fn create_api_key(
+        &self,
+        request: Request<CreateApiKeyRequest>,
+    ) -> Result<Response<ApiKeySecret>, Status> {
+        let principal = caller(&request)?;
+        let req = request.into_inner();
+        let params = CreateApiKeyParams {
+            user_id: Some(req.user_id).filter(|id| *id != 0),
+            name: req.name,
+            scopes: req.scopes,
+        };
+
+        let issued = api_keys::create(&self.api_keys, &self.directory, &principal, params)?;
+
+        Ok(Response::new(issued))
+    }
+
+    async fn list_api_keys(
+        &self,
+        request: Request<ListApiKeysRequest>,
+    ) -> Result<Response<ListApiKeysResponse>, Status> {
+        let principal = caller(&request)?;
+        let user_id = Some(request.into_inner().user_id).filter(|id| *id != 0);
+
+        let api_keys = api_keys::list(&self.api_keys, &principal, user_id)?;
+
+        Ok(Response::new(ListApiKeysResponse { api_keys }))
+    }
+
+    async fn rotate_api_key(
+        &self,
+        request: Request<RotateApiKeyRequest>,
+    ) -> Result<Response<ApiKeySecret>, Status> {
+        let principal = caller(&request)?;
+        let key_id = request.into_inner().key_id;
+
+        let rotated = api_keys::rotate(&self.api_keys, &principal, &key_id)?;
+
+        Ok(Response::new(rotated))
+    }
+
+    async fn revoke_api_key(
+        &self,
+        request: Request<RevokeApiKeyRequest>,
+    ) -> Result<Response<RevokeApiKeyResponse>, Status> {
+        let principal = caller(&request)?;
+        let key_id = request.into_inner().key_id;
+
+        let api_key = api_keys::revoke(&self.api_keys, &principal, &key_id)?;
+
+        Ok(Response::new(RevokeApiKeyResponse {
+            api_key: Some(api_key),
+        }))
+    }
+
+    async fn list_users(
+        &self,
+        request: Request<ListUsersRequest>,
+    ) -> Result<Response<ListUsersResponse>, Status> {
+        let response = self.directory.list(request.into_inner())?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn import_users(
+        &self,
+        request: Request<Streaming<CreateUserRequest>>,
+    ) -> Result<Response<ImportSummary>, Status> {
+        let dry_run = request
+            .metadata()
+            .get(DRY_RUN_METADATA)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.eq_ignore_ascii_case("true"));
+        let may_grant_access = check_access_change(&request, true).is_ok();
+        let summary = self
+            .import(request.into_inner(), dry_run, may_grant_access)
+            .await?;
+
+        Ok(Response::new(summary))
+    }
+
+    async fn export_users(
+        &self,
+        request: Request<ExportRequest>,
+    ) -> Result<Response<Self::ExportUsersStream>, Status> {
+        let filter = request.into_inner().filter.unwrap_or_default();
+        let directory = self.directory.clone();
+
+        // The bounded channel holds back the store scan until the client catches up
+        let (tx, rx) = mpsc::channel(32);
+
+        tokio::spawn(async move {
+            let mut cursor = Some(0);
+            while let Some(after) = cursor {
+                let (users, next) = directory.export_page(&filter, after);
+                for user in users {
+                    if tx.send(Ok(user)).await.is_err() {
+                        return;
+                    }
+                }
+                cursor = next;
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
     async fn subscribe_user_updates(
         &self,
         request: Request<SubscribeRequest>,
     ) -> Result<Response<Self::SubscribeUserUpdatesStream>, Status> {
-        let req = request.into_inner();
-        let user_id = req.user_id;
-        let interval = if req.interval_seconds > 0 {
-            Duration::from_secs(req.interval_seconds as u64)
-        } else {
-            Duration::from_secs(2)
+        // The stream ends as soon as the caller's session or API key is revoked
+        let revocation = match request.extensions().get::<Principal>() {
+            Some(principal) => principal.revocation(&self.sessions, &self.api_keys),
+            None => Revocation::never(),
         };
+        let req = request.into_inner();
+        let keepalive = u64::try_from(req.interval_seconds)
+            .ok()
+            .map(Duration::from_secs);
+        let mut subscription = self.directory.subscribe(req.user_id, keepalive);
 
         let (tx, rx) = mpsc::channel(32);
 
         tokio::spawn(async move {
-            let mut counter = 0;
+            let revoked = revocation.revoked();
+            tokio::pin!(revoked);
             loop {
-                tokio::time::sleep(interval).await;
-
-                counter += 1;
-                let timestamp = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-
-                let update_type = match counter % 3 {
-                    0 => "profile_update",
-                    1 => "activity_update",
-                    _ => "status_update",
+                let user_update = tokio::select! {
+                    _ = &mut revoked => {
+                        let _ = tx.send(Err(Status::unauthenticated("Session or API key revoked"))).await;
+                        break;
+                    }
+                    // Without heartbeats nothing else notices the client leaving
+                    _ = tx.closed() => break,
+                    user_update = subscription.next() => user_update,
                 };
-
-                let user_update = UserUpdate {
-                    user: Some(User {
-                        id: user_id,
-                        name: format!("User {}", user_id),
-                        email: format!("user{}@example.com", user_id),
-                        age: 30 + (counter % 10),
-                    }),
-                    update_type: update_type.to_string(),
-                    timestamp: timestamp as i64,
+                let Some(user_update) = user_update else {
+                    break;
                 };
-
                 if tx.send(Ok(user_update)).await.is_err() {
                     break;
                 }
@@ -147,3 +440,131 @@ impl UserService for UserServiceImpl {
         Ok(Response::new(ReceiverStream::new(rx)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AuthConfig, LockoutConfig};
+    use crate::journal::Journal;
+    use crate::lockout::LoginGuard;
+    use crate::tokens::TokenService;
+    use std::sync::Arc;
+
+    fn service() -> UserServiceImpl {
+        let directory = UserDirectory::new(Arc::default(), Journal::default());
+        let sessions = SessionStore::new(3600);
+        let tokens = TokenService::new(&AuthConfig {
+            jwt_secret: "test-secret".to_string(),
+            ..AuthConfig::default()
+        });
+        let api_keys = ApiKeyStore::new(Journal::default());
+        let credentials = Credentials::new(
+            directory.clone(),
+            tokens,
+            sessions.clone(),
+            api_keys.clone(),
+            LoginGuard::new(LockoutConfig::default()),
+        );
+        UserServiceImpl::new(directory, credentials, sessions, api_keys)
+    }
+
+    #[tokio::test]
+    async fn test_update_subscriptions_end_when_the_client_goes_away() {
+        let service = service();
+        let request = Request::new(SubscribeRequest {
+            user_id: 0,
+            interval_seconds: 0,
+        });
+        let stream = service.subscribe_user_updates(request).await.unwrap();
+        assert_eq!(service.directory.subscriber_count(), 1);
+
+        drop(stream);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while service.directory.subscriber_count() > 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("the subscription outlived its client");
+    }
+
+    #[tokio::test]
+    async fn test_update_subscriptions_end_when_their_api_key_is_revoked() {
+        use tokio_stream::StreamExt;
+
+        let service = service();
+        let issued = service.api_keys.create(1, "watcher", Vec::new()).unwrap();
+        let key_id = issued.api_key.unwrap().id;
+        let mut request = Request::new(SubscribeRequest {
+            user_id: 0,
+            interval_seconds: 0,
+        });
+        request.extensions_mut().insert(Principal {
+            subject: "1".to_string(),
+            roles: Vec::new(),
+            permissions: Default::default(),
+            session_id: None,
+            api_key_id: Some(key_id.clone()),
+        });
+        let mut stream = service
+            .subscribe_user_updates(request)
+            .await
+            .unwrap()
+            .into_inner();
+
+        service.api_keys.revoke(&key_id).unwrap();
+        let ended = tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("the stream outlived its key");
+        assert_eq!(
+            ended.unwrap().unwrap_err().code(),
+            tonic::Code::Unauthenticated
+        );
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_import_reports_the_fields_to_fix() {
+        let service = service();
+        let record = |email: &str, age| {
+            Ok(CreateUserRequest {
+                name: "Eve".to_string(),
+                email: email.to_string(),
+                age,
+                ..Default::default()
+            })
+        };
+
+        for dry_run in [true, false] {
+            let records = vec![
+                record("eve@example.com", 30),
+                record("not an email", 30),
+                record("EVE@example.com", 30),
+                record("young@example.com", -1),
+            ];
+            let summary = service
+                .import(futures_util::stream::iter(records), dry_run, false)
+                .await
+                .unwrap();
+            assert_eq!((summary.received, summary.imported), (4, 1));
+
+            let reported: Vec<_> = summary
+                .errors
+                .iter()
+                .map(|error| {
+                    let fields: Vec<_> = error
+                        .field_violations
+                        .iter()
+                        .map(|violation| violation.field.as_str())
+                        .collect();
+                    (error.index, fields)
+                })
+                .collect();
+            assert_eq!(
+                reported,
+                vec![(1, vec!["email"]), (2, vec!["email"]), (3, vec!["age"])],
+                "dry run: {dry_run}"
+            );
+        }
+    }
+}
@@ -6,7 +6,13 @@
 //! SPDX-License-Identifier: Apache-2.0
 //! Author: imshike@gmail.com
 
+pub mod api_keys;
+pub mod content;
+pub mod credentials;
 pub mod grid;
 pub mod grpc_helloworld;
 pub mod grpc_user;
+pub mod sessions;
 pub mod user_info;
+pub mod users;
+pub mod users_rest;
@@ -0,0 +1,212 @@
+//! Session management handler
+//!
+//! Lists and revokes login sessions on behalf of a caller, shared by the REST,
+//! JSON-RPC and gRPC APIs. Users manage their own sessions; callers with the
+//! `users:admin` permission manage anyone's. Leaving out the user id means the
+//! caller's own sessions.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use crate::auth::Principal;
+use crate::errors::{AppError, ErrorKind};
+use crate::handlers::grid::AppState;
+use crate::protos::user::{ListSessionsResponse, RevokeSessionsResponse, Session};
+use crate::rbac::Permission;
+use crate::sessions::SessionStore;
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use serde::Deserialize;
+
+/// Query of the REST session routes
+#[derive(Debug, Default, Deserialize)]
+pub struct SessionQuery {
+    #[serde(default)]
+    pub user_id: Option<i32>,
+}
+
+fn mark_current(mut session: Session, principal: &Principal) -> Session {
+    session.current = principal.session_id.as_deref() == Some(session.id.as_str());
+    session
+}
+
+/// Sessions of `user_id`, or of the caller, oldest first
+pub fn list(
+    sessions: &SessionStore,
+    principal: &Principal,
+    user_id: Option<i32>,
+) -> Result<Vec<Session>, AppError> {
+    let user_id = principal.target_user(user_id)?;
+    Ok(sessions
+        .list(user_id)
+        .into_iter()
+        .map(|session| mark_current(session, principal))
+        .collect())
+}
+
+/// Revoke one session, returning it
+///
+/// Other users' sessions look missing to callers who may not manage them.
+pub fn revoke(
+    sessions: &SessionStore,
+    principal: &Principal,
+    session_id: &str,
+) -> Result<Session, AppError> {
+    let session = sessions.get(session_id).ok_or(ErrorKind::NotFound)?;
+    if Some(session.user_id) != principal.user_id() && !principal.can(Permission::UsersAdmin) {
+        return Err(ErrorKind::NotFound.into());
+    }
+    let session = sessions.revoke(session_id).ok_or(ErrorKind::NotFound)?;
+    tracing::info!(
+        "Session {} of user {} revoked by {}",
+        session.id,
+        session.user_id,
+        principal.subject
+    );
+    Ok(mark_current(session, principal))
+}
+
+/// Revoke every session of `user_id`, or of the caller, returning how many
+pub fn revoke_all(
+    sessions: &SessionStore,
+    principal: &Principal,
+    user_id: Option<i32>,
+) -> Result<u32, AppError> {
+    let user_id = principal.target_user(user_id)?;
+    let revoked = sessions.revoke_user(user_id);
+    tracing::info!(
+        "{} sessions of user {} revoked by {}",
+        revoked,
+        user_id,
+        principal.subject
+    );
+    Ok(u32::try_from(revoked).unwrap_or(u32::MAX))
+}
+
+/// `GET /sessions`
+#[utoipa::path(
+    get,
+    path = "/sessions",
+    params(("user_id" = Option<i32>, Query, description = "Owner of the sessions; the caller if left out")),
+    responses(
+        (status = 200, description = "The user's live sessions", body = ListSessionsResponse),
+        (status = 403, description = "Another user's sessions without `users:admin`"),
+    )
+)]
+pub async fn list_sessions(
+    principal: Principal,
+    Query(query): Query<SessionQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<ListSessionsResponse>, AppError> {
+    let sessions = list(&state.sessions, &principal, query.user_id)?;
+    Ok(Json(ListSessionsResponse { sessions }))
+}
+
+/// `DELETE /sessions/{id}`
+#[utoipa::path(
+    delete,
+    path = "/sessions/{id}",
+    params(("id" = String, Path, description = "Session id")),
+    responses(
+        (status = 200, description = "The session was revoked", body = RevokeSessionsResponse),
+        (status = 404, description = "No such session"),
+    )
+)]
+pub async fn revoke_session(
+    principal: Principal,
+    Path(session_id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<RevokeSessionsResponse>, AppError> {
+    revoke(&state.sessions, &principal, &session_id)?;
+    Ok(Json(RevokeSessionsResponse { revoked: 1 }))
+}
+
+/// `DELETE /sessions`
+#[utoipa::path(
+    delete,
+    path = "/sessions",
+    params(("user_id" = Option<i32>, Query, description = "Owner of the sessions; the caller if left out")),
+    responses(
+        (status = 200, description = "How many sessions were revoked", body = RevokeSessionsResponse),
+        (status = 403, description = "Another user's sessions without `users:admin`"),
+    )
+)]
+pub async fn revoke_sessions(
+    principal: Principal,
+    Query(query): Query<SessionQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<RevokeSessionsResponse>, AppError> {
+    let revoked = revoke_all(&state.sessions, &principal, query.user_id)?;
+    Ok(Json(RevokeSessionsResponse { revoked }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sessions::{ClientInfo, Protocol};
+    use std::collections::BTreeSet;
+
+    fn login(sessions: &SessionStore, user_id: i32, permissions: &[Permission]) -> Principal {
+        let session = sessions.create(user_id, &ClientInfo::new(None, Protocol::Rest));
+        Principal {
+            subject: user_id.to_string(),
+            roles: Vec::new(),
+            permissions: permissions.iter().copied().collect(),
+            session_id: Some(session.id),
+            api_key_id: None,
+        }
+    }
+
+    #[test]
+    fn test_users_manage_only_their_own_sessions() {
+        let sessions = SessionStore::new(3600);
+        let alice = login(&sessions, 1, &[]);
+        let other_device = login(&sessions, 1, &[]);
+        let bob = login(&sessions, 2, &[]);
+
+        let own = list(&sessions, &alice, None).unwrap();
+        assert_eq!(own.len(), 2);
+        assert_eq!(own.iter().filter(|session| session.current).count(), 1);
+        assert!(matches!(
+            list(&sessions, &alice, Some(2)).map_err(AppError::into_kind),
+            Err(ErrorKind::Forbidden)
+        ));
+
+        let bob_session = bob.session_id.as_deref().unwrap();
+        assert!(matches!(
+            revoke(&sessions, &alice, bob_session).map_err(AppError::into_kind),
+            Err(ErrorKind::NotFound)
+        ));
+        let other_session = other_device.session_id.as_deref().unwrap();
+        assert!(!revoke(&sessions, &alice, other_session).unwrap().current);
+
+        assert_eq!(revoke_all(&sessions, &alice, None).unwrap(), 1);
+        assert_eq!(sessions.list(2).len(), 1);
+    }
+
+    #[test]
+    fn test_admins_manage_anyones_sessions() {
+        let sessions = SessionStore::new(3600);
+        let admin = login(&sessions, 1, &[Permission::UsersAdmin]);
+        let bob = login(&sessions, 2, &[]);
+        login(&sessions, 2, &[]);
+
+        assert_eq!(list(&sessions, &admin, Some(2)).unwrap().len(), 2);
+        revoke(&sessions, &admin, bob.session_id.as_deref().unwrap()).unwrap();
+        assert_eq!(revoke_all(&sessions, &admin, Some(2)).unwrap(), 1);
+        assert!(sessions.list(2).is_empty());
+
+        let proxied = Principal {
+            subject: "ops".to_string(),
+            roles: Vec::new(),
+            permissions: BTreeSet::from([Permission::UsersAdmin]),
+            session_id: None,
+            api_key_id: None,
+        };
+        assert!(list(&sessions, &proxied, None).is_err());
+        assert_eq!(list(&sessions, &proxied, Some(1)).unwrap().len(), 1);
+    }
+}
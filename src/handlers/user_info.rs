@@ -6,53 +6,217 @@
 //! SPDX-License-Identifier: Apache-2.0
 //! Author: imshike@gmail.com
 
+use crate::api_keys::ApiKeyStore;
+use crate::auth::Principal;
+use crate::errors::{ErrorKind, FieldViolation};
+use crate::handlers::api_keys;
+use crate::handlers::credentials::{current_password_check, Credentials};
+use crate::handlers::sessions;
+use crate::handlers::users::{UserDirectory, UserPatch};
+use crate::protos::user::{
+    ApiKeySecret, ListApiKeysResponse, ListSessionsResponse, ListUsersRequest, ListUsersResponse,
+    RevokeApiKeyResponse, RevokeSessionsResponse, SetPasswordResponse, TokenResponse,
+    UnlockLoginResponse,
+};
+use crate::rbac::Permission;
+use crate::rpc_api::{
+    ApiKeyParams, ApiKeyQuery, CreateApiKeyParams, RefreshTokenParams, RevokeSessionParams,
+    SessionsParams, SetPasswordParams, UnlockLoginParams, UpdateUserInfoParams,
+    UpdateUserInfoResponse, UserInfo, VerifyCredentialsParams, VerifyCredentialsResponse,
+};
+use crate::sessions::{ClientInfo, SessionStore};
 use jsonrpsee::types::ErrorObjectOwned;
-use serde_json::json;
+
+impl From<UpdateUserInfoParams> for UserPatch {
+    fn from(params: UpdateUserInfoParams) -> Self {
+        Self {
+            name: params.name,
+            email: params.email,
+            age: params.age,
+            roles: params.roles,
+            permissions: params.permissions,
+        }
+    }
+}
 
 /// Get user information
-pub async fn get_user_info() -> Result<serde_json::Value, ErrorObjectOwned> {
-    Ok(json!({
-        "name": "John Doe",
-        "age": 30,
-        "email": "john@example.com",
-        "status": "active"
-    }))
+pub async fn get_user_info() -> Result<UserInfo, ErrorObjectOwned> {
+    Ok(UserInfo {
+        name: "John Doe".to_string(),
+        age: 30,
+        email: "john@example.com".to_string(),
+        status: "active".to_string(),
+    })
 }
 
-/// Update user information
+/// Update user information, changing only the provided fields
+///
+/// Changing roles or permissions takes `users:admin`.
 pub async fn update_user_info(
-    params: serde_json::Value,
-) -> Result<serde_json::Value, ErrorObjectOwned> {
-    let name = params
-        .get("name")
-        .and_then(|v: &serde_json::Value| v.as_str())
-        .unwrap_or("");
-    let age = params
-        .get("age")
-        .and_then(|v: &serde_json::Value| v.as_u64())
-        .unwrap_or(0);
-
-    Ok(json!({
-        "success": true,
-        "message": format!("User information updated: {} ({} years old)", name, age)
-    }))
-}
-
-/// Verify user credentials
+    directory: &UserDirectory,
+    principal: Option<&Principal>,
+    params: UpdateUserInfoParams,
+) -> Result<UpdateUserInfoResponse, ErrorObjectOwned> {
+    let user_id = params.user_id;
+    let patch = UserPatch::from(params);
+    if patch.changes_access() {
+        Principal::require(principal, Permission::UsersAdmin)?;
+    }
+    let user = directory.update(user_id, patch)?;
+
+    Ok(UpdateUserInfoResponse {
+        success: true,
+        message: format!("User {} updated", user.id),
+        user,
+    })
+}
+
+/// List users page by page, mirroring the gRPC `ListUsers` call
+pub async fn list_users(
+    directory: &UserDirectory,
+    request: ListUsersRequest,
+) -> Result<ListUsersResponse, ErrorObjectOwned> {
+    directory.list(request).map_err(Into::into)
+}
+
+/// Verify user credentials, returning an access and refresh token on success
 pub async fn verify_credentials(
-    params: serde_json::Value,
-) -> Result<serde_json::Value, ErrorObjectOwned> {
-    let username = params
-        .get("username")
-        .and_then(|v: &serde_json::Value| v.as_str())
-        .unwrap_or("");
-    let password = params
-        .get("password")
-        .and_then(|v: &serde_json::Value| v.as_str())
-        .unwrap_or("");
-
-    Ok(json!({
-        "authenticated": username == "admin" && password == "123456",
-        "token": "sample-jwt-token"
-    }))
+    credentials: &Credentials,
+    params: VerifyCredentialsParams,
+    client: &ClientInfo,
+) -> Result<VerifyCredentialsResponse, ErrorObjectOwned> {
+    let tokens = credentials
+        .login(&params.username, &params.password, client)
+        .await?;
+
+    Ok(VerifyCredentialsResponse {
+        authenticated: true,
+        tokens,
+    })
+}
+
+/// Lift the login throttling of a username and/or client address
+pub async fn unlock_login(
+    credentials: &Credentials,
+    params: UnlockLoginParams,
+) -> Result<UnlockLoginResponse, ErrorObjectOwned> {
+    if params.username.is_none() && params.ip.is_none() {
+        return Err(ErrorKind::InvalidFields(vec![FieldViolation::new(
+            "username",
+            "username or ip is required",
+        )])
+        .into());
+    }
+
+    let unlocked = credentials.unlock_login(params.username.as_deref(), params.ip);
+    Ok(UnlockLoginResponse { unlocked })
+}
+
+/// Exchange a refresh token for a new token pair
+pub async fn refresh_token(
+    credentials: &Credentials,
+    params: RefreshTokenParams,
+    client: &ClientInfo,
+) -> Result<TokenResponse, ErrorObjectOwned> {
+    credentials
+        .refresh(&params.refresh_token, client)
+        .await
+        .map_err(Into::into)
+}
+
+/// Change a user's password; see `current_password_check` for who may
+pub async fn set_password(
+    credentials: &Credentials,
+    principal: Option<&Principal>,
+    params: SetPasswordParams,
+) -> Result<SetPasswordResponse, ErrorObjectOwned> {
+    let principal = principal.ok_or(ErrorKind::Unauthorized)?;
+    let current_password =
+        current_password_check(principal, params.user_id, &params.current_password)?;
+    credentials
+        .set_password(params.user_id, current_password, &params.new_password)
+        .await?;
+
+    Ok(SetPasswordResponse {
+        success: true,
+        message: format!("Password of user {} changed", params.user_id),
+    })
+}
+
+/// List the login sessions of a user, by default the caller
+pub async fn list_sessions(
+    store: &SessionStore,
+    principal: Option<&Principal>,
+    params: SessionsParams,
+) -> Result<ListSessionsResponse, ErrorObjectOwned> {
+    let principal = principal.ok_or(ErrorKind::Unauthorized)?;
+    let sessions = sessions::list(store, principal, params.user_id)?;
+    Ok(ListSessionsResponse { sessions })
+}
+
+/// Revoke one login session
+pub async fn revoke_session(
+    store: &SessionStore,
+    principal: Option<&Principal>,
+    params: RevokeSessionParams,
+) -> Result<RevokeSessionsResponse, ErrorObjectOwned> {
+    let principal = principal.ok_or(ErrorKind::Unauthorized)?;
+    sessions::revoke(store, principal, &params.session_id)?;
+    Ok(RevokeSessionsResponse { revoked: 1 })
+}
+
+/// Revoke every login session of a user, by default the caller
+pub async fn revoke_sessions(
+    store: &SessionStore,
+    principal: Option<&Principal>,
+    params: SessionsParams,
+) -> Result<RevokeSessionsResponse, ErrorObjectOwned> {
+    let principal = principal.ok_or(ErrorKind::Unauthorized)?;
+    let revoked = sessions::revoke_all(store, principal, params.user_id)?;
+    Ok(RevokeSessionsResponse { revoked })
+}
+
+/// Issue an API key for the caller, or for another user for admins
+pub async fn create_api_key(
+    store: &ApiKeyStore,
+    directory: &UserDirectory,
+    principal: Option<&Principal>,
+    params: CreateApiKeyParams,
+) -> Result<ApiKeySecret, ErrorObjectOwned> {
+    let principal = principal.ok_or(ErrorKind::Unauthorized)?;
+    api_keys::create(store, directory, principal, params).map_err(Into::into)
+}
+
+/// List the API keys of a user, by default the caller
+pub async fn list_api_keys(
+    store: &ApiKeyStore,
+    principal: Option<&Principal>,
+    params: ApiKeyQuery,
+) -> Result<ListApiKeysResponse, ErrorObjectOwned> {
+    let principal = principal.ok_or(ErrorKind::Unauthorized)?;
+    let api_keys = api_keys::list(store, principal, params.user_id)?;
+    Ok(ListApiKeysResponse { api_keys })
+}
+
+/// Give an API key a new secret
+pub async fn rotate_api_key(
+    store: &ApiKeyStore,
+    principal: Option<&Principal>,
+    params: ApiKeyParams,
+) -> Result<ApiKeySecret, ErrorObjectOwned> {
+    let principal = principal.ok_or(ErrorKind::Unauthorized)?;
+    api_keys::rotate(store, principal, &params.key_id).map_err(Into::into)
+}
+
+/// Revoke an API key
+pub async fn revoke_api_key(
+    store: &ApiKeyStore,
+    principal: Option<&Principal>,
+    params: ApiKeyParams,
+) -> Result<RevokeApiKeyResponse, ErrorObjectOwned> {
+    let principal = principal.ok_or(ErrorKind::Unauthorized)?;
+    let api_key = api_keys::revoke(store, principal, &params.key_id)?;
+    Ok(RevokeApiKeyResponse {
+        api_key: Some(api_key),
+    })
 }
@@ -0,0 +1,1250 @@
+//! User directory module
+//!
+//! User business logic shared by the gRPC, JSON-RPC and REST user APIs.
+//!
+//! Every create, update and delete is published as a `UserUpdate` on the
+//! directory's event bus, which backs the gRPC and JSON-RPC subscriptions.
+//!
+//! Names, emails and ages are validated on every create and update, and emails
+//! are unique across users ignoring case. Roles must be short names without
+//! whitespace or commas, and permissions must be ones `rbac` knows. Rejections
+//! carry one `FieldViolation` per offending field.
+//!
+//! Password hashes live beside the user records rather than in them, so they
+//! never leave the directory through the user APIs.
+//!
+//! Updates are partial: a `UserPatch` names the fields to change and leaves the
+//! rest of the record alone.
+//!
+//! Listing uses keyset pagination: the opaque page token records the sort key
+//! of the last user returned, so pages stay consistent while users are created
+//! or deleted between requests. Tokens are bound to the filter and ordering they
+//! were issued for and are rejected when reused with a different query.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use crate::avatars;
+use crate::errors::{AppError, ErrorKind, FieldViolation};
+use crate::events::EventBus;
+use crate::journal::{Journal, Mutation};
+use crate::protos::user::{
+    CreateUserRequest, ListUsersRequest, ListUsersResponse, UpdateUserRequest, User, UserFilter,
+    UserUpdate,
+};
+use crate::rbac::Permission;
+use crate::store::Store;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use dashmap::{mapref::entry::Entry, DashMap};
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::collections::HashSet;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use utoipa::ToSchema;
+
+/// `update_type` values carried by published user updates
+pub const USER_CREATED: &str = "created";
+pub const USER_UPDATED: &str = "updated";
+pub const USER_DELETED: &str = "deleted";
+/// `update_type` of the synthetic keepalive sent to idle subscribers
+pub const HEARTBEAT: &str = "heartbeat";
+
+/// Number of users fetched from the store at a time by `export_page`
+pub const EXPORT_PAGE_SIZE: usize = 256;
+
+/// Longest accepted user name, in characters
+const MAX_NAME_LEN: usize = 100;
+/// Longest accepted email address (RFC 5321)
+const MAX_EMAIL_LEN: usize = 254;
+/// Accepted ages, inclusive
+const AGE_RANGE: RangeInclusive<i32> = 0..=150;
+/// Accepted password lengths, in characters
+const PASSWORD_LEN: RangeInclusive<usize> = 8..=128;
+/// Longest accepted role name, in characters
+const MAX_ROLE_LEN: usize = 64;
+/// Email index entry held by a user being created, before it has an id
+const PENDING_USER: i32 = -1;
+
+/// Page size used when the request leaves it unset
+const DEFAULT_PAGE_SIZE: usize = 50;
+/// Larger page sizes are clamped to this value
+const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SortField {
+    Id,
+    Name,
+    Email,
+    Age,
+}
+
+/// Parsed `order_by` clause
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Ordering {
+    field: SortField,
+    descending: bool,
+}
+
+impl Ordering {
+    fn parse(order_by: &str) -> Result<Self, AppError> {
+        let mut parts = order_by.split_whitespace();
+        let field = match parts.next() {
+            None | Some("id") => SortField::Id,
+            Some("name") => SortField::Name,
+            Some("email") => SortField::Email,
+            Some("age") => SortField::Age,
+            Some(field) => {
+                return Err(invalid(
+                    "order_by",
+                    format!("cannot sort by `{field}`; use id, name, email or age"),
+                ))
+            }
+        };
+        let descending = match parts.next() {
+            None | Some("asc") => false,
+            Some("desc") => true,
+            Some(direction) => {
+                return Err(invalid(
+                    "order_by",
+                    format!("unknown direction `{direction}`; use asc or desc"),
+                ))
+            }
+        };
+        if parts.next().is_some() {
+            return Err(invalid(
+                "order_by",
+                "expected a field and optional direction",
+            ));
+        }
+        Ok(Self { field, descending })
+    }
+
+    /// Compare by the sort field, breaking ties by id
+    fn compare(&self, a: &User, b: &User) -> cmp::Ordering {
+        let by_field = match self.field {
+            SortField::Id => cmp::Ordering::Equal,
+            SortField::Name => a.name.cmp(&b.name),
+            SortField::Email => a.email.cmp(&b.email),
+            SortField::Age => a.age.cmp(&b.age),
+        };
+        let ordering = by_field.then(a.id.cmp(&b.id));
+        if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+/// Decoded contents of a page token
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct PageToken {
+    /// Fingerprint of the filter and ordering the token was issued for
+    query: u32,
+    id: i32,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    age: i32,
+}
+
+impl PageToken {
+    fn after(user: &User, ordering: Ordering, query: u32) -> Self {
+        let text = match ordering.field {
+            SortField::Name => user.name.clone(),
+            SortField::Email => user.email.clone(),
+            SortField::Id | SortField::Age => String::new(),
+        };
+        Self {
+            query,
+            id: user.id,
+            text,
+            age: user.age,
+        }
+    }
+
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(token: &str) -> Result<Self, AppError> {
+        URL_SAFE_NO_PAD
+            .decode(token)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(|| invalid("page_token", "malformed page token"))
+    }
+
+    /// Stand-in user carrying the cursor's sort key, for comparisons
+    fn cursor(&self, ordering: Ordering) -> User {
+        let mut user = User {
+            id: self.id,
+            age: self.age,
+            ..Default::default()
+        };
+        match ordering.field {
+            SortField::Name => user.name = self.text.clone(),
+            SortField::Email => user.email = self.text.clone(),
+            SortField::Id | SortField::Age => {}
+        }
+        user
+    }
+}
+
+fn invalid(field: &str, description: impl Into<String>) -> AppError {
+    ErrorKind::InvalidFields(vec![FieldViolation::new(field, description)]).into()
+}
+
+fn email_taken() -> AppError {
+    ErrorKind::AlreadyExists(vec![FieldViolation::new(
+        "email",
+        "email is already registered to another user",
+    )])
+    .into()
+}
+
+/// Normalized form of an email used for uniqueness checks
+fn email_key(email: &str) -> String {
+    email.to_lowercase()
+}
+
+/// Structural email check: one `@`, a non-empty local part and a dotted domain
+fn is_valid_email(email: &str) -> bool {
+    let Some((local, domain)) = email.split_once('@') else {
+        return false;
+    };
+    let domain_is_valid = domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+        });
+    !local.is_empty()
+        && domain_is_valid
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Violations among the user fields being set; `None` fields are not checked
+fn field_violations(
+    name: Option<&str>,
+    email: Option<&str>,
+    age: Option<i32>,
+) -> Vec<FieldViolation> {
+    let mut violations = Vec::new();
+    if let Some(name) = name {
+        if name.trim().is_empty() {
+            violations.push(FieldViolation::new("name", "name must not be empty"));
+        } else if name.chars().count() > MAX_NAME_LEN {
+            violations.push(FieldViolation::new(
+                "name",
+                format!("name must be at most {MAX_NAME_LEN} characters"),
+            ));
+        }
+    }
+    if let Some(email) = email {
+        if email.len() > MAX_EMAIL_LEN {
+            violations.push(FieldViolation::new(
+                "email",
+                format!("email must be at most {MAX_EMAIL_LEN} bytes"),
+            ));
+        } else if !is_valid_email(email) {
+            violations.push(FieldViolation::new("email", "email is not a valid address"));
+        }
+    }
+    if let Some(age) = age {
+        if !AGE_RANGE.contains(&age) {
+            violations.push(FieldViolation::new(
+                "age",
+                format!(
+                    "age must be between {} and {}",
+                    AGE_RANGE.start(),
+                    AGE_RANGE.end()
+                ),
+            ));
+        }
+    }
+    violations
+}
+
+/// Violations among the roles and permissions being set
+fn access_violations(
+    roles: Option<&[String]>,
+    permissions: Option<&[String]>,
+) -> Vec<FieldViolation> {
+    let mut violations = Vec::new();
+    for role in roles.unwrap_or_default() {
+        let valid = !role.is_empty()
+            && role.chars().count() <= MAX_ROLE_LEN
+            && !role
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control() || c == ',');
+        if !valid {
+            violations.push(FieldViolation::new(
+                "roles",
+                format!(
+                    "`{role}` is not a valid role; use at most {MAX_ROLE_LEN} characters without whitespace or commas"
+                ),
+            ));
+        }
+    }
+    for permission in permissions.unwrap_or_default() {
+        if let Err(e) = permission.parse::<Permission>() {
+            violations.push(FieldViolation::new("permissions", e));
+        }
+    }
+    violations
+}
+
+/// Violation for a password outside the accepted length, reported against `field`
+pub fn password_violation(field: &str, password: &str) -> Option<FieldViolation> {
+    let len = password.chars().count();
+    (!PASSWORD_LEN.contains(&len)).then(|| {
+        FieldViolation::new(
+            field,
+            format!(
+                "password must be between {} and {} characters",
+                PASSWORD_LEN.start(),
+                PASSWORD_LEN.end()
+            ),
+        )
+    })
+}
+
+fn starts_with_ignore_case(value: &str, prefix: &str) -> bool {
+    value.to_lowercase().starts_with(&prefix.to_lowercase())
+}
+
+fn contains_ignore_case(value: &str, text: &str) -> bool {
+    value.to_lowercase().contains(&text.to_lowercase())
+}
+
+fn matches(filter: &UserFilter, user: &User) -> bool {
+    (contains_ignore_case(&user.name, &filter.query)
+        || contains_ignore_case(&user.email, &filter.query))
+        && starts_with_ignore_case(&user.name, &filter.name_prefix)
+        && starts_with_ignore_case(&user.email, &filter.email_prefix)
+        && filter.min_age.is_none_or(|min| user.age >= min)
+        && filter.max_age.is_none_or(|max| user.age <= max)
+}
+
+/// Fingerprint binding a page token to its query
+fn fingerprint(filter: &UserFilter, ordering: Ordering) -> u32 {
+    crc32fast::hash(format!("{filter:?}|{ordering:?}").as_bytes())
+}
+
+/// Whether a new user record is given roles or permissions, which takes `users:admin`
+pub fn grants_access(request: &CreateUserRequest) -> bool {
+    !request.roles.is_empty() || !request.permissions.is_empty()
+}
+
+/// Fields to change on a user; `None` leaves the field untouched
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, ToSchema)]
+pub struct UserPatch {
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub age: Option<i32>,
+    #[serde(default)]
+    pub roles: Option<Vec<String>>,
+    #[serde(default)]
+    pub permissions: Option<Vec<String>>,
+}
+
+impl UserPatch {
+    /// Whether the patch changes what the user may do
+    pub fn changes_access(&self) -> bool {
+        self.roles.is_some() || self.permissions.is_some()
+    }
+
+    /// Build the patch selected by the request's `update_mask`
+    ///
+    /// Without a mask (or with an empty one) every non-default field is applied;
+    /// the `*` path replaces all fields. Unknown paths are rejected.
+    pub fn from_update_request(request: &UpdateUserRequest) -> Result<Self, AppError> {
+        let paths = request
+            .update_mask
+            .as_ref()
+            .map(|mask| mask.paths.as_slice())
+            .unwrap_or_default();
+
+        if paths.is_empty() {
+            return Ok(Self {
+                name: Some(request.name.clone()).filter(|name| !name.is_empty()),
+                email: Some(request.email.clone()).filter(|email| !email.is_empty()),
+                age: Some(request.age).filter(|age| *age != 0),
+                roles: Some(request.roles.clone()).filter(|roles| !roles.is_empty()),
+                permissions: Some(request.permissions.clone())
+                    .filter(|permissions| !permissions.is_empty()),
+            });
+        }
+
+        let mut patch = Self::default();
+        for path in paths {
+            match path.as_str() {
+                "name" => patch.name = Some(request.name.clone()),
+                "email" => patch.email = Some(request.email.clone()),
+                "age" => patch.age = Some(request.age),
+                "roles" => patch.roles = Some(request.roles.clone()),
+                "permissions" => patch.permissions = Some(request.permissions.clone()),
+                "*" => {
+                    patch.name = Some(request.name.clone());
+                    patch.email = Some(request.email.clone());
+                    patch.age = Some(request.age);
+                    patch.roles = Some(request.roles.clone());
+                    patch.permissions = Some(request.permissions.clone());
+                }
+                _ => {
+                    return Err(invalid(
+                        "update_mask",
+                        format!("unknown field path `{path}`"),
+                    ))
+                }
+            }
+        }
+        Ok(patch)
+    }
+
+    fn apply(self, user: &mut User) {
+        if let Some(name) = self.name {
+            user.name = name;
+        }
+        if let Some(email) = self.email {
+            user.email = email;
+        }
+        if let Some(age) = self.age {
+            user.age = age;
+        }
+        if let Some(roles) = self.roles {
+            user.roles = roles;
+        }
+        if let Some(permissions) = self.permissions {
+            user.permissions = permissions;
+        }
+    }
+}
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+fn user_update(user: Option<User>, update_type: &str) -> UserUpdate {
+    UserUpdate {
+        user,
+        update_type: update_type.to_string(),
+        timestamp: unix_now(),
+    }
+}
+
+/// Stored password hash of a user, in PHC string format
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PasswordCredential {
+    pub user_id: i32,
+    pub password_hash: String,
+}
+
+/// Users, their journal and change feed, shared by every user-facing API
+#[derive(Debug, Clone)]
+pub struct UserDirectory {
+    users: Arc<Store<User>>,
+    /// Owner of every registered email, keyed by `email_key`
+    emails: Arc<DashMap<String, i32>>,
+    /// Password hashes by user id
+    credentials: Arc<DashMap<i32, String>>,
+    journal: Journal,
+    events: EventBus<UserUpdate>,
+}
+
+impl UserDirectory {
+    pub fn new(users: Arc<Store<User>>, journal: Journal) -> Self {
+        let emails = DashMap::new();
+        for user in users.snapshot() {
+            emails.entry(email_key(&user.email)).or_insert(user.id);
+        }
+        Self {
+            users,
+            emails: Arc::new(emails),
+            credentials: Arc::default(),
+            journal,
+            events: EventBus::default(),
+        }
+    }
+
+    /// Follow changes to one user, or to every user when `user_id` is 0
+    ///
+    /// With a `keepalive` period, a heartbeat is emitted after each quiet period
+    /// of that length.
+    pub fn subscribe(&self, user_id: i32, keepalive: Option<Duration>) -> UserSubscription {
+        let keepalive = keepalive.filter(|period| !period.is_zero()).map(|period| {
+            let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        UserSubscription {
+            receiver: self.events.subscribe(),
+            user_id,
+            keepalive,
+        }
+    }
+
+    /// Number of open `subscribe` subscriptions
+    #[cfg(test)]
+    pub fn subscriber_count(&self) -> usize {
+        self.events.subscriber_count()
+    }
+
+    /// Load password hashes recovered from the journal
+    pub fn restore_credentials(&self, credentials: Vec<PasswordCredential>) {
+        for credential in credentials {
+            self.credentials
+                .insert(credential.user_id, credential.password_hash);
+        }
+    }
+
+    /// Copy of every password hash, for journal compaction
+    pub fn credentials_snapshot(&self) -> Vec<PasswordCredential> {
+        let mut credentials: Vec<_> = self
+            .credentials
+            .iter()
+            .map(|entry| PasswordCredential {
+                user_id: *entry.key(),
+                password_hash: entry.value().clone(),
+            })
+            .collect();
+        credentials.sort_unstable_by_key(|credential| credential.user_id);
+        credentials
+    }
+
+    pub fn get(&self, user_id: i32) -> Result<User, AppError> {
+        u64::try_from(user_id)
+            .ok()
+            .and_then(|id| self.users.get(id))
+            .ok_or(AppError::from(ErrorKind::UserNotFound))
+    }
+
+    /// Check a new user record without creating it
+    pub fn validate(&self, request: &CreateUserRequest) -> Result<(), AppError> {
+        let mut violations =
+            field_violations(Some(&request.name), Some(&request.email), Some(request.age));
+        if !request.password.is_empty() {
+            violations.extend(password_violation("password", &request.password));
+        }
+        violations.extend(access_violations(
+            Some(&request.roles),
+            Some(&request.permissions),
+        ));
+        if !violations.is_empty() {
+            return Err(ErrorKind::InvalidFields(violations).into());
+        }
+        if self.emails.contains_key(&email_key(&request.email)) {
+            return Err(email_taken());
+        }
+        Ok(())
+    }
+
+    /// `validate` for one record of a batch, also rejecting emails used by
+    /// earlier records of it, which are collected in `emails`
+    pub fn validate_batch(
+        &self,
+        request: &CreateUserRequest,
+        emails: &mut HashSet<String>,
+    ) -> Result<(), AppError> {
+        self.validate(request)?;
+        if !emails.insert(email_key(&request.email)) {
+            return Err(email_taken());
+        }
+        Ok(())
+    }
+
+    /// Look up a user by email, ignoring case
+    pub fn find_by_email(&self, email: &str) -> Option<User> {
+        let user_id = *self.emails.get(&email_key(email))?;
+        self.get(user_id).ok()
+    }
+
+    /// Password hash of the user, if one has been set
+    pub fn password_hash(&self, user_id: i32) -> Option<String> {
+        self.credentials.get(&user_id).map(|hash| hash.clone())
+    }
+
+    /// Replace the password hash of an existing user
+    pub fn set_password_hash(&self, user_id: i32, password_hash: String) -> Result<(), AppError> {
+        // Under the user's lock, so a concurrent delete cannot leave the hash behind
+        let stored = u64::try_from(user_id).ok().and_then(|id| {
+            self.users.update(id, |_| {
+                self.journal
+                    .record(Mutation::PasswordSet(PasswordCredential {
+                        user_id,
+                        password_hash: password_hash.clone(),
+                    }))?;
+                self.credentials.insert(user_id, password_hash);
+                Ok::<_, AppError>(())
+            })
+        });
+        stored
+            .ok_or(AppError::from(ErrorKind::UserNotFound))
+            .flatten()
+    }
+
+    /// Create a user, storing `password_hash` (the hash of `request.password`) with it
+    pub fn create(
+        &self,
+        request: CreateUserRequest,
+        password_hash: Option<String>,
+    ) -> Result<User, AppError> {
+        self.validate(&request)?;
+        let email = request.email.clone();
+        self.claim_email(&email, None)?;
+
+        let created = self.users.insert_with(|id| {
+            let user = User {
+                id: id as i32,
+                name: request.name,
+                email: request.email,
+                age: request.age,
+                roles: request.roles,
+                permissions: request.permissions,
+                avatar_url: String::new(),
+            };
+            // Only keep the password once the user is journaled too
+            if let Some(password_hash) = &password_hash {
+                self.journal
+                    .record(Mutation::PasswordSet(PasswordCredential {
+                        user_id: user.id,
+                        password_hash: password_hash.clone(),
+                    }))?;
+            }
+            self.journal.record(Mutation::UserPut(user.clone()))?;
+            if let Some(password_hash) = password_hash {
+                self.credentials.insert(user.id, password_hash);
+            }
+            Ok::<_, AppError>(user)
+        });
+
+        match &created {
+            Ok(user) => {
+                self.emails.insert(email_key(&email), user.id);
+                self.publish(user, USER_CREATED);
+            }
+            Err(_) => self.release_email(&email, PENDING_USER),
+        }
+        created
+    }
+
+    /// Apply `patch` to the user, returning the updated record
+    pub fn update(&self, user_id: i32, patch: UserPatch) -> Result<User, AppError> {
+        let mut violations =
+            field_violations(patch.name.as_deref(), patch.email.as_deref(), patch.age);
+        violations.extend(access_violations(
+            patch.roles.as_deref(),
+            patch.permissions.as_deref(),
+        ));
+        if !violations.is_empty() {
+            return Err(ErrorKind::InvalidFields(violations).into());
+        }
+
+        let new_email = patch.email.clone();
+        let claimed = match &new_email {
+            Some(email) => self.claim_email(email, Some(user_id))?,
+            None => false,
+        };
+
+        let updated = u64::try_from(user_id).ok().and_then(|id| {
+            self.users.update(id, |user| {
+                let previous_email = user.email.clone();
+                patch.apply(user);
+                self.journal.record(Mutation::UserPut(user.clone()))?;
+                Ok::<_, AppError>((user.clone(), previous_email))
+            })
+        });
+
+        match updated
+            .ok_or(AppError::from(ErrorKind::UserNotFound))
+            .flatten()
+        {
+            Ok((user, previous_email)) => {
+                if email_key(&previous_email) != email_key(&user.email) {
+                    self.release_email(&previous_email, user_id);
+                }
+                self.publish(&user, USER_UPDATED);
+                Ok(user)
+            }
+            Err(err) => {
+                if let (true, Some(email)) = (claimed, &new_email) {
+                    self.release_email(email, user_id);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Whether any user's `avatar_url` points at the picture hashing to `digest`
+    pub fn avatar_in_use(&self, digest: &str) -> bool {
+        self.users
+            .snapshot()
+            .iter()
+            .any(|user| avatars::digest_of(&user.avatar_url) == Some(digest))
+    }
+
+    /// Point the user at a new picture, returning the updated record
+    pub fn set_avatar_url(&self, user_id: i32, avatar_url: String) -> Result<User, AppError> {
+        let updated = u64::try_from(user_id).ok().and_then(|id| {
+            self.users.update(id, |user| {
+                user.avatar_url = avatar_url;
+                self.journal.record(Mutation::UserPut(user.clone()))?;
+                Ok::<_, AppError>(user.clone())
+            })
+        });
+        let user = updated.ok_or(ErrorKind::UserNotFound)??;
+        self.publish(&user, USER_UPDATED);
+        Ok(user)
+    }
+
+    pub fn delete(&self, user_id: i32) -> Result<User, AppError> {
+        let removed = u64::try_from(user_id).ok().and_then(|id| {
+            self.users.remove(id, |_| {
+                self.journal.record(Mutation::UserDelete(user_id))?;
+                self.credentials.remove(&user_id);
+                Ok::<_, AppError>(())
+            })
+        });
+        let user = removed.ok_or(ErrorKind::UserNotFound)??;
+        self.release_email(&user.email, user.id);
+        self.publish(&user, USER_DELETED);
+        Ok(user)
+    }
+
+    /// Return one page of the users matching the request's filter, in the requested order
+    pub fn list(&self, request: ListUsersRequest) -> Result<ListUsersResponse, AppError> {
+        list_users(&self.users, request)
+    }
+
+    /// Users matching `filter` among the next `EXPORT_PAGE_SIZE` users after id `after`
+    ///
+    /// Returns the cursor for the following page, or `None` once the store is exhausted.
+    pub fn export_page(&self, filter: &UserFilter, after: u64) -> (Vec<User>, Option<u64>) {
+        let (page, next) = self.users.page_after(after, EXPORT_PAGE_SIZE);
+        let users = page
+            .into_iter()
+            .map(|(_, user)| user)
+            .filter(|user| matches(filter, user))
+            .collect();
+        (users, next)
+    }
+
+    /// Reserve `email` for `owner` (or a user being created, for `None`)
+    ///
+    /// Returns whether the reservation is new, i.e. must be released if the
+    /// change it guards fails.
+    fn claim_email(&self, email: &str, owner: Option<i32>) -> Result<bool, AppError> {
+        match self.emails.entry(email_key(email)) {
+            Entry::Occupied(entry) if owner == Some(*entry.get()) => Ok(false),
+            Entry::Occupied(_) => Err(email_taken()),
+            Entry::Vacant(entry) => {
+                entry.insert(owner.unwrap_or(PENDING_USER));
+                Ok(true)
+            }
+        }
+    }
+
+    fn release_email(&self, email: &str, owner: i32) {
+        self.emails
+            .remove_if(&email_key(email), |_, holder| *holder == owner);
+    }
+
+    fn publish(&self, user: &User, update_type: &str) {
+        self.events
+            .publish(user_update(Some(user.clone()), update_type));
+    }
+}
+
+/// Live feed of user changes returned by `UserDirectory::subscribe`
+#[derive(Debug)]
+pub struct UserSubscription {
+    receiver: broadcast::Receiver<UserUpdate>,
+    user_id: i32,
+    keepalive: Option<Interval>,
+}
+
+impl UserSubscription {
+    /// Wait for the next change or heartbeat; `None` once the directory is gone
+    pub async fn next(&mut self) -> Option<UserUpdate> {
+        loop {
+            let keepalive = async {
+                match self.keepalive.as_mut() {
+                    Some(interval) => interval.tick().await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                received = self.receiver.recv() => match received {
+                    Ok(update) => {
+                        let user_id = update.user.as_ref().map_or(0, |user| user.id);
+                        if self.user_id == 0 || self.user_id == user_id {
+                            if let Some(interval) = self.keepalive.as_mut() {
+                                interval.reset();
+                            }
+                            return Some(update);
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("User subscriber lagged, skipped {} updates", skipped);
+                    }
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = keepalive => return Some(user_update(None, HEARTBEAT)),
+            }
+        }
+    }
+}
+
+fn list_users(
+    users: &Store<User>,
+    request: ListUsersRequest,
+) -> Result<ListUsersResponse, AppError> {
+    let page_size = match usize::try_from(request.page_size) {
+        Ok(0) => DEFAULT_PAGE_SIZE,
+        Ok(size) => size.min(MAX_PAGE_SIZE),
+        Err(_) => return Err(invalid("page_size", "page_size must not be negative")),
+    };
+    let ordering = Ordering::parse(&request.order_by)?;
+    let filter = request.filter.unwrap_or_default();
+    if let (Some(min), Some(max)) = (filter.min_age, filter.max_age) {
+        if min > max {
+            return Err(invalid(
+                "filter.min_age",
+                "min_age must not be greater than max_age",
+            ));
+        }
+    }
+
+    let query = fingerprint(&filter, ordering);
+    let cursor = if request.page_token.is_empty() {
+        None
+    } else {
+        let token = PageToken::decode(&request.page_token)?;
+        if token.query != query {
+            return Err(invalid(
+                "page_token",
+                "page token was issued for a different filter or order_by",
+            ));
+        }
+        Some(token.cursor(ordering))
+    };
+
+    let mut page: Vec<User> = users
+        .snapshot()
+        .into_iter()
+        .filter(|user| matches(&filter, user))
+        .filter(|user| {
+            cursor
+                .as_ref()
+                .is_none_or(|cursor| ordering.compare(user, cursor) == cmp::Ordering::Greater)
+        })
+        .collect();
+    page.sort_by(|a, b| ordering.compare(a, b));
+
+    let next_page_token = if page.len() > page_size {
+        page.truncate(page_size);
+        page.last()
+            .map(|last| PageToken::after(last, ordering, query).encode())
+            .unwrap_or_default()
+    } else {
+        String::new()
+    };
+
+    Ok(ListUsersResponse {
+        users: page,
+        next_page_token,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> Store<User> {
+        let users = Store::new();
+        let people = [
+            ("Alice", "alice@example.com", 31),
+            ("bob", "bob@example.com", 25),
+            ("Carol", "carol@corp.example", 42),
+            ("alan", "alan@corp.example", 19),
+            ("Dave", "dave@example.com", 25),
+        ];
+        for (i, (name, email, age)) in people.into_iter().enumerate() {
+            let id = i as i32 + 1;
+            users.insert(
+                id as u64,
+                User {
+                    id,
+                    name: name.to_string(),
+                    email: email.to_string(),
+                    age,
+                    ..Default::default()
+                },
+            );
+        }
+        users
+    }
+
+    fn ids(response: &ListUsersResponse) -> Vec<i32> {
+        response.users.iter().map(|user| user.id).collect()
+    }
+
+    #[test]
+    fn test_list_users_pages_through_results() {
+        let users = store();
+        let mut request = ListUsersRequest {
+            page_size: 2,
+            order_by: "age desc".to_string(),
+            ..Default::default()
+        };
+
+        let mut seen = Vec::new();
+        loop {
+            let response = list_users(&users, request.clone()).unwrap();
+            seen.extend(ids(&response));
+            if response.next_page_token.is_empty() {
+                break;
+            }
+            request.page_token = response.next_page_token;
+        }
+        assert_eq!(seen, vec![3, 1, 5, 2, 4]);
+    }
+
+    #[test]
+    fn test_list_users_filters() {
+        let users = store();
+        let request = ListUsersRequest {
+            filter: Some(UserFilter {
+                name_prefix: "a".to_string(),
+                min_age: Some(20),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(ids(&list_users(&users, request).unwrap()), vec![1]);
+
+        let request = ListUsersRequest {
+            filter: Some(UserFilter {
+                email_prefix: "CAROL@".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(ids(&list_users(&users, request).unwrap()), vec![3]);
+
+        let request = ListUsersRequest {
+            filter: Some(UserFilter {
+                query: "CORP".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(ids(&list_users(&users, request).unwrap()), vec![3, 4]);
+    }
+
+    #[test]
+    fn test_list_users_rejects_invalid_requests() {
+        let users = store();
+        let first_page = list_users(
+            &users,
+            ListUsersRequest {
+                page_size: 1,
+                order_by: "name".to_string(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let requests = [
+            (
+                ListUsersRequest {
+                    page_size: -1,
+                    ..Default::default()
+                },
+                "page_size",
+            ),
+            (
+                ListUsersRequest {
+                    order_by: "password".to_string(),
+                    ..Default::default()
+                },
+                "order_by",
+            ),
+            (
+                ListUsersRequest {
+                    page_token: "not a token".to_string(),
+                    ..Default::default()
+                },
+                "page_token",
+            ),
+            // Token issued for a different ordering
+            (
+                ListUsersRequest {
+                    page_token: first_page.next_page_token,
+                    order_by: "email".to_string(),
+                    ..Default::default()
+                },
+                "page_token",
+            ),
+        ];
+        for (request, field) in requests {
+            let err = list_users(&users, request).unwrap_err();
+            assert!(matches!(err.kind(), ErrorKind::InvalidFields(_)));
+            assert_eq!(err.field_violations()[0].field, field);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mutations_publish_updates() {
+        let directory = UserDirectory::new(Arc::new(store()), Journal::default());
+        let mut all = directory.subscribe(0, None);
+        let mut only_second = directory.subscribe(2, None);
+
+        directory
+            .update(
+                1,
+                UserPatch {
+                    age: Some(40),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        directory.delete(2).unwrap();
+
+        let update = all.next().await.unwrap();
+        assert_eq!(update.update_type, USER_UPDATED);
+        assert_eq!(update.user.unwrap().age, 40);
+        assert_eq!(all.next().await.unwrap().update_type, USER_DELETED);
+
+        let update = only_second.next().await.unwrap();
+        assert_eq!(update.update_type, USER_DELETED);
+        assert_eq!(update.user.unwrap().id, 2);
+    }
+
+    #[test]
+    fn test_export_walks_every_page() {
+        let users = Store::new();
+        for id in 1..=(EXPORT_PAGE_SIZE as i32 * 2 + 3) {
+            users.insert(
+                id as u64,
+                User {
+                    id,
+                    name: format!("user {id}"),
+                    age: id % 50,
+                    ..Default::default()
+                },
+            );
+        }
+        let directory = UserDirectory::new(Arc::new(users), Journal::default());
+        let filter = UserFilter {
+            min_age: Some(40),
+            ..Default::default()
+        };
+
+        let mut exported = Vec::new();
+        let mut cursor = Some(0);
+        let mut pages = 0;
+        while let Some(after) = cursor {
+            let (page, next) = directory.export_page(&filter, after);
+            exported.extend(page.into_iter().map(|user| user.id));
+            cursor = next;
+            pages += 1;
+        }
+
+        assert_eq!(pages, 3);
+        assert!(exported.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(exported.len(), 100);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_subscription_heartbeat() {
+        let directory = UserDirectory::new(Arc::new(store()), Journal::default());
+        let mut subscription = directory.subscribe(1, Some(Duration::from_secs(5)));
+
+        let update = subscription.next().await.unwrap();
+        assert_eq!(update.update_type, HEARTBEAT);
+        assert!(update.user.is_none());
+    }
+
+    fn update_request(paths: &[&str]) -> UpdateUserRequest {
+        UpdateUserRequest {
+            user_id: 1,
+            name: String::new(),
+            email: "new@example.com".to_string(),
+            age: 0,
+            update_mask: Some(prost_types::FieldMask {
+                paths: paths.iter().map(|path| path.to_string()).collect(),
+            }),
+            roles: vec!["ops".to_string()],
+            permissions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_patch_honors_update_mask() {
+        let patch = UserPatch::from_update_request(&update_request(&["name", "age"])).unwrap();
+        assert_eq!(
+            patch,
+            UserPatch {
+                name: Some(String::new()),
+                email: None,
+                age: Some(0),
+                ..Default::default()
+            }
+        );
+        assert!(!patch.changes_access());
+
+        let patch = UserPatch::from_update_request(&update_request(&["permissions"])).unwrap();
+        assert_eq!(patch.permissions, Some(Vec::new()));
+        assert!(patch.changes_access());
+
+        let patch = UserPatch::from_update_request(&update_request(&[])).unwrap();
+        assert_eq!(
+            patch,
+            UserPatch {
+                email: Some("new@example.com".to_string()),
+                roles: Some(vec!["ops".to_string()]),
+                ..Default::default()
+            }
+        );
+
+        let err = UserPatch::from_update_request(&update_request(&["id"])).unwrap_err();
+        assert_eq!(err.field_violations()[0].field, "update_mask");
+    }
+
+    fn new_user(name: &str, email: &str, age: i32) -> CreateUserRequest {
+        CreateUserRequest {
+            name: name.to_string(),
+            email: email.to_string(),
+            age,
+            ..Default::default()
+        }
+    }
+
+    fn violated_fields(err: &AppError) -> Vec<&str> {
+        err.field_violations()
+            .iter()
+            .map(|violation| violation.field.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_create_validates_fields() {
+        let directory = UserDirectory::new(Arc::new(Store::new()), Journal::default());
+
+        let err = directory
+            .create(new_user(" ", "not-an-email", -3), None)
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::InvalidFields(_)));
+        assert_eq!(violated_fields(&err), vec!["name", "email", "age"]);
+
+        for email in [
+            "a@b",
+            "@example.com",
+            "a b@example.com",
+            "a@-x.com",
+            "a@x..com",
+        ] {
+            let err = directory
+                .create(new_user("Eve", email, 30), None)
+                .unwrap_err();
+            assert_eq!(violated_fields(&err), vec!["email"], "{email}");
+        }
+
+        let err = directory
+            .create(
+                CreateUserRequest {
+                    roles: vec!["ops".to_string(), "on call".to_string()],
+                    permissions: vec!["users:read".to_string(), "users:root".to_string()],
+                    ..new_user("Eve", "eve@example.com", 30)
+                },
+                None,
+            )
+            .unwrap_err();
+        assert_eq!(violated_fields(&err), vec!["roles", "permissions"]);
+
+        assert!(directory
+            .create(new_user("Eve", "eve@example.com", 30), None)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_batch_validation_catches_emails_repeated_within_it() {
+        let directory = UserDirectory::new(Arc::new(store()), Journal::default());
+        let mut emails = HashSet::new();
+
+        let mut validate =
+            |email: &str| directory.validate_batch(&new_user("Eve", email, 30), &mut emails);
+        assert!(validate("eve@example.com").is_ok());
+        assert!(validate("EVE@example.com").is_err());
+        assert!(validate("alice@example.com").is_err());
+        assert!(validate("frank@example.com").is_ok());
+    }
+
+    #[test]
+    fn test_emails_are_unique_ignoring_case() {
+        let directory = UserDirectory::new(Arc::new(store()), Journal::default());
+
+        let err = directory
+            .create(new_user("Alice 2", "ALICE@example.com", 30), None)
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::AlreadyExists(_)));
+        assert_eq!(violated_fields(&err), vec!["email"]);
+
+        // Bob cannot take Alice's address, but Alice can change the case of her own
+        let take = |user_id, email: &str| {
+            directory.update(
+                user_id,
+                UserPatch {
+                    email: Some(email.to_string()),
+                    ..Default::default()
+                },
+            )
+        };
+        assert!(matches!(
+            take(2, "alice@example.com").map_err(AppError::into_kind),
+            Err(ErrorKind::AlreadyExists(_))
+        ));
+        assert!(take(1, "Alice@Example.com").is_ok());
+
+        // Alice's old address is freed once she moves and after Bob is deleted
+        assert!(take(1, "alice@new.example").is_ok());
+        assert!(take(2, "alice@example.com").is_ok());
+        directory.delete(2).unwrap();
+        assert!(directory
+            .create(new_user("Bobby", "ALICE@example.com", 20), None)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_update_changes_only_patched_fields() {
+        let directory = UserDirectory::new(Arc::new(store()), Journal::default());
+        let patch = UserPatch {
+            age: Some(32),
+            ..Default::default()
+        };
+
+        let user = directory.update(1, patch).unwrap();
+        assert_eq!(
+            (user.name.as_str(), user.email.as_str(), user.age),
+            ("Alice", "alice@example.com", 32)
+        );
+        assert!(matches!(
+            directory
+                .update(99, UserPatch::default())
+                .map_err(AppError::into_kind),
+            Err(ErrorKind::UserNotFound)
+        ));
+    }
+}
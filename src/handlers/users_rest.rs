@@ -0,0 +1,608 @@
+//! REST user resource handler
+//!
+//! Serves `/users` over the same `UserDirectory` and `Credentials` as the gRPC
+//! `UserService`, so validation, unique emails, pagination and change events
+//! behave identically. Bodies are the prost messages themselves, serialized as
+//! JSON and documented through their `ToSchema` derives.
+//!
+//! Avatars are uploaded as `multipart/form-data` with a single `avatar` part.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use crate::auth::Principal;
+use crate::avatars::{self, ImageFormat};
+use crate::errors::{AppError, ErrorKind, FieldViolation};
+use crate::handlers::grid::AppState;
+use crate::handlers::users::{grants_access, UserPatch};
+use crate::protos::user::{
+    CreateUserRequest, ListUsersRequest, ListUsersResponse, User, UserFilter,
+};
+use crate::rbac::Permission;
+use axum::{
+    extract::{multipart::MultipartError, Multipart, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+/// Query of `GET /users` and `GET /users/search`
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UserQuery {
+    /// Maximum number of users to return; 0 selects the server default
+    #[serde(default)]
+    pub page_size: i32,
+    /// `next_page_token` from the previous page
+    #[serde(default)]
+    pub page_token: String,
+    /// Sort field (`id`, `name`, `email` or `age`), optionally followed by `desc`
+    #[serde(default)]
+    pub order_by: String,
+    #[serde(default)]
+    pub name_prefix: String,
+    #[serde(default)]
+    pub email_prefix: String,
+    pub min_age: Option<i32>,
+    pub max_age: Option<i32>,
+    /// Text found in the name or email, ignoring case
+    #[serde(default)]
+    pub q: String,
+}
+
+/// Query of `GET /users/{id}/avatar`
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AvatarQuery {
+    /// Picture hash from `avatar_url`; makes the response cacheable for good
+    pub v: Option<String>,
+}
+
+impl From<UserQuery> for ListUsersRequest {
+    fn from(query: UserQuery) -> Self {
+        Self {
+            page_size: query.page_size,
+            page_token: query.page_token,
+            order_by: query.order_by,
+            filter: Some(UserFilter {
+                name_prefix: query.name_prefix,
+                email_prefix: query.email_prefix,
+                min_age: query.min_age,
+                max_age: query.max_age,
+                query: query.q,
+            }),
+        }
+    }
+}
+
+/// List users page by page
+#[utoipa::path(
+    get,
+    path = "/users",
+    params(UserQuery),
+    responses(
+        (status = 200, description = "One page of users", body = ListUsersResponse),
+        (status = 400, description = "Invalid filter, ordering or page token"),
+    )
+)]
+pub async fn list_users(
+    State(state): State<AppState>,
+    Query(query): Query<UserQuery>,
+) -> Result<Json<ListUsersResponse>, AppError> {
+    Ok(Json(state.directory.list(query.into())?))
+}
+
+/// Find users whose name or email contains `q`
+#[utoipa::path(
+    get,
+    path = "/users/search",
+    params(UserQuery),
+    responses(
+        (status = 200, description = "One page of matching users", body = ListUsersResponse),
+        (status = 400, description = "Missing `q`, or an invalid filter"),
+    )
+)]
+pub async fn search_users(
+    State(state): State<AppState>,
+    Query(query): Query<UserQuery>,
+) -> Result<Json<ListUsersResponse>, AppError> {
+    if query.q.trim().is_empty() {
+        return Err(
+            ErrorKind::InvalidFields(vec![FieldViolation::new("q", "must not be empty")]).into(),
+        );
+    }
+    Ok(Json(state.directory.list(query.into())?))
+}
+
+/// Fetch one user
+#[utoipa::path(
+    get,
+    path = "/users/{id}",
+    params(("id" = i32, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The user", body = User),
+        (status = 404, description = "No such user"),
+    )
+)]
+pub async fn get_user(
+    State(state): State<AppState>,
+    Path(user_id): Path<i32>,
+) -> Result<Json<User>, AppError> {
+    Ok(Json(state.directory.get(user_id)?))
+}
+
+/// Create a user; open to anonymous callers like the gRPC `CreateUser`
+///
+/// Giving the user roles or permissions takes `users:admin`.
+#[utoipa::path(
+    post,
+    path = "/users",
+    request_body = CreateUserRequest,
+    security((), ("bearer_auth" = []), ("api_key" = [])),
+    responses(
+        (status = 201, description = "The new user", body = User),
+        (status = 400, description = "Invalid fields"),
+        (status = 409, description = "Email already in use"),
+    )
+)]
+pub async fn create_user(
+    principal: Option<Principal>,
+    State(state): State<AppState>,
+    Json(request): Json<CreateUserRequest>,
+) -> Result<(StatusCode, Json<User>), AppError> {
+    if grants_access(&request) {
+        Principal::require(principal.as_ref(), Permission::UsersAdmin)?;
+    }
+    let user = state.credentials.create_user(request).await?;
+    Ok((StatusCode::CREATED, Json(user)))
+}
+
+/// Change the given fields of a user
+///
+/// Changing roles or permissions takes `users:admin`.
+#[utoipa::path(
+    patch,
+    path = "/users/{id}",
+    params(("id" = i32, Path, description = "User id")),
+    request_body = UserPatch,
+    responses(
+        (status = 200, description = "The updated user", body = User),
+        (status = 400, description = "Invalid fields"),
+        (status = 404, description = "No such user"),
+        (status = 409, description = "Email already in use"),
+    )
+)]
+pub async fn update_user(
+    principal: Principal,
+    State(state): State<AppState>,
+    Path(user_id): Path<i32>,
+    Json(patch): Json<UserPatch>,
+) -> Result<Json<User>, AppError> {
+    if patch.changes_access() {
+        Principal::require(Some(&principal), Permission::UsersAdmin)?;
+    }
+    Ok(Json(state.directory.update(user_id, patch)?))
+}
+
+/// Delete a user
+#[utoipa::path(
+    delete,
+    path = "/users/{id}",
+    params(("id" = i32, Path, description = "User id")),
+    responses(
+        (status = 204, description = "User deleted"),
+        (status = 404, description = "No such user"),
+    )
+)]
+pub async fn delete_user(
+    State(state): State<AppState>,
+    Path(user_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    state.credentials.delete_user(user_id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn avatar_part_error(err: MultipartError) -> AppError {
+    if err.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        return ErrorKind::PayloadTooLarge.into();
+    }
+    ErrorKind::InvalidFields(vec![FieldViolation::new("avatar", err.body_text())]).into()
+}
+
+/// Read the `avatar` part of an upload, refusing it once it outgrows `max_bytes`
+async fn read_avatar(mut multipart: Multipart, max_bytes: usize) -> Result<Vec<u8>, AppError> {
+    let missing = || {
+        AppError::from(ErrorKind::InvalidFields(vec![FieldViolation::new(
+            "avatar", "missing",
+        )]))
+    };
+    let mut field = multipart
+        .next_field()
+        .await
+        .map_err(avatar_part_error)?
+        .ok_or_else(missing)?;
+    if field.name() != Some("avatar") {
+        return Err(missing());
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = field.chunk().await.map_err(avatar_part_error)? {
+        if bytes.len() + chunk.len() > max_bytes {
+            return Err(ErrorKind::PayloadTooLarge.into());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// Upload a new picture for a user
+///
+/// Users change their own picture; changing another user's takes `users:admin`.
+#[utoipa::path(
+    put,
+    path = "/users/{id}/avatar",
+    params(("id" = i32, Path, description = "User id")),
+    request_body(content_type = "multipart/form-data", description = "PNG, JPEG or WebP picture in an `avatar` part"),
+    responses(
+        (status = 200, description = "The user, with its new `avatar_url`", body = User),
+        (status = 400, description = "No `avatar` part"),
+        (status = 404, description = "No such user"),
+        (status = 413, description = "Picture exceeds `avatars.max_bytes`"),
+        (status = 415, description = "Not a PNG, JPEG or WebP picture"),
+    )
+)]
+pub async fn put_avatar(
+    principal: Principal,
+    State(state): State<AppState>,
+    Path(user_id): Path<i32>,
+    multipart: Multipart,
+) -> Result<Json<User>, AppError> {
+    let user_id = principal.target_user(Some(user_id))?;
+    state.directory.get(user_id)?;
+    let bytes = read_avatar(multipart, state.avatars.max_bytes()).await?;
+
+    let _replacing = state.avatars.replacing().await;
+    let digest = state.avatars.save(&bytes).await?;
+    let previous = state.directory.get(user_id)?.avatar_url;
+    let user = state
+        .directory
+        .set_avatar_url(user_id, avatars::avatar_url(user_id, &digest))?;
+    tracing::info!(
+        "Avatar of user {} set to {} by {}",
+        user_id,
+        digest,
+        principal.subject
+    );
+    // The old picture goes unless it was re-uploaded or another user has it
+    if let Some(replaced) = avatars::digest_of(&previous)
+        .filter(|replaced| *replaced != digest && !state.directory.avatar_in_use(replaced))
+    {
+        if let Err(err) = state.avatars.remove(replaced).await {
+            tracing::warn!("Failed to delete replaced avatar {}: {}", replaced, err);
+        }
+    }
+    Ok(Json(user))
+}
+
+/// Fetch a user's picture
+///
+/// The picture's hash is its `ETag`. Requested through `avatar_url`, whose `v`
+/// names the current picture, it may be cached for good; otherwise for
+/// `avatars.cache_max_age_secs`.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/avatar",
+    params(("id" = i32, Path, description = "User id"), AvatarQuery),
+    responses(
+        (status = 200, description = "The PNG, JPEG or WebP picture", content_type = "image/*"),
+        (status = 304, description = "The cached picture is current"),
+        (status = 404, description = "No such user, or the user has no picture"),
+    )
+)]
+pub async fn get_avatar(
+    State(state): State<AppState>,
+    Path(user_id): Path<i32>,
+    Query(query): Query<AvatarQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let user = state.directory.get(user_id)?;
+    let digest = avatars::digest_of(&user.avatar_url).ok_or(ErrorKind::NotFound)?;
+
+    let etag = format!("\"{digest}\"");
+    let cache_control = if query.v.as_deref() == Some(digest) {
+        "private, max-age=31536000, immutable".to_string()
+    } else {
+        format!("private, max-age={}", state.avatars.cache_max_age_secs())
+    };
+    let cache_headers = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, cache_control),
+    ];
+
+    let revalidated = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|tags| {
+            tags.split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        });
+    if revalidated {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
+
+    let bytes = state
+        .avatars
+        .load(digest)
+        .await?
+        .ok_or(ErrorKind::NotFound)?;
+    let format = ImageFormat::sniff(&bytes).ok_or(ErrorKind::InternalError)?;
+    Ok((
+        cache_headers,
+        [
+            (header::CONTENT_TYPE, format.content_type()),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        ],
+        bytes,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avatars::AvatarStore;
+    use crate::config::AvatarConfig;
+    use crate::handlers::grid::tests::state_with;
+    use axum::{body::Body, extract::FromRequest, http::Request};
+    use std::collections::BTreeSet;
+
+    fn new_user(name: &str, email: &str) -> CreateUserRequest {
+        CreateUserRequest {
+            name: name.to_string(),
+            email: email.to_string(),
+            age: 30,
+            ..Default::default()
+        }
+    }
+
+    fn principal(permissions: &[Permission]) -> Principal {
+        Principal {
+            subject: "7".to_string(),
+            roles: Vec::new(),
+            permissions: permissions.iter().copied().collect::<BTreeSet<_>>(),
+            session_id: None,
+            api_key_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_users_round_trip_through_rest() {
+        let state = state_with(Vec::new());
+        let (status, Json(alice)) = create_user(
+            None,
+            State(state.clone()),
+            Json(new_user("Alice", "alice@example.com")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        let _ = create_user(
+            None,
+            State(state.clone()),
+            Json(new_user("Bob", "bob@corp.example")),
+        )
+        .await
+        .unwrap();
+
+        let patch = UserPatch {
+            age: Some(31),
+            ..Default::default()
+        };
+        let Json(updated) = update_user(
+            principal(&[]),
+            State(state.clone()),
+            Path(alice.id),
+            Json(patch),
+        )
+        .await
+        .unwrap();
+        assert_eq!((updated.age, updated.name.as_str()), (31, "Alice"));
+        assert_eq!(
+            get_user(State(state.clone()), Path(alice.id))
+                .await
+                .unwrap()
+                .0,
+            updated
+        );
+
+        let query = UserQuery {
+            q: "CORP".to_string(),
+            ..Default::default()
+        };
+        let Json(found) = search_users(State(state.clone()), Query(query))
+            .await
+            .unwrap();
+        assert_eq!(found.users.len(), 1);
+        assert_eq!(found.users[0].name, "Bob");
+        let Json(all) = list_users(State(state.clone()), Query(UserQuery::default()))
+            .await
+            .unwrap();
+        assert_eq!(all.users.len(), 2);
+        assert!(
+            search_users(State(state.clone()), Query(UserQuery::default()))
+                .await
+                .is_err()
+        );
+
+        let status = delete_user(State(state.clone()), Path(alice.id))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(matches!(
+            get_user(State(state), Path(alice.id))
+                .await
+                .map_err(AppError::into_kind),
+            Err(ErrorKind::UserNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_granting_access_takes_users_admin() {
+        let state = state_with(Vec::new());
+        let mut request = new_user("Alice", "alice@example.com");
+        request.roles = vec!["admin".to_string()];
+
+        for caller in [None, Some(principal(&[Permission::UsersWrite]))] {
+            assert!(
+                create_user(caller, State(state.clone()), Json(request.clone()))
+                    .await
+                    .is_err()
+            );
+        }
+        let admin = principal(&[Permission::UsersAdmin]);
+        let (_, Json(user)) = create_user(Some(admin), State(state.clone()), Json(request))
+            .await
+            .unwrap();
+        assert_eq!(user.roles, ["admin"]);
+
+        let patch = UserPatch {
+            permissions: Some(vec!["users:admin".to_string()]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            update_user(
+                principal(&[Permission::UsersWrite]),
+                State(state),
+                Path(user.id),
+                Json(patch),
+            )
+            .await
+            .map_err(AppError::into_kind),
+            Err(ErrorKind::Forbidden)
+        ));
+    }
+
+    fn upload(part: &str, bytes: &[u8]) -> Request<Body> {
+        let mut body = format!(
+            "--XX\r\nContent-Disposition: form-data; name=\"{part}\"; filename=\"a\"\r\n\r\n"
+        )
+        .into_bytes();
+        body.extend_from_slice(bytes);
+        body.extend_from_slice(b"\r\n--XX--\r\n");
+        Request::builder()
+            .method("PUT")
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=XX")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn put(
+        state: &AppState,
+        caller: Principal,
+        user_id: i32,
+        request: Request<Body>,
+    ) -> Result<Json<User>, AppError> {
+        let multipart = Multipart::from_request(request, &()).await.unwrap();
+        put_avatar(caller, State(state.clone()), Path(user_id), multipart).await
+    }
+
+    #[tokio::test]
+    async fn test_avatars_upload_and_serve_with_cache_headers() {
+        let dir =
+            std::env::temp_dir().join(format!("omni-gate-rest-avatars-{}", std::process::id()));
+        let mut state = state_with(Vec::new());
+        state.avatars = AvatarStore::new(&AvatarConfig {
+            dir: dir.to_string_lossy().into_owned(),
+            max_bytes: 64,
+            ..AvatarConfig::default()
+        });
+        let (_, Json(alice)) = create_user(
+            None,
+            State(state.clone()),
+            Json(new_user("Alice", "alice@example.com")),
+        )
+        .await
+        .unwrap();
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let admin = principal(&[Permission::UsersAdmin]);
+
+        for (request, expected) in [
+            (
+                upload("avatar", b"GIF89a"),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ),
+            (upload("avatar", &[0xff; 65]), StatusCode::PAYLOAD_TOO_LARGE),
+            (upload("picture", png), StatusCode::BAD_REQUEST),
+        ] {
+            let err = put(&state, admin.clone(), alice.id, request)
+                .await
+                .unwrap_err();
+            assert_eq!(err.into_response().status(), expected);
+        }
+        assert!(matches!(
+            put(&state, principal(&[]), alice.id, upload("avatar", png))
+                .await
+                .map_err(AppError::into_kind),
+            Err(ErrorKind::Forbidden)
+        ));
+
+        let Json(user) = put(&state, admin, alice.id, upload("avatar", png))
+            .await
+            .unwrap();
+        let digest = avatars::digest_of(&user.avatar_url).unwrap().to_string();
+        let fetch = |v: Option<&str>, etag: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(etag) = etag {
+                headers.insert(header::IF_NONE_MATCH, etag.parse().unwrap());
+            }
+            let query = AvatarQuery {
+                v: v.map(str::to_string),
+            };
+            get_avatar(State(state.clone()), Path(alice.id), Query(query), headers)
+        };
+
+        let response = fetch(Some(&digest), None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        assert!(response.headers()[header::CACHE_CONTROL]
+            .to_str()
+            .unwrap()
+            .contains("immutable"));
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let response = fetch(None, None).await.unwrap();
+        assert_eq!(
+            response.headers()[header::CACHE_CONTROL],
+            "private, max-age=300"
+        );
+        let response = fetch(None, Some(&etag)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // A replaced picture is deleted once nobody else points at it
+        let (_, Json(bob)) = create_user(
+            None,
+            State(state.clone()),
+            Json(new_user("Bob", "bob@example.com")),
+        )
+        .await
+        .unwrap();
+        let admin = principal(&[Permission::UsersAdmin]);
+        let other_png = b"\x89PNG\r\n\x1a\n\0\0\0\rIEND";
+        for (user_id, picture, kept) in [
+            (bob.id, png, true),
+            (alice.id, other_png, true),
+            (bob.id, other_png, false),
+        ] {
+            let Json(user) = put(&state, admin.clone(), user_id, upload("avatar", picture))
+                .await
+                .unwrap();
+            assert_ne!(avatars::digest_of(&user.avatar_url), None);
+            assert_eq!(state.avatars.load(&digest).await.unwrap().is_some(), kept);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
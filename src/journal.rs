@@ -0,0 +1,673 @@
+//! Durability module
+//!
+//! Persists grid and user mutations to a write-ahead log in the configured data
+//! directory and periodically compacts the log into a snapshot. On startup the
+//! snapshot is loaded and the log replayed on top of it.
+//!
+//! Compaction renames the live log aside, captures the stores, writes the
+//! snapshot atomically and only then deletes the renamed log. Mutations are
+//! journaled while their record's shard is write-locked, so a capture taken after
+//! the rename always contains everything in the renamed log.
+//!
+//! With `sync_writes`, records are not synced there, under the shard lock, but
+//! by `flush`, which the protocol layers below await before answering a request.
+//! Concurrent requests share one `fsync`, run on the blocking pool. Records
+//! made outside a request, such as by the grid reaper, reach the disk with the
+//! next flush or compaction.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use crate::api_keys::StoredApiKey;
+use crate::config::StorageConfig;
+use crate::errors::AppError;
+use crate::handlers::grid::GridItem;
+use crate::handlers::users::PasswordCredential;
+use crate::protos::user::User;
+use crate::wal::Wal;
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures_util::future::BoxFuture;
+use jsonrpsee::server::middleware::rpc::RpcServiceT;
+use jsonrpsee::types::ErrorObjectOwned;
+use jsonrpsee::MethodResponse;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+const WAL_FILE: &str = "wal.log";
+const COMPACTING_FILE: &str = "wal.compacting.log";
+const SNAPSHOT_FILE: &str = "snapshot.json";
+
+/// A single state change recorded in the log
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Mutation {
+    GridItemPut(GridItem),
+    GridItemDelete(u64),
+    UserPut(User),
+    /// Deleting a user also drops its password
+    UserDelete(i32),
+    PasswordSet(PasswordCredential),
+    ApiKeyPut(StoredApiKey),
+    ApiKeyDelete(String),
+}
+
+/// Full state as written by compaction and returned by recovery
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub grid_items: Vec<GridItem>,
+    pub users: Vec<User>,
+    #[serde(default)]
+    pub credentials: Vec<PasswordCredential>,
+    #[serde(default)]
+    pub api_keys: Vec<StoredApiKey>,
+}
+
+/// Handle to the write-ahead log, a no-op when persistence is disabled
+#[derive(Debug, Clone, Default)]
+pub struct Journal {
+    inner: Option<Arc<JournalFiles>>,
+}
+
+#[derive(Debug)]
+struct JournalFiles {
+    dir: PathBuf,
+    sync: bool,
+    wal: Mutex<Wal>,
+    /// Records appended so far, counted under the `wal` lock
+    appended: AtomicU64,
+    /// How many of the appended records are known to be on disk
+    synced: AtomicU64,
+    /// Held while syncing, so waiting flushes share the next sync
+    syncing: tokio::sync::Mutex<()>,
+}
+
+impl JournalFiles {
+    /// Sync the live log, covering every record appended before it started
+    fn sync(&self) -> io::Result<()> {
+        let (file, appended) = {
+            let wal = self.wal.lock().unwrap_or_else(|e| e.into_inner());
+            (wal.handle()?, self.appended.load(Ordering::Acquire))
+        };
+        file.sync_data().inspect_err(|e| {
+            tracing::error!(
+                "Failed to sync {}: {}",
+                self.dir.join(WAL_FILE).display(),
+                e
+            );
+        })?;
+        self.synced.fetch_max(appended, Ordering::AcqRel);
+        Ok(())
+    }
+}
+
+impl Journal {
+    /// Open the journal and recover the state persisted in the data directory
+    pub fn open(config: &StorageConfig) -> io::Result<(Self, Snapshot)> {
+        if !config.enabled {
+            return Ok((Self::default(), Snapshot::default()));
+        }
+
+        let dir = PathBuf::from(&config.data_dir);
+        std::fs::create_dir_all(&dir)?;
+
+        let mut state = RecoveredState::from(read_snapshot(&dir.join(SNAPSHOT_FILE))?);
+        let mut replayed = 0;
+
+        // A compaction interrupted before its snapshot landed leaves the renamed log behind
+        let compacting = dir.join(COMPACTING_FILE);
+        if compacting.exists() {
+            for record in Wal::read(&compacting)? {
+                state.apply(decode(&record)?);
+                replayed += 1;
+            }
+        }
+
+        let (wal, records) = Wal::open(dir.join(WAL_FILE))?;
+        for record in records {
+            state.apply(decode(&record)?);
+            replayed += 1;
+        }
+
+        tracing::info!(
+            "Recovered state from {}: replayed {} log records",
+            dir.display(),
+            replayed
+        );
+
+        let journal = Self {
+            inner: Some(Arc::new(JournalFiles {
+                dir,
+                sync: config.sync_writes,
+                wal: Mutex::new(wal),
+                appended: AtomicU64::new(0),
+                synced: AtomicU64::new(0),
+                syncing: tokio::sync::Mutex::new(()),
+            })),
+        };
+        Ok((journal, state.into()))
+    }
+
+    /// Append a mutation to the log
+    ///
+    /// Call this from inside the store closure that applies the mutation. The
+    /// record is durable once a later `flush` returns.
+    pub fn record(&self, mutation: Mutation) -> io::Result<()> {
+        let Some(files) = &self.inner else {
+            return Ok(());
+        };
+
+        let payload = serde_json::to_vec(&mutation)?;
+        let mut wal = files.wal.lock().unwrap_or_else(|e| e.into_inner());
+        wal.append(&payload).inspect_err(|e| {
+            tracing::error!("Failed to append to {}: {}", wal.path().display(), e);
+        })?;
+        files.appended.fetch_add(1, Ordering::AcqRel);
+        Ok(())
+    }
+
+    /// Wait until every record appended so far is on disk, if writes are synced
+    pub async fn flush(&self) -> io::Result<()> {
+        let Some(files) = self.inner.as_ref().filter(|files| files.sync) else {
+            return Ok(());
+        };
+        let appended = files.appended.load(Ordering::Acquire);
+        if files.synced.load(Ordering::Acquire) >= appended {
+            return Ok(());
+        }
+
+        let _syncing = files.syncing.lock().await;
+        // The sync we waited for may have covered our records too
+        if files.synced.load(Ordering::Acquire) >= appended {
+            return Ok(());
+        }
+        let files = Arc::clone(files);
+        tokio::task::spawn_blocking(move || files.sync())
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Fold the log into a fresh snapshot produced by `capture`
+    ///
+    /// Must not run concurrently with itself; the server drives it from a single task.
+    pub fn compact(&self, capture: impl FnOnce() -> Snapshot) -> io::Result<()> {
+        let Some(files) = &self.inner else {
+            return Ok(());
+        };
+
+        let compacting = files.dir.join(COMPACTING_FILE);
+        // If a previous attempt failed after renaming, its log is still covered by
+        // the capture below, so the live log can keep growing until the next run.
+        if !compacting.exists() {
+            let mut wal = files.wal.lock().unwrap_or_else(|e| e.into_inner());
+            // Flushes only sync the live log, so sync what leaves it now
+            if files.sync {
+                wal.sync()?;
+                let appended = files.appended.load(Ordering::Acquire);
+                files.synced.fetch_max(appended, Ordering::AcqRel);
+            }
+            std::fs::rename(wal.path(), &compacting)?;
+            let (fresh, _) = Wal::open(files.dir.join(WAL_FILE))?;
+            *wal = fresh;
+        }
+
+        let snapshot = capture();
+        write_snapshot(&files.dir, &snapshot)?;
+        std::fs::remove_file(&compacting)?;
+
+        tracing::debug!(
+            "Compacted journal into snapshot with {} grid items and {} users",
+            snapshot.grid_items.len(),
+            snapshot.users.len()
+        );
+        Ok(())
+    }
+}
+
+/// Axum middleware holding each REST response back until the journal is flushed
+pub async fn durable(State(journal): State<Journal>, request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    match journal.flush().await {
+        Ok(()) => response,
+        Err(err) => AppError::internal(err).into_response(),
+    }
+}
+
+/// Tower layer holding each gRPC response back until the journal is flushed
+#[derive(Debug, Clone)]
+pub struct GrpcDurableLayer {
+    journal: Journal,
+}
+
+impl GrpcDurableLayer {
+    pub fn new(journal: Journal) -> Self {
+        Self { journal }
+    }
+}
+
+impl<S> tower::Layer<S> for GrpcDurableLayer {
+    type Service = GrpcDurable<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcDurable {
+            inner,
+            journal: self.journal.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GrpcDurable<S> {
+    inner: S,
+    journal: Journal,
+}
+
+impl<S, B, R> tower::Service<axum::http::Request<B>> for GrpcDurable<S>
+where
+    S: tower::Service<axum::http::Request<B>, Response = axum::http::Response<R>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    R: Default + Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<S::Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: axum::http::Request<B>) -> Self::Future {
+        let future = self.inner.call(request);
+        let journal = self.journal.clone();
+        Box::pin(async move {
+            let response = future.await?;
+            match journal.flush().await {
+                Ok(()) => Ok(response),
+                Err(err) => Ok(tonic::Status::from(AppError::internal(err)).into_http()),
+            }
+        })
+    }
+}
+
+/// JSON-RPC middleware holding each call's response back until the journal is
+/// flushed
+#[derive(Debug, Clone)]
+pub struct RpcDurableLayer {
+    journal: Journal,
+}
+
+impl RpcDurableLayer {
+    pub fn new(journal: Journal) -> Self {
+        Self { journal }
+    }
+}
+
+impl<S> tower::Layer<S> for RpcDurableLayer {
+    type Service = RpcDurable<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcDurable {
+            inner,
+            journal: self.journal.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RpcDurable<S> {
+    inner: S,
+    journal: Journal,
+}
+
+impl<'a, S> RpcServiceT<'a> for RpcDurable<S>
+where
+    S: RpcServiceT<'a>,
+    S::Future: 'a,
+{
+    type Future = BoxFuture<'a, MethodResponse>;
+
+    fn call(&self, request: jsonrpsee::types::Request<'a>) -> Self::Future {
+        let id = request.id().into_owned();
+        let future = self.inner.call(request);
+        let journal = self.journal.clone();
+        Box::pin(async move {
+            let response = future.await;
+            match journal.flush().await {
+                Ok(()) => response,
+                Err(err) => {
+                    MethodResponse::error(id, ErrorObjectOwned::from(AppError::internal(err)))
+                }
+            }
+        })
+    }
+}
+
+/// State being rebuilt from the snapshot and log during recovery
+#[derive(Default)]
+struct RecoveredState {
+    grid_items: BTreeMap<u64, GridItem>,
+    users: BTreeMap<i32, User>,
+    credentials: BTreeMap<i32, String>,
+    api_keys: BTreeMap<String, StoredApiKey>,
+}
+
+impl RecoveredState {
+    fn apply(&mut self, mutation: Mutation) {
+        match mutation {
+            Mutation::GridItemPut(item) => {
+                self.grid_items.insert(item.id, item);
+            }
+            Mutation::GridItemDelete(id) => {
+                self.grid_items.remove(&id);
+            }
+            Mutation::UserPut(user) => {
+                self.users.insert(user.id, user);
+            }
+            Mutation::UserDelete(id) => {
+                self.users.remove(&id);
+                self.credentials.remove(&id);
+            }
+            Mutation::PasswordSet(credential) => {
+                self.credentials
+                    .insert(credential.user_id, credential.password_hash);
+            }
+            Mutation::ApiKeyPut(stored) => {
+                self.api_keys.insert(stored.api_key.id.clone(), stored);
+            }
+            Mutation::ApiKeyDelete(id) => {
+                self.api_keys.remove(&id);
+            }
+        }
+    }
+}
+
+impl From<Snapshot> for RecoveredState {
+    fn from(snapshot: Snapshot) -> Self {
+        Self {
+            grid_items: snapshot
+                .grid_items
+                .into_iter()
+                .map(|item| (item.id, item))
+                .collect(),
+            users: snapshot
+                .users
+                .into_iter()
+                .map(|user| (user.id, user))
+                .collect(),
+            credentials: snapshot
+                .credentials
+                .into_iter()
+                .map(|credential| (credential.user_id, credential.password_hash))
+                .collect(),
+            api_keys: snapshot
+                .api_keys
+                .into_iter()
+                .map(|stored| (stored.api_key.id.clone(), stored))
+                .collect(),
+        }
+    }
+}
+
+impl From<RecoveredState> for Snapshot {
+    fn from(state: RecoveredState) -> Self {
+        // A password is logged ahead of its user, so a failed create can leave one behind
+        let credentials = state
+            .credentials
+            .into_iter()
+            .filter(|(user_id, _)| state.users.contains_key(user_id))
+            .map(|(user_id, password_hash)| PasswordCredential {
+                user_id,
+                password_hash,
+            })
+            .collect();
+        // Keys are revoked after their user is deleted; a crash in between leaves them behind
+        let api_keys = state
+            .api_keys
+            .into_values()
+            .filter(|stored| state.users.contains_key(&stored.api_key.user_id))
+            .collect();
+        Self {
+            grid_items: state.grid_items.into_values().collect(),
+            users: state.users.into_values().collect(),
+            credentials,
+            api_keys,
+        }
+    }
+}
+
+fn decode(record: &[u8]) -> io::Result<Mutation> {
+    serde_json::from_slice(record).map_err(io::Error::from)
+}
+
+fn read_snapshot(path: &Path) -> io::Result<Snapshot> {
+    match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).map_err(io::Error::from),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Snapshot::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Write the snapshot to a temporary file and atomically move it into place
+fn write_snapshot(dir: &Path, snapshot: &Snapshot) -> io::Result<()> {
+    let tmp = dir.join(format!("{SNAPSHOT_FILE}.tmp"));
+    let mut file = File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec(snapshot)?)?;
+    file.sync_all()?;
+
+    std::fs::rename(&tmp, dir.join(SNAPSHOT_FILE))?;
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(name: &str) -> StorageConfig {
+        let dir =
+            std::env::temp_dir().join(format!("omni-gate-journal-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        StorageConfig {
+            enabled: true,
+            data_dir: dir.to_string_lossy().into_owned(),
+            sync_writes: false,
+            ..StorageConfig::default()
+        }
+    }
+
+    fn item(id: u64, name: &str) -> GridItem {
+        GridItem {
+            id,
+            name: name.to_string(),
+            description: String::new(),
+            x: 0,
+            y: 0,
+            expires_at: None,
+            owner: String::new(),
+            grants: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_journal_replays_log() {
+        let config = storage("replay");
+        {
+            let (journal, recovered) = Journal::open(&config).unwrap();
+            assert!(recovered.grid_items.is_empty());
+            journal.record(Mutation::GridItemPut(item(1, "a"))).unwrap();
+            journal.record(Mutation::GridItemPut(item(2, "b"))).unwrap();
+            journal
+                .record(Mutation::GridItemPut(item(1, "a2")))
+                .unwrap();
+            journal.record(Mutation::GridItemDelete(2)).unwrap();
+        }
+
+        let (_, recovered) = Journal::open(&config).unwrap();
+        assert_eq!(recovered.grid_items.len(), 1);
+        assert_eq!(recovered.grid_items[0].name, "a2");
+    }
+
+    #[test]
+    fn test_journal_replays_passwords_of_live_users_only() {
+        let config = storage("passwords");
+        let user = |id| User {
+            id,
+            name: format!("user {id}"),
+            ..User::default()
+        };
+        let password = |user_id, hash: &str| {
+            Mutation::PasswordSet(PasswordCredential {
+                user_id,
+                password_hash: hash.to_string(),
+            })
+        };
+        {
+            let (journal, _) = Journal::open(&config).unwrap();
+            journal.record(password(1, "first")).unwrap();
+            journal.record(Mutation::UserPut(user(1))).unwrap();
+            journal.record(password(1, "second")).unwrap();
+            journal.record(password(2, "deleted")).unwrap();
+            journal.record(Mutation::UserPut(user(2))).unwrap();
+            journal.record(Mutation::UserDelete(2)).unwrap();
+            // Logged by a create that failed before its user was written
+            journal.record(password(3, "orphan")).unwrap();
+        }
+
+        let (_, recovered) = Journal::open(&config).unwrap();
+        assert_eq!(
+            recovered.credentials,
+            vec![PasswordCredential {
+                user_id: 1,
+                password_hash: "second".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_journal_replays_api_keys_of_live_users_only() {
+        let config = storage("api-keys");
+        let api_key = |id: &str, user_id| {
+            Mutation::ApiKeyPut(StoredApiKey {
+                api_key: crate::protos::user::ApiKey {
+                    id: id.to_string(),
+                    user_id,
+                    ..Default::default()
+                },
+                key_hash: format!("hash of {id}"),
+            })
+        };
+        {
+            let (journal, _) = Journal::open(&config).unwrap();
+            journal
+                .record(Mutation::UserPut(User {
+                    id: 1,
+                    ..User::default()
+                }))
+                .unwrap();
+            journal.record(api_key("a", 1)).unwrap();
+            journal.record(api_key("b", 1)).unwrap();
+            journal.record(api_key("c", 2)).unwrap();
+            journal
+                .record(Mutation::ApiKeyDelete("b".to_string()))
+                .unwrap();
+        }
+
+        let (_, recovered) = Journal::open(&config).unwrap();
+        let ids: Vec<_> = recovered
+            .api_keys
+            .iter()
+            .map(|stored| stored.api_key.id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["a"]);
+    }
+
+    #[test]
+    fn test_journal_compaction_keeps_later_records() {
+        let config = storage("compact");
+        {
+            let (journal, _) = Journal::open(&config).unwrap();
+            journal.record(Mutation::GridItemPut(item(1, "a"))).unwrap();
+            journal
+                .compact(|| Snapshot {
+                    grid_items: vec![item(1, "a")],
+                    ..Snapshot::default()
+                })
+                .unwrap();
+            journal.record(Mutation::GridItemPut(item(2, "b"))).unwrap();
+        }
+
+        let dir = PathBuf::from(&config.data_dir);
+        assert!(dir.join(SNAPSHOT_FILE).exists());
+        assert!(!dir.join(COMPACTING_FILE).exists());
+
+        let (_, recovered) = Journal::open(&config).unwrap();
+        let names: Vec<_> = recovered
+            .grid_items
+            .iter()
+            .map(|i| i.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_journal_recovers_interrupted_compaction() {
+        let config = storage("interrupted");
+        {
+            let (journal, _) = Journal::open(&config).unwrap();
+            journal.record(Mutation::GridItemPut(item(1, "a"))).unwrap();
+        }
+        let dir = PathBuf::from(&config.data_dir);
+        std::fs::rename(dir.join(WAL_FILE), dir.join(COMPACTING_FILE)).unwrap();
+
+        let (_, recovered) = Journal::open(&config).unwrap();
+        assert_eq!(recovered.grid_items.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_journal_flush_syncs_every_record_appended_before_it() {
+        let config = StorageConfig {
+            sync_writes: true,
+            ..storage("flush")
+        };
+        let (journal, _) = Journal::open(&config).unwrap();
+        let files = journal.inner.clone().unwrap();
+        journal.record(Mutation::GridItemPut(item(1, "a"))).unwrap();
+        journal.record(Mutation::GridItemPut(item(2, "b"))).unwrap();
+        assert_eq!(files.synced.load(Ordering::Acquire), 0);
+
+        let flushes: Vec<_> = (0..4).map(|_| journal.flush()).collect();
+        futures_util::future::try_join_all(flushes).await.unwrap();
+        assert_eq!(files.synced.load(Ordering::Acquire), 2);
+
+        // Records leaving with a compaction are synced on the way out
+        journal.record(Mutation::GridItemDelete(2)).unwrap();
+        journal.compact(Snapshot::default).unwrap();
+        assert_eq!(files.synced.load(Ordering::Acquire), 3);
+    }
+
+    #[test]
+    fn test_journal_refuses_a_damaged_log() {
+        let config = storage("damaged");
+        {
+            let (journal, _) = Journal::open(&config).unwrap();
+            journal.record(Mutation::GridItemPut(item(1, "a"))).unwrap();
+            journal.record(Mutation::GridItemPut(item(2, "b"))).unwrap();
+        }
+        let path = PathBuf::from(&config.data_dir).join(WAL_FILE);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[10] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let err = Journal::open(&config).map(|_| ()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -4,6 +4,9 @@
 //! SPDX-License-Identifier: Apache-2.0
 //! Author: imshike@gmail.com
 
+pub mod rpc_api;
+pub mod store;
+
 pub mod protos {
     pub mod helloworld {
         include!(concat!(env!("OUT_DIR"), "/helloworld.rs"));
@@ -11,4 +14,7 @@ pub mod protos {
     pub mod user {
         include!(concat!(env!("OUT_DIR"), "/user.rs"));
     }
+    pub mod grid {
+        include!(concat!(env!("OUT_DIR"), "/grid.rs"));
+    }
 }
@@ -0,0 +1,370 @@
+//! Login throttling module
+//!
+//! Counts failed logins per username and per client address so password
+//! guessing and spraying slow down and then stop. Failures past the backoff
+//! threshold block further attempts for an exponentially growing delay; enough
+//! of them lock the username or address for a fixed period. Blocked attempts are
+//! rejected before the password is checked.
+//!
+//! Attempts still checking their password count toward the next block, so
+//! parallel guesses cannot all get past the check before the first failure is
+//! recorded.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use crate::config::LockoutConfig;
+use crate::errors::{AppError, ErrorKind};
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// What failed logins are counted against
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Target {
+    /// Usernames are compared ignoring case, like emails
+    User(String),
+    Ip(IpAddr),
+}
+
+impl Target {
+    fn user(username: &str) -> Self {
+        Target::User(username.trim().to_lowercase())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last_failure: Instant,
+    blocked_until: Option<Instant>,
+    /// Attempts begun but not yet completed
+    pending: u32,
+}
+
+impl Failures {
+    fn new(now: Instant) -> Self {
+        Self {
+            count: 0,
+            last_failure: now,
+            blocked_until: None,
+            pending: 0,
+        }
+    }
+}
+
+/// Shared failed-login bookkeeping
+#[derive(Debug, Clone)]
+pub struct LoginGuard {
+    config: Arc<LockoutConfig>,
+    failures: Arc<DashMap<Target, Failures>>,
+}
+
+impl LoginGuard {
+    pub fn new(config: LockoutConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            failures: Arc::default(),
+        }
+    }
+
+    /// Reject the attempt if the username or client address is blocked
+    pub fn check(&self, username: &str, source: Option<IpAddr>) -> Result<(), AppError> {
+        let now = Instant::now();
+        let blocked_until = Self::targets(username, source)
+            .filter_map(|target| self.failures.get(&target)?.blocked_until)
+            .filter(|until| *until > now)
+            .max();
+        match blocked_until {
+            Some(until) => Err(ErrorKind::LoginLocked {
+                retry_after_secs: (until - now).as_secs_f64().ceil() as u64,
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Start an attempt for a username from a client address
+    ///
+    /// Fails like `check` if either is blocked, and also while as many attempts
+    /// are under way as failures would still pass unblocked. Complete the
+    /// attempt with `Attempt::succeeded` or `Attempt::failed`; dropping it
+    /// otherwise forgets it.
+    pub fn begin(&self, username: &str, source: Option<IpAddr>) -> Result<Attempt, AppError> {
+        self.check(username, source)?;
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.failure_window_secs);
+        let mut attempt = Attempt {
+            guard: self.clone(),
+            username: username.to_string(),
+            source,
+            reserved: Vec::new(),
+        };
+        for target in Self::targets(username, source) {
+            let threshold = match target {
+                Target::User(_) => self.config.max_failures_per_user,
+                Target::Ip(_) => self.config.max_failures_per_ip,
+            };
+            let mut failures = self
+                .failures
+                .entry(target.clone())
+                .or_insert(Failures::new(now));
+            // Blocks set since `check` by attempts completing in between count too
+            let rejected = match failures.blocked_until.filter(|until| *until > now) {
+                Some(until) => Some((until - now).as_secs_f64().ceil() as u64),
+                None => {
+                    let count = if now.duration_since(failures.last_failure) > window {
+                        0
+                    } else {
+                        failures.count
+                    };
+                    // Failures that would still leave the target unblocked
+                    let unblocked = self
+                        .config
+                        .backoff_after_failures
+                        .min(threshold.saturating_sub(1))
+                        .saturating_sub(count);
+                    (failures.pending >= unblocked.max(1)).then_some(1)
+                }
+            };
+            if rejected.is_none() {
+                failures.pending += 1;
+            }
+            drop(failures);
+            match rejected {
+                Some(retry_after_secs) => {
+                    return Err(ErrorKind::LoginLocked { retry_after_secs }.into())
+                }
+                None => attempt.reserved.push(target),
+            }
+        }
+        Ok(attempt)
+    }
+
+    /// Count a failed attempt, blocking further ones once thresholds are reached
+    pub fn record_failure(&self, username: &str, source: Option<IpAddr>) {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.failure_window_secs);
+        for target in Self::targets(username, source) {
+            let threshold = match target {
+                Target::User(_) => self.config.max_failures_per_user,
+                Target::Ip(_) => self.config.max_failures_per_ip,
+            };
+            let mut failures = self
+                .failures
+                .entry(target.clone())
+                .or_insert(Failures::new(now));
+            if now.duration_since(failures.last_failure) > window {
+                failures.count = 0;
+            }
+            failures.count = failures.count.saturating_add(1);
+            failures.last_failure = now;
+
+            let block = if failures.count >= threshold {
+                tracing::warn!(
+                    "Locking out {:?} after {} failed logins",
+                    target,
+                    failures.count
+                );
+                Some(Duration::from_secs(self.config.lockout_secs))
+            } else {
+                self.backoff(failures.count)
+            };
+            failures.blocked_until = block.map(|delay| now + delay);
+        }
+    }
+
+    /// Forget the failures of a username after it logged in
+    ///
+    /// Address counters are kept, so one correct guess does not reset a spray.
+    pub fn record_success(&self, username: &str) {
+        self.failures.remove(&Target::user(username));
+    }
+
+    /// Clear the failures and any block of a username and/or client address
+    ///
+    /// Returns whether anything was blocked or being counted.
+    pub fn unlock(&self, username: Option<&str>, source: Option<IpAddr>) -> bool {
+        let targets = username.map(Target::user).into_iter();
+        let targets = targets.chain(source.map(Target::Ip));
+        targets.fold(false, |cleared, target| {
+            self.failures.remove(&target).is_some() || cleared
+        })
+    }
+
+    /// Drop the entries whose block and failure window have both passed
+    pub fn prune(&self) -> usize {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.failure_window_secs);
+        let before = self.failures.len();
+        self.failures.retain(|_, failures| {
+            failures.pending > 0
+                || failures.blocked_until.is_some_and(|until| until > now)
+                || now.duration_since(failures.last_failure) <= window
+        });
+        before.saturating_sub(self.failures.len())
+    }
+
+    fn targets(username: &str, source: Option<IpAddr>) -> impl Iterator<Item = Target> {
+        std::iter::once(Target::user(username)).chain(source.map(Target::Ip))
+    }
+
+    /// Delay after the `count`th consecutive failure, doubling past the threshold
+    fn backoff(&self, count: u32) -> Option<Duration> {
+        let past = count.checked_sub(self.config.backoff_after_failures)?;
+        let exponent = past.checked_sub(1)?;
+        let base = Duration::from_millis(self.config.backoff_base_ms);
+        let max = Duration::from_secs(self.config.max_backoff_secs);
+        let delay = base
+            .checked_mul(2u32.saturating_pow(exponent))
+            .unwrap_or(max);
+        Some(delay.min(max))
+    }
+}
+
+/// A login attempt under way, from `LoginGuard::begin`
+#[derive(Debug)]
+pub struct Attempt {
+    guard: LoginGuard,
+    username: String,
+    source: Option<IpAddr>,
+    /// Targets whose pending count includes this attempt
+    reserved: Vec<Target>,
+}
+
+impl Attempt {
+    /// The password was right
+    pub fn succeeded(self) {
+        self.guard.record_success(&self.username);
+    }
+
+    /// The password was wrong
+    pub fn failed(self) {
+        self.guard.record_failure(&self.username, self.source);
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        for target in &self.reserved {
+            if let Some(mut failures) = self.guard.failures.get_mut(target) {
+                failures.pending = failures.pending.saturating_sub(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard() -> LoginGuard {
+        LoginGuard::new(LockoutConfig {
+            backoff_after_failures: 2,
+            backoff_base_ms: 1000,
+            max_backoff_secs: 3,
+            max_failures_per_user: 6,
+            max_failures_per_ip: 10,
+            lockout_secs: 60,
+            failure_window_secs: 300,
+        })
+    }
+
+    fn retry_after(result: Result<(), AppError>) -> Option<u64> {
+        result.err().and_then(|err| err.retry_after_secs())
+    }
+
+    fn ip(last: u8) -> Option<IpAddr> {
+        Some(IpAddr::from([10, 0, 0, last]))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_backoff_doubles_then_locks_out() {
+        let guard = guard();
+        let mut delays = Vec::new();
+        for _ in 0..6 {
+            guard.record_failure("Alice@example.com", ip(1));
+            let delay = retry_after(guard.check("alice@example.com", None));
+            delays.push(delay);
+            tokio::time::advance(Duration::from_secs(delay.unwrap_or(0))).await;
+        }
+
+        assert_eq!(
+            delays,
+            [None, None, Some(1), Some(2), Some(3), Some(60)],
+            "backoff is capped at max_backoff_secs before the lockout"
+        );
+        assert!(guard.check("alice@example.com", None).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_addresses_are_throttled_across_usernames() {
+        let guard = guard();
+        for n in 0..10 {
+            tokio::time::advance(Duration::from_secs(10)).await;
+            guard.record_failure(&format!("user{n}@example.com"), ip(1));
+        }
+
+        assert_eq!(
+            retry_after(guard.check("fresh@example.com", ip(1))),
+            Some(60)
+        );
+        assert!(guard.check("fresh@example.com", ip(2)).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_success_and_unlock_clear_failures() {
+        let guard = guard();
+        for _ in 0..3 {
+            guard.record_failure("alice@example.com", ip(1));
+        }
+        guard.record_success("alice@example.com");
+        assert!(guard.check("alice@example.com", None).is_ok());
+        // The address keeps its count
+        assert!(guard.check("bob@example.com", ip(1)).is_err());
+
+        assert!(guard.unlock(None, ip(1)));
+        assert!(guard.check("bob@example.com", ip(1)).is_ok());
+        assert!(!guard.unlock(Some("alice@example.com"), None));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failures_expire_after_the_window() {
+        let guard = guard();
+        guard.record_failure("alice@example.com", ip(1));
+        guard.record_failure("alice@example.com", ip(1));
+        assert_eq!(guard.prune(), 0);
+
+        tokio::time::advance(Duration::from_secs(301)).await;
+        guard.record_failure("alice@example.com", None);
+        assert!(guard.check("alice@example.com", None).is_ok());
+
+        assert_eq!(guard.prune(), 1, "only the address entry is stale");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_parallel_attempts_cannot_outrun_the_count() {
+        let guard = guard();
+        // Two failures pass unblocked, so only two attempts may be under way
+        let first = guard.begin("alice@example.com", ip(1)).unwrap();
+        let second = guard.begin("alice@example.com", ip(2)).unwrap();
+        assert!(guard.begin("alice@example.com", ip(3)).is_err());
+
+        first.failed();
+        second.failed();
+        // Each failure now blocks, so attempts go one at a time
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let third = guard.begin("alice@example.com", ip(3)).unwrap();
+        assert!(guard.begin("alice@example.com", ip(4)).is_err());
+        third.succeeded();
+        assert!(guard.begin("alice@example.com", ip(4)).is_ok());
+
+        // Attempts dropped without completing are forgotten
+        drop(guard.begin("bob@example.com", None).unwrap());
+        drop(guard.begin("bob@example.com", None).unwrap());
+        assert!(guard.begin("bob@example.com", None).is_ok());
+    }
+}
@@ -6,11 +6,25 @@
 //! SPDX-License-Identifier: Apache-2.0
 //! Author: imshike@gmail.com
 
+mod api_keys;
+mod auth;
+mod avatars;
 mod config;
 mod errors;
+mod events;
 mod handlers;
+mod journal;
+mod lockout;
+mod multiplex;
+mod rbac;
+mod request_id;
 mod routes;
+mod rpc_api;
 mod server;
+mod sessions;
+mod store;
+mod tokens;
+mod wal;
 
 mod protos {
     pub mod helloworld {
@@ -19,6 +33,9 @@ mod protos {
     pub mod user {
         include!(concat!(env!("OUT_DIR"), "/user.rs"));
     }
+    pub mod grid {
+        include!(concat!(env!("OUT_DIR"), "/grid.rs"));
+    }
 }
 
 use server::start_server;
@@ -0,0 +1,124 @@
+//! Protocol multiplexing module
+//!
+//! Lets gRPC share the REST listener. Requests whose content type is
+//! `application/grpc`, with or without a suffix such as `+proto`, go to the
+//! tonic routes, everything else, gRPC-Web included, to the REST router. The listener speaks HTTP/1.1
+//! and cleartext HTTP/2, which gRPC clients use with prior knowledge.
+//!
+//! gRPC requests skip the REST middleware; the routes carry their own request
+//! id and authorization layers, as on the standalone gRPC port.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{ConnectInfo, Request, State},
+    http::{header::CONTENT_TYPE, HeaderMap},
+    middleware::Next,
+    response::Response,
+    BoxError,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tonic::transport::server::TcpConnectInfo;
+use tower::util::BoxCloneSyncService;
+use tower::{Service, ServiceExt};
+
+/// Media type of gRPC requests, before any `+proto` or `+json` suffix
+const GRPC_CONTENT_TYPE: &str = "application/grpc";
+
+/// Whether a request with `headers` is a gRPC call
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| {
+            let media_type = content_type.split(';').next().unwrap_or_default();
+            let media_type = media_type.trim().to_ascii_lowercase();
+            media_type
+                .strip_prefix(GRPC_CONTENT_TYPE)
+                .is_some_and(|suffix| suffix.is_empty() || suffix.starts_with('+'))
+        })
+}
+
+/// gRPC routes, with their layers, as the REST listener calls them
+pub type GrpcService = BoxCloneSyncService<Request, Response, Infallible>;
+
+/// Box `service` for `grpc`
+pub fn grpc_service<S, B>(service: S) -> GrpcService
+where
+    S: Service<Request, Response = Response<B>, Error = Infallible> + Clone + Send + Sync + 'static,
+    S::Future: Send + 'static,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    BoxCloneSyncService::new(service.map_response(|response| response.map(Body::new)))
+}
+
+/// Axum middleware handing gRPC calls to `grpc`; add as the outermost layer
+pub async fn grpc(State(grpc): State<GrpcService>, mut request: Request, next: Next) -> Response {
+    if !is_grpc(request.headers()) {
+        return next.run(request).await;
+    }
+    // tonic reads the caller's address from its own connection info
+    if let Some(ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        let info = TcpConnectInfo {
+            local_addr: None,
+            remote_addr: Some(*addr),
+        };
+        request.extensions_mut().insert(info);
+    }
+    match grpc.oneshot(request).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware::from_fn_with_state, routing::post, Router};
+    use tower::service_fn;
+
+    #[tokio::test]
+    async fn test_grpc_calls_are_told_apart_by_content_type() {
+        let grpc = service_fn(|request: Request| async move {
+            let addr = request
+                .extensions()
+                .get::<TcpConnectInfo>()
+                .and_then(|info| info.remote_addr());
+            Ok::<_, Infallible>(Response::new(format!("grpc from {addr:?}")))
+        });
+        let app = Router::new()
+            .route("/user.UserService/GetUser", post(|| async { "rest" }))
+            .layer(from_fn_with_state(grpc_service(grpc), super::grpc));
+        let addr = SocketAddr::from(([10, 0, 0, 1], 4321));
+
+        for (content_type, expected) in [
+            ("application/grpc", "grpc from Some(10.0.0.1:4321)"),
+            ("application/grpc+proto", "grpc from Some(10.0.0.1:4321)"),
+            (
+                "Application/gRPC; charset=utf-8",
+                "grpc from Some(10.0.0.1:4321)",
+            ),
+            ("application/grpc-web", "rest"),
+            ("application/grpc-web+proto", "rest"),
+            ("application/grpc-web-text", "rest"),
+            ("application/grpcfoo", "rest"),
+            ("application/json", "rest"),
+        ] {
+            let mut request = Request::post("/user.UserService/GetUser")
+                .header(CONTENT_TYPE, content_type)
+                .body(Body::empty())
+                .unwrap();
+            request.extensions_mut().insert(ConnectInfo(addr));
+            let response = app.clone().oneshot(request).await.unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(body, expected, "{content_type}");
+        }
+    }
+}
@@ -0,0 +1,601 @@
+//! Role-based access control module
+//!
+//! Users hold roles, and roles grant permissions; users can also be granted
+//! permissions directly. Every authenticated caller additionally holds the
+//! roles in `rbac.default_roles`. Role definitions come from the `[rbac.roles]`
+//! config table, where `*` grants every permission.
+//!
+//! The policy below is the one place that says what each REST route, JSON-RPC
+//! method and gRPC method requires. It is enforced the same way in front of all
+//! three servers: by the `authorize_rest` middleware on the axum router, by
+//! `RpcAuthorizeLayer` in the jsonrpsee server and by `GrpcAuthLayer` around
+//! tonic. Calls missing from the policy are denied. Handlers still check what
+//! depends on the call's arguments, such as whose sessions are being revoked.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use crate::auth::{Authenticator, Principal};
+use crate::config::RbacConfig;
+use crate::errors::{AppError, ErrorKind};
+use crate::sessions::{ClientInfo, Protocol};
+use axum::{
+    extract::{MatchedPath, Request},
+    http::Method,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures_util::future::{ready, Either, Ready};
+use jsonrpsee::server::middleware::rpc::RpcServiceT;
+use jsonrpsee::types::ErrorObjectOwned;
+use jsonrpsee::MethodResponse;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tonic::transport::server::TcpConnectInfo;
+
+/// Something a caller may be allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Permission {
+    /// List, fetch and stream grid items
+    GridRead,
+    /// Create, change and delete grid items
+    GridWrite,
+    /// Read user records and follow their changes
+    UsersRead,
+    /// Create, change and delete user records
+    UsersWrite,
+    /// Manage roles, passwords, logins and sessions of other users
+    UsersAdmin,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 5] = [
+        Permission::GridRead,
+        Permission::GridWrite,
+        Permission::UsersRead,
+        Permission::UsersWrite,
+        Permission::UsersAdmin,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::GridRead => "grid:read",
+            Permission::GridWrite => "grid:write",
+            Permission::UsersRead => "users:read",
+            Permission::UsersWrite => "users:write",
+            Permission::UsersAdmin => "users:admin",
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|permission| permission.as_str() == name)
+            .ok_or_else(|| format!("unknown permission `{name}`"))
+    }
+}
+
+/// What a call requires of its caller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Requirement {
+    /// Anyone, including anonymous callers
+    Public,
+    /// Any authenticated caller; the handler checks the rest
+    Authenticated,
+    Permission(Permission),
+}
+
+const PUBLIC: Requirement = Requirement::Public;
+const AUTHENTICATED: Requirement = Requirement::Authenticated;
+const GRID_READ: Requirement = Requirement::Permission(Permission::GridRead);
+const GRID_WRITE: Requirement = Requirement::Permission(Permission::GridWrite);
+const USERS_READ: Requirement = Requirement::Permission(Permission::UsersRead);
+const USERS_WRITE: Requirement = Requirement::Permission(Permission::UsersWrite);
+const USERS_ADMIN: Requirement = Requirement::Permission(Permission::UsersAdmin);
+
+/// REST routes by method and route template
+const REST_POLICY: &[(&str, &str, Requirement)] = &[
+    ("GET", "/health", PUBLIC),
+    ("GET", "/openrpc.json", PUBLIC),
+    ("GET", "/api-docs/openapi.json", PUBLIC),
+    ("GET", "/swagger-ui", PUBLIC),
+    ("GET", "/swagger-ui/", PUBLIC),
+    ("GET", "/swagger-ui/{*rest}", PUBLIC),
+    ("GET", "/grid", GRID_READ),
+    ("POST", "/grid", GRID_WRITE),
+    ("GET", "/grid/events", GRID_READ),
+    ("GET", "/grid/{id}", GRID_READ),
+    ("PUT", "/grid/{id}", GRID_WRITE),
+    ("DELETE", "/grid/{id}", GRID_WRITE),
+    ("PUT", "/grid/{id}/grants", GRID_WRITE),
+    ("GET", "/sessions", AUTHENTICATED),
+    ("DELETE", "/sessions", AUTHENTICATED),
+    ("DELETE", "/sessions/{id}", AUTHENTICATED),
+    ("GET", "/api-keys", AUTHENTICATED),
+    ("POST", "/api-keys", AUTHENTICATED),
+    ("DELETE", "/api-keys/{id}", AUTHENTICATED),
+    ("POST", "/api-keys/{id}/rotate", AUTHENTICATED),
+    ("GET", "/users", USERS_READ),
+    ("POST", "/users", PUBLIC),
+    ("GET", "/users/search", USERS_READ),
+    ("GET", "/users/{id}", USERS_READ),
+    ("PATCH", "/users/{id}", USERS_WRITE),
+    ("DELETE", "/users/{id}", USERS_WRITE),
+    ("GET", "/users/{id}/avatar", USERS_READ),
+    ("PUT", "/users/{id}/avatar", AUTHENTICATED),
+];
+
+/// JSON-RPC methods by name
+const RPC_POLICY: &[(&str, Requirement)] = &[
+    ("get_user_info", USERS_READ),
+    ("update_user_info", USERS_WRITE),
+    ("list_users", USERS_READ),
+    ("verify_credentials", PUBLIC),
+    ("unlock_login", USERS_ADMIN),
+    ("refresh_token", PUBLIC),
+    ("set_password", AUTHENTICATED),
+    ("list_sessions", AUTHENTICATED),
+    ("revoke_session", AUTHENTICATED),
+    ("revoke_sessions", AUTHENTICATED),
+    ("create_api_key", AUTHENTICATED),
+    ("list_api_keys", AUTHENTICATED),
+    ("rotate_api_key", AUTHENTICATED),
+    ("revoke_api_key", AUTHENTICATED),
+    ("subscribe_user_updates", USERS_READ),
+    ("unsubscribe_user_updates", PUBLIC),
+    ("rpc.discover", PUBLIC),
+];
+
+/// gRPC methods by full path
+const GRPC_POLICY: &[(&str, Requirement)] = &[
+    ("/helloworld.Greeter/SayHello", PUBLIC),
+    ("/helloworld.Greeter/Echo", PUBLIC),
+    ("/user.UserService/GetUser", USERS_READ),
+    // Sign-up; setting roles or permissions needs `users:admin`
+    ("/user.UserService/CreateUser", PUBLIC),
+    ("/user.UserService/UpdateUser", USERS_WRITE),
+    ("/user.UserService/DeleteUser", USERS_WRITE),
+    ("/user.UserService/ListUsers", USERS_READ),
+    ("/user.UserService/ImportUsers", USERS_WRITE),
+    ("/user.UserService/ExportUsers", USERS_READ),
+    ("/user.UserService/SetPassword", AUTHENTICATED),
+    ("/user.UserService/Login", PUBLIC),
+    ("/user.UserService/RefreshToken", PUBLIC),
+    ("/user.UserService/UnlockLogin", USERS_ADMIN),
+    ("/user.UserService/ListSessions", AUTHENTICATED),
+    ("/user.UserService/RevokeSession", AUTHENTICATED),
+    ("/user.UserService/RevokeSessions", AUTHENTICATED),
+    ("/user.UserService/CreateApiKey", AUTHENTICATED),
+    ("/user.UserService/ListApiKeys", AUTHENTICATED),
+    ("/user.UserService/RotateApiKey", AUTHENTICATED),
+    ("/user.UserService/RevokeApiKey", AUTHENTICATED),
+    ("/user.UserService/SubscribeUserUpdates", USERS_READ),
+];
+
+/// Requirement of a REST route, given its route template
+pub fn rest_requirement(method: &Method, route: &str) -> Option<Requirement> {
+    REST_POLICY
+        .iter()
+        .find(|(m, r, _)| *m == method.as_str() && *r == route)
+        .map(|(_, _, requirement)| *requirement)
+}
+
+/// Requirement of a JSON-RPC method
+pub fn rpc_requirement(method: &str) -> Option<Requirement> {
+    RPC_POLICY
+        .iter()
+        .find(|(name, _)| *name == method)
+        .map(|(_, requirement)| *requirement)
+}
+
+/// Requirement of a gRPC method, given its full path
+pub fn grpc_requirement(path: &str) -> Option<Requirement> {
+    GRPC_POLICY
+        .iter()
+        .find(|(name, _)| *name == path)
+        .map(|(_, requirement)| *requirement)
+}
+
+/// Check `principal` against a call's requirement; calls without one are denied
+pub fn authorize(
+    requirement: Option<Requirement>,
+    principal: Option<&Principal>,
+) -> Result<(), AppError> {
+    match requirement {
+        Some(Requirement::Public) => Ok(()),
+        Some(Requirement::Authenticated) => principal
+            .map(|_| ())
+            .ok_or(AppError::from(ErrorKind::Unauthorized)),
+        Some(Requirement::Permission(permission)) => {
+            Principal::require(principal, permission).map(|_| ())
+        }
+        None if principal.is_none() => Err(ErrorKind::Unauthorized.into()),
+        None => Err(ErrorKind::Forbidden.into()),
+    }
+}
+
+/// Permissions granted by each role
+#[derive(Debug, Clone, Default)]
+pub struct Roles {
+    roles: Arc<HashMap<String, BTreeSet<Permission>>>,
+    default_roles: Arc<[String]>,
+}
+
+impl Roles {
+    /// Build the role table, skipping unknown permission names with a warning
+    pub fn new(config: &RbacConfig) -> Self {
+        let roles = config
+            .roles
+            .iter()
+            .map(|(role, names)| {
+                let permissions = names
+                    .iter()
+                    .flat_map(|name| match name.as_str() {
+                        "*" => Permission::ALL.to_vec(),
+                        name => match name.parse() {
+                            Ok(permission) => vec![permission],
+                            Err(e) => {
+                                tracing::warn!("Ignoring permission of role `{}`: {}", role, e);
+                                Vec::new()
+                            }
+                        },
+                    })
+                    .collect();
+                (role.clone(), permissions)
+            })
+            .collect();
+        Self {
+            roles: Arc::new(roles),
+            default_roles: config.default_roles.clone().into(),
+        }
+    }
+
+    /// Roles of an authenticated caller holding `roles`, defaults included
+    pub fn with_defaults(&self, mut roles: Vec<String>) -> Vec<String> {
+        for role in self.default_roles.iter() {
+            if !roles.contains(role) {
+                roles.push(role.clone());
+            }
+        }
+        roles
+    }
+
+    /// Permissions of a caller holding `roles` and granted `permissions` directly
+    pub fn permissions(&self, roles: &[String], permissions: &[String]) -> BTreeSet<Permission> {
+        let granted = permissions.iter().filter_map(|name| name.parse().ok());
+        roles
+            .iter()
+            .filter_map(|role| self.roles.get(role))
+            .flatten()
+            .copied()
+            .chain(granted)
+            .collect()
+    }
+}
+
+/// Axum middleware enforcing the REST policy; add with `Router::route_layer`
+pub async fn authorize_rest(request: Request, next: Next) -> Response {
+    // Unmatched requests fall through to the 404 fallback
+    let Some(route) = request.extensions().get::<MatchedPath>() else {
+        return next.run(request).await;
+    };
+    let requirement = rest_requirement(request.method(), route.as_str());
+    match authorize(requirement, request.extensions().get::<Principal>()) {
+        Ok(()) => next.run(request).await,
+        Err(err) => err.into_response(),
+    }
+}
+
+/// jsonrpsee RPC middleware enforcing the JSON-RPC policy
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RpcAuthorizeLayer;
+
+impl<S> tower::Layer<S> for RpcAuthorizeLayer {
+    type Service = RpcAuthorize<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcAuthorize { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RpcAuthorize<S> {
+    inner: S,
+}
+
+impl<'a, S> RpcServiceT<'a> for RpcAuthorize<S>
+where
+    S: RpcServiceT<'a>,
+{
+    type Future = Either<S::Future, Ready<MethodResponse>>;
+
+    fn call(&self, request: jsonrpsee::types::Request<'a>) -> Self::Future {
+        let requirement = rpc_requirement(request.method_name());
+        match authorize(requirement, request.extensions().get::<Principal>()) {
+            Ok(()) => Either::Left(self.inner.call(request)),
+            Err(err) => Either::Right(ready(MethodResponse::error(
+                request.id().into_owned(),
+                ErrorObjectOwned::from(err),
+            ))),
+        }
+    }
+}
+
+/// Tower layer authenticating and authorizing every call to the gRPC server
+///
+/// Tonic interceptors do not see which method is called, so the check runs
+/// on the HTTP request instead. The caller's `Principal` is left in the
+/// request extensions for the services to read.
+#[derive(Debug, Clone)]
+pub struct GrpcAuthLayer {
+    authenticator: Authenticator,
+}
+
+impl GrpcAuthLayer {
+    pub fn new(authenticator: Authenticator) -> Self {
+        Self { authenticator }
+    }
+}
+
+impl<S> tower::Layer<S> for GrpcAuthLayer {
+    type Service = GrpcAuth<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcAuth {
+            inner,
+            authenticator: self.authenticator.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GrpcAuth<S> {
+    inner: S,
+    authenticator: Authenticator,
+}
+
+impl<S, B, R> tower::Service<axum::http::Request<B>> for GrpcAuth<S>
+where
+    S: tower::Service<axum::http::Request<B>, Response = axum::http::Response<R>>,
+    R: Default,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Either<S::Future, Ready<Result<S::Response, S::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: axum::http::Request<B>) -> Self::Future {
+        let addr = request
+            .extensions()
+            .get::<TcpConnectInfo>()
+            .and_then(|info| info.remote_addr())
+            .map(|addr| addr.ip());
+        let client = ClientInfo::new(addr, Protocol::Grpc);
+
+        let principal = match self.authenticator.authenticate(request.headers(), &client) {
+            Ok(principal) => principal,
+            Err(err) => return Either::Right(ready(Ok(tonic::Status::from(err).into_http()))),
+        };
+        let requirement = grpc_requirement(request.uri().path());
+        if let Err(err) = authorize(requirement, principal.as_ref()) {
+            return Either::Right(ready(Ok(tonic::Status::from(err).into_http())));
+        }
+
+        if let Some(principal) = principal {
+            request.extensions_mut().insert(principal);
+        }
+        Either::Left(self.inner.call(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roles() -> Roles {
+        Roles::new(&RbacConfig::default())
+    }
+
+    fn principal(roles: &[&str]) -> Principal {
+        let roles: Vec<String> = roles.iter().map(|role| role.to_string()).collect();
+        Principal {
+            subject: "7".to_string(),
+            permissions: self::roles().permissions(&roles, &[]),
+            roles,
+            session_id: None,
+            api_key_id: None,
+        }
+    }
+
+    #[test]
+    fn test_roles_grant_their_permissions() {
+        let roles = roles();
+        let user = roles.with_defaults(Vec::new());
+        assert_eq!(user, ["user"]);
+        assert_eq!(
+            roles.permissions(&user, &["users:write".to_string(), "bogus".to_string()]),
+            BTreeSet::from([
+                Permission::GridRead,
+                Permission::GridWrite,
+                Permission::UsersRead,
+                Permission::UsersWrite,
+            ])
+        );
+        assert_eq!(
+            roles.permissions(&["admin".to_string()], &[]),
+            BTreeSet::from(Permission::ALL)
+        );
+        assert!(roles.permissions(&["nobody".to_string()], &[]).is_empty());
+    }
+
+    #[test]
+    fn test_policy_is_enforced() {
+        let user = principal(&["user"]);
+        let admin = principal(&["admin"]);
+
+        let unlock = rpc_requirement("unlock_login");
+        assert!(matches!(
+            authorize(unlock, None).map_err(AppError::into_kind),
+            Err(ErrorKind::Unauthorized)
+        ));
+        assert!(matches!(
+            authorize(unlock, Some(&user)).map_err(AppError::into_kind),
+            Err(ErrorKind::Forbidden)
+        ));
+        assert!(authorize(unlock, Some(&admin)).is_ok());
+
+        assert!(authorize(grpc_requirement("/user.UserService/Login"), None).is_ok());
+        let sessions = rest_requirement(&Method::DELETE, "/sessions/{id}");
+        assert!(authorize(sessions, None).is_err());
+        assert!(authorize(sessions, Some(&user)).is_ok());
+
+        // Unlisted calls are denied, even to admins
+        assert!(authorize(rpc_requirement("shutdown"), Some(&admin)).is_err());
+        assert!(authorize(rest_requirement(&Method::PATCH, "/grid"), Some(&admin)).is_err());
+    }
+
+    #[test]
+    fn test_every_rpc_method_has_a_policy() {
+        use crate::handlers::users::UserDirectory;
+        use crate::journal::Journal;
+        use crate::routes::json_rpc::{create_rpc_module, RpcContext};
+
+        let directory = UserDirectory::new(Arc::default(), Journal::default());
+        let sessions = crate::sessions::SessionStore::new(60);
+        let tokens = crate::tokens::TokenService::new(&Default::default());
+        let guard = crate::lockout::LoginGuard::new(Default::default());
+        let api_keys = crate::api_keys::ApiKeyStore::new(Journal::default());
+        let credentials = crate::handlers::credentials::Credentials::new(
+            directory.clone(),
+            tokens,
+            sessions.clone(),
+            api_keys.clone(),
+            guard,
+        );
+        let module = create_rpc_module(RpcContext {
+            directory,
+            credentials,
+            sessions,
+            api_keys,
+        });
+
+        for method in module.method_names() {
+            assert!(rpc_requirement(method).is_some(), "{method} has no policy");
+        }
+    }
+
+    /// Full paths of the methods `proto` declares, as `/package.Service/Method`
+    fn grpc_paths(proto: &str) -> Vec<String> {
+        let mut package = "";
+        let mut service = "";
+        let mut paths = Vec::new();
+        for line in proto.lines().map(str::trim) {
+            if let Some(name) = line.strip_prefix("package ") {
+                package = name.trim_end_matches(';').trim();
+            } else if let Some(name) = line.strip_prefix("service ") {
+                service = name.trim_end_matches('{').trim();
+            } else if let Some(rpc) = line.strip_prefix("rpc ") {
+                let method = rpc.split('(').next().unwrap_or_default().trim();
+                paths.push(format!("/{package}.{service}/{method}"));
+            }
+        }
+        paths
+    }
+
+    #[test]
+    fn test_every_grpc_method_has_a_policy() {
+        let paths: Vec<_> = [
+            include_str!("../protos/helloworld.proto"),
+            include_str!("../protos/user.proto"),
+        ]
+        .into_iter()
+        .flat_map(grpc_paths)
+        .collect();
+        assert!(paths.contains(&"/helloworld.Greeter/SayHello".to_string()));
+        assert!(paths.contains(&"/user.UserService/GetUser".to_string()));
+
+        let missing: Vec<_> = paths
+            .iter()
+            .filter(|path| grpc_requirement(path).is_none())
+            .collect();
+        assert!(missing.is_empty(), "no policy: {missing:?}");
+    }
+
+    #[tokio::test]
+    async fn test_every_rest_route_has_a_policy() {
+        use axum::{body::Body, http::header::ALLOW, http::StatusCode};
+        use tower::ServiceExt;
+
+        let routes = crate::routes::app_routes();
+        // axum cannot list its routes; read the templates off its Debug output,
+        // leaving out the fallback router's
+        let debug = format!("{routes:?}");
+        let debug = debug.split("fallback_router").next().unwrap_or_default();
+        let templates: Vec<&str> = debug
+            .split("RouteId(")
+            .skip(1)
+            .filter_map(|entry| entry.split_once("): \"")?.1.split('"').next())
+            .filter(|template| template.starts_with('/'))
+            .collect();
+        assert!(templates.contains(&"/health"), "routes: {templates:?}");
+        assert!(templates.contains(&"/grid/{id}"), "routes: {templates:?}");
+
+        let app = routes.with_state(crate::handlers::grid::tests::state_with(Vec::new()));
+        let mut missing = Vec::new();
+        for template in templates {
+            // A method no route takes is answered with the ones it does
+            let path = template.replace("{id}", "1");
+            let request = axum::http::Request::builder()
+                .method(Method::TRACE)
+                .uri(&path)
+                .body(Body::empty())
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED, "{path}");
+            let allow = response.headers()[ALLOW].to_str().unwrap();
+            for method in allow.split(',').map(str::trim).filter(|m| *m != "HEAD") {
+                let method = Method::from_str(method).unwrap();
+                if rest_requirement(&method, template).is_none() {
+                    missing.push(format!("{method} {template}"));
+                }
+            }
+        }
+        assert!(missing.is_empty(), "no policy: {missing:?}");
+    }
+
+    #[test]
+    fn test_every_rest_route_is_documented() {
+        use utoipa::OpenApi;
+
+        let document =
+            serde_json::to_value(crate::routes::ApiDoc::openapi()).expect("document serializes");
+        let undocumented: Vec<_> = REST_POLICY
+            .iter()
+            .filter(|(_, route, _)| {
+                !["/openrpc.json", "/api-docs/openapi.json"].contains(route)
+                    && !route.starts_with("/swagger-ui")
+            })
+            .filter(|(method, route, _)| {
+                document["paths"][*route][method.to_ascii_lowercase()].is_null()
+            })
+            .collect();
+        assert!(undocumented.is_empty(), "undocumented: {undocumented:?}");
+    }
+}
@@ -0,0 +1,136 @@
+//! Request id module
+//!
+//! Every REST, JSON-RPC and gRPC request gets an id: the one in its
+//! `x-request-id` header when the client sent a usable one, a random one
+//! otherwise. The id is echoed in the response headers and readable with
+//! `current()` while the request is handled, so error responses can quote it
+//! and a client's report can be matched with the server log.
+//!
+//! Calls on a JSON-RPC WebSocket run outside the request that opened the
+//! connection and have no id.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::http::{HeaderMap, HeaderValue, Request, Response};
+use futures_util::future::BoxFuture;
+use std::task::{Context, Poll};
+
+/// Header carrying the request id both ways
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Longest client-chosen id kept; longer ones are replaced
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Id of the request being handled, if any
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// The client's id if it is short and plain enough to log, a new one otherwise
+fn from_headers(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+        })
+        .map(str::to_string)
+        .unwrap_or_else(new_request_id)
+}
+
+fn new_request_id() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Tower layer giving each HTTP request an id; see the module docs
+#[derive(Debug, Clone, Default)]
+pub struct RequestIdLayer;
+
+impl<S> tower::Layer<S> for RequestIdLayer {
+    type Service = RequestId<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestId { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestId<S> {
+    inner: S,
+}
+
+impl<S, B, R> tower::Service<Request<B>> for RequestId<S>
+where
+    S: tower::Service<Request<B>, Response = Response<R>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<S::Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let id = from_headers(request.headers());
+        let header = HeaderValue::from_str(&id).expect("request ids are plain ASCII");
+        // Services may reject a request before returning their future
+        let future = REQUEST_ID.sync_scope(id.clone(), || self.inner.call(request));
+        Box::pin(REQUEST_ID.scope(id, async move {
+            let mut response = future.await?;
+            response.headers_mut().insert(REQUEST_ID_HEADER, header);
+            Ok(response)
+        }))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tower::{service_fn, Layer, ServiceExt};
+
+    /// Run `f` as if handling the request `id`
+    pub(crate) fn in_request<T>(id: &str, f: impl FnOnce() -> T) -> T {
+        REQUEST_ID.sync_scope(id.to_string(), f)
+    }
+
+    #[tokio::test]
+    async fn test_requests_get_an_id_seen_by_handlers_and_clients() {
+        let service = RequestIdLayer.layer(service_fn(|_: Request<()>| async {
+            Ok::<_, std::convert::Infallible>(Response::new(current().unwrap()))
+        }));
+
+        let request = Request::builder()
+            .header(REQUEST_ID_HEADER, "client-id-1")
+            .body(())
+            .unwrap();
+        let response = service.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "client-id-1");
+        assert_eq!(response.body(), "client-id-1");
+
+        let request = Request::builder()
+            .header(REQUEST_ID_HEADER, "not plain enough")
+            .body(())
+            .unwrap();
+        let response = service.oneshot(request).await.unwrap();
+        assert_eq!(response.body().len(), 32);
+        assert_eq!(
+            response.headers()[REQUEST_ID_HEADER],
+            response.body().as_str()
+        );
+        assert_eq!(current(), None);
+    }
+}
@@ -28,6 +28,7 @@ pub struct HealthResponse {
 #[utoipa::path(
     get,
     path = "/health",
+    security(()),
     responses(
         (status = 200, description = "Health check successful", body = HealthResponse),
     )
@@ -2,104 +2,602 @@
 //!
 //! Handles JSON-RPC related requests using jsonrpsee native server.
 //!
+//! Callers authenticate with an `Authorization` or `X-Api-Key` header on the
+//! HTTP request or the WebSocket handshake.
+//!
+//! Connections are accepted here rather than by jsonrpsee so each request can
+//! carry the caller's `Principal` and address (as `ConnectInfo`) in its
+//! extensions, where methods read them.
+//!
+//! Depending on `server.jsonrpc_mode`, the same service also answers on the
+//! REST port, at `POST /rpc` and `GET /rpc/ws`, behind the REST CORS and
+//! request id layers.
+//!
+//! The methods implement the `UserRpc` trait of `rpc_api`. Every call is
+//! checked against the `rbac` policy before its method runs.
+//!
+//! A WebSocket connection opened with a session's token or an API key is
+//! closed as soon as that session or key is revoked, ending its subscriptions.
+//!
 //! Copyright © 2025 imshike@gmail.com
 //! SPDX-License-Identifier: Apache-2.0
 //! Author: imshike@gmail.com
 
-use jsonrpsee::core::error::StringError;
-use jsonrpsee::server::RpcModule;
-use jsonrpsee::server::SubscriptionMessage;
-use jsonrpsee::types::ErrorObjectOwned;
-use serde_json::Value;
+use axum::error_handling::HandleError;
+use axum::extract::ConnectInfo;
+use axum::http::header::CONTENT_TYPE;
+use axum::routing::{get_service, post_service};
+use axum::{BoxError, Router};
+use futures_util::future::BoxFuture;
+use jsonrpsee::core::{async_trait, RpcResult, SubscriptionResult};
+use jsonrpsee::server::middleware::rpc::{RpcServiceBuilder, RpcServiceT};
+use jsonrpsee::server::{
+    serve_with_graceful_shutdown, stop_channel, ws::is_upgrade_request, HttpRequest, HttpResponse,
+    Methods, PendingSubscriptionSink, RpcModule, Server, ServerHandle, StopHandle,
+    SubscriptionMessage, TowerService, TowerServiceBuilder,
+};
+use jsonrpsee::types::{ErrorObjectOwned, Id, Request, Response, ResponsePayload};
+use jsonrpsee::Extensions;
+use serde_json::value::RawValue;
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
 use std::time::Duration;
+use tokio::net::TcpListener;
+use tower::layer::util::{Identity, Stack};
+use tower::Layer;
 
+use crate::api_keys::ApiKeyStore;
+use crate::auth::{Authenticator, Principal};
+use crate::errors::AppError;
+use crate::handlers::credentials::Credentials;
 use crate::handlers::user_info as rpc;
+use crate::handlers::users::UserDirectory;
+use crate::journal::{Journal, RpcDurableLayer};
+use crate::protos::user::{
+    ApiKeySecret, ListApiKeysResponse, ListSessionsResponse, ListUsersRequest, ListUsersResponse,
+    RevokeApiKeyResponse, RevokeSessionsResponse, SetPasswordResponse, TokenResponse,
+    UnlockLoginResponse,
+};
+use crate::rbac::RpcAuthorizeLayer;
+use crate::request_id::RequestIdLayer;
+use crate::routes::openrpc;
+use crate::rpc_api::{
+    ApiKeyParams, ApiKeyQuery, CreateApiKeyParams, RefreshTokenParams, RevokeSessionParams,
+    SessionsParams, SetPasswordParams, SubscribeUserUpdatesParams, UnlockLoginParams,
+    UpdateUserInfoParams, UpdateUserInfoResponse, UserInfo, UserRpcServer, VerifyCredentialsParams,
+    VerifyCredentialsResponse,
+};
+use crate::sessions::{ClientInfo, Protocol, Revocation, SessionStore};
 
-/// Create and configure JSON-RPC module
-pub fn create_rpc_module() -> RpcModule<()> {
-    let mut module = RpcModule::new(());
+/// Pause after a failed accept, so a full descriptor table is not spun on
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
 
-    module
-        .register_async_method("get_user_info", |_params, _subscription, _ctx| async move {
-            rpc::get_user_info().await.map_err(|e: ErrorObjectOwned| e)
-        })
-        .unwrap();
+/// RPC middleware applied to every call
+type RpcMiddleware =
+    Stack<ObjectParamsLayer, Stack<RpcAuthorizeLayer, Stack<RpcDurableLayer, Identity>>>;
+/// Per-connection service serving the methods
+type RpcService = TowerService<RpcMiddleware, Identity>;
 
-    module
-        .register_async_method(
-            "update_user_info",
-            |params, _subscription, _ctx| async move {
-                let value: Value = params.parse().unwrap_or_default();
-                rpc::update_user_info(value)
-                    .await
-                    .map_err(|e: ErrorObjectOwned| e)
-            },
-        )
-        .unwrap();
+/// State shared by the JSON-RPC methods
+#[derive(Debug, Clone)]
+pub struct RpcContext {
+    pub directory: UserDirectory,
+    pub credentials: Credentials,
+    pub sessions: SessionStore,
+    pub api_keys: ApiKeyStore,
+}
 
-    module
-        .register_async_method(
-            "verify_credentials",
-            |params, _subscription, _ctx| async move {
-                let value: Value = params.parse().unwrap_or_default();
-                rpc::verify_credentials(value)
-                    .await
-                    .map_err(|e: ErrorObjectOwned| e)
-            },
-        )
-        .unwrap();
+/// Address of the client that sent the call
+fn client(extensions: &Extensions) -> ClientInfo {
+    let addr = extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    ClientInfo::new(addr, Protocol::JsonRpc)
+}
+
+/// RPC middleware passing parameters given by name as the single parameter object
+///
+/// Every method takes at most one parameter object, so `"params": {...}` means
+/// the same as `"params": [{...}]`, the form the generated server decodes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ObjectParamsLayer;
+
+impl<S> tower::Layer<S> for ObjectParamsLayer {
+    type Service = ObjectParams<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ObjectParams { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectParams<S> {
+    inner: S,
+}
+
+impl<'a, S> RpcServiceT<'a> for ObjectParams<S>
+where
+    S: RpcServiceT<'a>,
+{
+    type Future = S::Future;
+
+    fn call(&self, mut request: Request<'a>) -> Self::Future {
+        let wrapped = request
+            .params
+            .as_ref()
+            .filter(|params| params.get().trim_start().starts_with('{'))
+            .and_then(|params| RawValue::from_string(format!("[{}]", params.get())).ok());
+        if let Some(wrapped) = wrapped {
+            request.params = Some(Cow::Owned(wrapped));
+        }
+        self.inner.call(request)
+    }
+}
+
+#[async_trait]
+impl UserRpcServer for RpcContext {
+    async fn get_user_info(&self) -> RpcResult<UserInfo> {
+        rpc::get_user_info().await
+    }
+
+    async fn update_user_info(
+        &self,
+        extensions: &Extensions,
+        params: UpdateUserInfoParams,
+    ) -> RpcResult<UpdateUserInfoResponse> {
+        let principal = extensions.get::<Principal>();
+        rpc::update_user_info(&self.directory, principal, params).await
+    }
+
+    async fn list_users(&self, params: Option<ListUsersRequest>) -> RpcResult<ListUsersResponse> {
+        rpc::list_users(&self.directory, params.unwrap_or_default()).await
+    }
+
+    async fn verify_credentials(
+        &self,
+        extensions: &Extensions,
+        params: VerifyCredentialsParams,
+    ) -> RpcResult<VerifyCredentialsResponse> {
+        rpc::verify_credentials(&self.credentials, params, &client(extensions)).await
+    }
+
+    async fn unlock_login(&self, params: UnlockLoginParams) -> RpcResult<UnlockLoginResponse> {
+        rpc::unlock_login(&self.credentials, params).await
+    }
+
+    async fn refresh_token(
+        &self,
+        extensions: &Extensions,
+        params: RefreshTokenParams,
+    ) -> RpcResult<TokenResponse> {
+        rpc::refresh_token(&self.credentials, params, &client(extensions)).await
+    }
+
+    async fn set_password(
+        &self,
+        extensions: &Extensions,
+        params: SetPasswordParams,
+    ) -> RpcResult<SetPasswordResponse> {
+        let principal = extensions.get::<Principal>();
+        rpc::set_password(&self.credentials, principal, params).await
+    }
+
+    async fn list_sessions(
+        &self,
+        extensions: &Extensions,
+        params: Option<SessionsParams>,
+    ) -> RpcResult<ListSessionsResponse> {
+        let principal = extensions.get::<Principal>();
+        rpc::list_sessions(&self.sessions, principal, params.unwrap_or_default()).await
+    }
+
+    async fn revoke_session(
+        &self,
+        extensions: &Extensions,
+        params: RevokeSessionParams,
+    ) -> RpcResult<RevokeSessionsResponse> {
+        let principal = extensions.get::<Principal>();
+        rpc::revoke_session(&self.sessions, principal, params).await
+    }
+
+    async fn revoke_sessions(
+        &self,
+        extensions: &Extensions,
+        params: Option<SessionsParams>,
+    ) -> RpcResult<RevokeSessionsResponse> {
+        let principal = extensions.get::<Principal>();
+        rpc::revoke_sessions(&self.sessions, principal, params.unwrap_or_default()).await
+    }
+
+    async fn create_api_key(
+        &self,
+        extensions: &Extensions,
+        params: CreateApiKeyParams,
+    ) -> RpcResult<ApiKeySecret> {
+        let principal = extensions.get::<Principal>();
+        rpc::create_api_key(&self.api_keys, &self.directory, principal, params).await
+    }
+
+    async fn list_api_keys(
+        &self,
+        extensions: &Extensions,
+        params: Option<ApiKeyQuery>,
+    ) -> RpcResult<ListApiKeysResponse> {
+        let principal = extensions.get::<Principal>();
+        rpc::list_api_keys(&self.api_keys, principal, params.unwrap_or_default()).await
+    }
+
+    async fn rotate_api_key(
+        &self,
+        extensions: &Extensions,
+        params: ApiKeyParams,
+    ) -> RpcResult<ApiKeySecret> {
+        let principal = extensions.get::<Principal>();
+        rpc::rotate_api_key(&self.api_keys, principal, params).await
+    }
+
+    async fn revoke_api_key(
+        &self,
+        extensions: &Extensions,
+        params: ApiKeyParams,
+    ) -> RpcResult<RevokeApiKeyResponse> {
+        let principal = extensions.get::<Principal>();
+        rpc::revoke_api_key(&self.api_keys, principal, params).await
+    }
+
+    async fn subscribe_user_updates(
+        &self,
+        pending: PendingSubscriptionSink,
+        params: Option<SubscribeUserUpdatesParams>,
+    ) -> SubscriptionResult {
+        let params = params.unwrap_or_default();
+        let keepalive = params.interval_seconds.map(Duration::from_secs);
+        let mut subscription = self.directory.subscribe(params.user_id, keepalive);
 
+        let sink = pending.accept().await?;
+        // Stop as soon as the client unsubscribes or disconnects, even when
+        // no update is coming
+        loop {
+            let update = tokio::select! {
+                update = subscription.next() => update,
+                _ = sink.closed() => break,
+            };
+            let Some(update) = update else { break };
+            let msg = SubscriptionMessage::from_json(&update)?;
+            if sink.send(msg).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Create the JSON-RPC module backed by the shared user directory
+pub fn create_rpc_module(context: RpcContext) -> RpcModule<RpcContext> {
+    let mut module = context.into_rpc();
+    let document = openrpc::document(module.method_names());
+    module
+        .register_method(openrpc::DISCOVER_METHOD, move |_, _, _| document.clone())
+        .expect("rpc.discover is not a UserRpc method");
     module
-        .register_subscription(
-            "subscribe_user_updates",
-            "subscribe_user_updates",
-            "unsubscribe_user_updates",
-            |params, pending, _ctx, _extensions| async move {
-                let value: Value = params.parse().unwrap_or_default();
-                let user_id = value.get("user_id").and_then(|v| v.as_i64()).unwrap_or(1);
-                let interval_secs = value
-                    .get("interval_seconds")
-                    .and_then(|v| v.as_u64())
-                    .unwrap_or(2);
-                let interval = Duration::from_secs(interval_secs);
-
-                let sink = pending.accept().await.unwrap();
-
-                let mut counter = 0;
-                loop {
-                    tokio::time::sleep(interval).await;
-
-                    counter += 1;
-                    let timestamp = std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs();
-
-                    let update_type = match counter % 3 {
-                        0 => "profile_update",
-                        1 => "activity_update",
-                        _ => "status_update",
-                    };
-
-                    let update = serde_json::json!({
-                        "user_id": user_id,
-                        "name": format!("User {}", user_id),
-                        "email": format!("user{}@example.com", user_id),
-                        "age": 30 + (counter % 10),
-                        "update_type": update_type,
-                        "timestamp": timestamp,
-                        "counter": counter
-                    });
-
-                    let msg = SubscriptionMessage::from_json(&update).unwrap();
-                    if sink.send(msg).await.is_err() {
-                        break;
-                    }
+}
+
+/// Serve `service` on `addr`; only failing to bind ends it
+///
+/// Failed accepts, such as running out of file descriptors or a peer resetting
+/// before it was accepted, are logged and retried after `ACCEPT_BACKOFF`.
+pub async fn serve(addr: SocketAddr, service: JsonRpcService) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    loop {
+        let (socket, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                tracing::warn!("JSON-RPC server failed to accept a connection: {}", err);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let stopped = service.stop_handle.clone().shutdown();
+        let service = RequestIdLayer.layer(JsonRpcService {
+            remote_addr: Some(remote_addr),
+            ..service.clone()
+        });
+        tokio::spawn(serve_with_graceful_shutdown(socket, service, stopped));
+    }
+}
+
+/// `POST /rpc` and the WebSocket endpoint `GET /rpc/ws`, for serving JSON-RPC
+/// on the REST port
+///
+/// The caller's address comes from the router's `ConnectInfo`.
+pub fn rpc_routes(service: JsonRpcService) -> Router {
+    let service = HandleError::new(
+        service,
+        |err: BoxError| async move { AppError::internal(err) },
+    );
+    Router::new()
+        .route_service("/rpc", post_service(service.clone()))
+        .route_service("/rpc/ws", get_service(service))
+}
+
+/// Service attaching the caller's identity and address to each request
+#[derive(Clone)]
+pub struct JsonRpcService {
+    builder: TowerServiceBuilder<RpcMiddleware, Identity>,
+    methods: Methods,
+    authenticator: Authenticator,
+    /// Peer of the connection being served, when it was accepted by `serve`
+    remote_addr: Option<SocketAddr>,
+    stop_handle: StopHandle,
+    /// Held for the life of the service; dropping it would stop every connection
+    _server_handle: ServerHandle,
+}
+
+impl JsonRpcService {
+    pub fn new(
+        module: RpcModule<RpcContext>,
+        authenticator: Authenticator,
+        journal: Journal,
+    ) -> Self {
+        let builder = Server::builder()
+            .set_rpc_middleware(
+                RpcServiceBuilder::new()
+                    .layer(RpcDurableLayer::new(journal))
+                    .layer(RpcAuthorizeLayer)
+                    .layer(ObjectParamsLayer),
+            )
+            .to_service_builder();
+        let (stop_handle, server_handle) = stop_channel();
+        Self {
+            builder,
+            methods: module.into(),
+            authenticator,
+            remote_addr: None,
+            stop_handle,
+            _server_handle: server_handle,
+        }
+    }
+
+    /// Service for one connection, or one request when mounted in the router
+    fn connection(&self) -> RpcService {
+        self.builder
+            .clone()
+            .build(self.methods.clone(), self.stop_handle.clone())
+    }
+
+    /// Service for a WebSocket connection that closes on `revocation`
+    fn closing_on(&self, revocation: Revocation) -> RpcService {
+        let (stop_handle, server_handle) = stop_channel();
+        let service = self
+            .builder
+            .clone()
+            .build(self.methods.clone(), stop_handle);
+        tokio::spawn(async move {
+            // `stopped` resolves once the connection has closed by itself
+            tokio::select! {
+                _ = revocation.revoked() => {
+                    let _ = server_handle.stop();
                 }
-                Ok::<(), StringError>(())
-            },
-        )
-        .unwrap();
+                _ = server_handle.clone().stopped() => {}
+            }
+        });
+        service
+    }
+}
 
-    module
+impl<B> tower::Service<HttpRequest<B>> for JsonRpcService
+where
+    RpcService: tower::Service<
+        HttpRequest<B>,
+        Response = HttpResponse,
+        Error = BoxError,
+        Future = BoxFuture<'static, Result<HttpResponse, BoxError>>,
+    >,
+{
+    type Response = HttpResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<HttpResponse, BoxError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Each call builds its own service, which is always ready
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut request: HttpRequest<B>) -> Self::Future {
+        let remote_addr = self.remote_addr.or_else(|| {
+            request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| *addr)
+        });
+        // Bad credentials fail the whole request, as on REST and gRPC
+        let client = ClientInfo::new(remote_addr.map(|addr| addr.ip()), Protocol::JsonRpc);
+        let principal = match self.authenticator.authenticate(request.headers(), &client) {
+            Ok(principal) => principal,
+            Err(err) => return Box::pin(std::future::ready(Ok(rejection(err)))),
+        };
+        // A WebSocket outlives its handshake; close it with the session or key
+        let revocation = principal
+            .as_ref()
+            .filter(|principal| {
+                (principal.session_id.is_some() || principal.api_key_id.is_some())
+                    && is_upgrade_request(&request)
+            })
+            .map(|principal| {
+                principal.revocation(self.authenticator.sessions(), self.authenticator.api_keys())
+            });
+        if let Some(principal) = principal {
+            request.extensions_mut().insert(principal);
+        }
+        if let Some(remote_addr) = remote_addr {
+            request.extensions_mut().insert(ConnectInfo(remote_addr));
+        }
+        match revocation {
+            Some(revocation) => self.closing_on(revocation).call(request),
+            None => self.connection().call(request),
+        }
+    }
+}
+
+/// Response refusing a request outright, with the HTTP status REST would send
+/// and a JSON-RPC error response whose id is `null`
+fn rejection(err: AppError) -> HttpResponse {
+    let status = err.kind().status_code();
+    let payload = ResponsePayload::<()>::error(ErrorObjectOwned::from(err));
+    let body = serde_json::to_string(&Response::new(payload, Id::Null))
+        .expect("JSON-RPC responses serialize");
+    HttpResponse::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.into())
+        .expect("status and content type are valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AuthConfig, LockoutConfig};
+    use crate::lockout::LoginGuard;
+    use crate::tokens::TokenService;
+    use jsonrpsee::types::error::INVALID_PARAMS_CODE;
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    fn module() -> RpcModule<RpcContext> {
+        let directory = UserDirectory::new(Arc::default(), Journal::default());
+        let sessions = SessionStore::new(3600);
+        let tokens = TokenService::new(&AuthConfig {
+            jwt_secret: "test-secret".to_string(),
+            ..AuthConfig::default()
+        });
+        let api_keys = ApiKeyStore::new(Journal::default());
+        let credentials = Credentials::new(
+            directory.clone(),
+            tokens,
+            sessions.clone(),
+            api_keys.clone(),
+            LoginGuard::new(LockoutConfig::default()),
+        );
+        create_rpc_module(RpcContext {
+            directory,
+            credentials,
+            sessions,
+            api_keys,
+        })
+    }
+
+    async fn call(module: &RpcModule<RpcContext>, method: &str, params: &str) -> Value {
+        let request =
+            format!(r#"{{"jsonrpc":"2.0","id":1,"method":"{method}","params":{params}}}"#);
+        let (response, _) = module.raw_json_request(&request, 1).await.unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_malformed_params_are_rejected_before_the_method_runs() {
+        let module = module();
+        for params in [
+            r#"[{"username": 7, "password": "secret"}]"#,
+            r#"[{"password": "secret"}]"#,
+            "[]",
+        ] {
+            let response = call(&module, "verify_credentials", params).await;
+            assert_eq!(response["error"]["code"], INVALID_PARAMS_CODE, "{params}");
+        }
+        let response = call(&module, "list_users", r#"[{"page_size": "ten"}]"#).await;
+        assert_eq!(response["error"]["code"], INVALID_PARAMS_CODE);
+
+        let response = call(&module, "list_users", "[]").await;
+        assert_eq!(response["result"]["users"], Value::Array(Vec::new()));
+        let response = call(&module, "get_user_info", "[]").await;
+        assert_eq!(response["result"]["status"], "active");
+    }
+
+    #[tokio::test]
+    async fn test_discover_describes_every_method() {
+        let module = module();
+        let response = call(&module, "rpc.discover", "[]").await;
+        let methods = response["result"]["methods"].as_array().unwrap();
+
+        let registered = module
+            .method_names()
+            .filter(|name| *name != openrpc::DISCOVER_METHOD)
+            .count();
+        assert_eq!(methods.len(), registered);
+        for method in methods {
+            assert!(
+                method["summary"].is_string(),
+                "{} is undocumented",
+                method["name"]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mounted_routes_apply_the_rpc_policy() {
+        use axum::body::Body;
+        use axum::http::{Method, Request as AxumRequest, StatusCode};
+        use tower::ServiceExt;
+
+        let authenticator = Authenticator::new(
+            TokenService::new(&AuthConfig::default()),
+            SessionStore::new(3600),
+            ApiKeyStore::new(Journal::default()),
+            UserDirectory::new(Arc::default(), Journal::default()),
+            crate::rbac::Roles::default(),
+            &AuthConfig::default(),
+        );
+        let routes = rpc_routes(JsonRpcService::new(
+            module(),
+            authenticator,
+            Journal::default(),
+        ));
+        let post = |method: &str| {
+            let body = format!(r#"{{"jsonrpc":"2.0","id":1,"method":"{method}","params":[]}}"#);
+            AxumRequest::post("/rpc")
+                .header("content-type", "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let response = routes.clone().oneshot(post("rpc.discover")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert!(body["result"]["methods"].is_array());
+
+        // Anonymous callers get a JSON-RPC error, not an HTTP one
+        let response = routes.clone().oneshot(post("list_users")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert!(body["error"]["code"].is_i64());
+
+        // A bad token fails even a public method, as it would on REST
+        let mut request = post("rpc.discover");
+        request
+            .headers_mut()
+            .insert("authorization", "Bearer not-a-token".parse().unwrap());
+        let response = routes.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["id"], Value::Null);
+        assert_eq!(body["error"]["data"]["code"], 1003);
+
+        let request = AxumRequest::builder()
+            .method(Method::GET)
+            .uri("/rpc")
+            .body(Body::empty())
+            .unwrap();
+        let response = routes.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
 }
@@ -6,12 +6,83 @@
 //! SPDX-License-Identifier: Apache-2.0
 //! Author: imshike@gmail.com
 
-use axum::Router;
+use crate::config::ApiDocsConfig;
+use crate::handlers::{api_keys, grid, sessions, users_rest};
+use axum::{routing::get, Json, Router};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use utoipa_swagger_ui::SwaggerUi;
 
 pub mod health;
 pub mod json_rpc;
+pub mod openrpc;
 pub mod rest;
 
+/// Where the OpenAPI document is served
+pub const OPENAPI_PATH: &str = "/api-docs/openapi.json";
+/// Where Swagger UI is served
+pub const SWAGGER_UI_PATH: &str = "/swagger-ui";
+
+/// OpenAPI document of the REST API
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "omni-gate REST API",
+        description = "JSON bodies are shown; requests and responses may also be \
+                       MessagePack, CBOR or Protobuf, chosen by `Content-Type` and `Accept`."
+    ),
+    paths(
+        health::health_check_handler,
+        grid::list,
+        grid::create,
+        grid::events,
+        grid::get_by_id,
+        grid::update,
+        grid::delete_by_id,
+        grid::set_grants,
+        sessions::list_sessions,
+        sessions::revoke_sessions,
+        sessions::revoke_session,
+        api_keys::list_api_keys,
+        api_keys::create_api_key,
+        api_keys::revoke_api_key,
+        api_keys::rotate_api_key,
+        users_rest::list_users,
+        users_rest::create_user,
+        users_rest::search_users,
+        users_rest::get_user,
+        users_rest::update_user,
+        users_rest::delete_user,
+        users_rest::get_avatar,
+        users_rest::put_avatar,
+    ),
+    modifiers(&SecuritySchemes),
+    security(("bearer_auth" = []), ("api_key" = []))
+)]
+pub struct ApiDoc;
+
+/// Adds the ways callers authenticate, as described in `auth`
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer_auth",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-Api-Key"))),
+        );
+    }
+}
+
 /// Integrate all routes
 pub fn app_routes() -> Router<crate::handlers::grid::AppState> {
     Router::new().merge(health_routes()).merge(rest_routes())
@@ -29,3 +100,20 @@ fn health_routes() -> Router<crate::handlers::grid::AppState> {
 fn rest_routes() -> Router<crate::handlers::grid::AppState> {
     rest::rest_routes()
 }
+
+/// Get the OpenAPI document and Swagger UI routes that `config` enables
+pub fn api_docs_routes(config: &ApiDocsConfig) -> Router<crate::handlers::grid::AppState> {
+    if !config.openapi {
+        return Router::new();
+    }
+    if config.swagger_ui {
+        return SwaggerUi::new(SWAGGER_UI_PATH)
+            .url(OPENAPI_PATH, ApiDoc::openapi())
+            .into();
+    }
+    let document = ApiDoc::openapi();
+    Router::new().route(
+        OPENAPI_PATH,
+        get(move || std::future::ready(Json(document.clone()))),
+    )
+}
@@ -0,0 +1,397 @@
+//! OpenRPC document module
+//!
+//! Describes the JSON-RPC API as an OpenRPC 1.x document, served by the
+//! `rpc.discover` method and at `GET /openrpc.json`. The methods listed are
+//! the ones registered on the RPC module; their param and result schemas come
+//! from the `ToSchema` derives of the types in `rpc_api`.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use crate::handlers::grid::AppState;
+use crate::protos::user::{
+    ApiKeySecret, ListApiKeysResponse, ListSessionsResponse, ListUsersRequest, ListUsersResponse,
+    RevokeApiKeyResponse, RevokeSessionsResponse, SetPasswordResponse, TokenResponse,
+    UnlockLoginResponse, UserUpdate,
+};
+use crate::rpc_api::{
+    ApiKeyParams, ApiKeyQuery, CreateApiKeyParams, RefreshTokenParams, RevokeSessionParams,
+    SessionsParams, SetPasswordParams, SubscribeUserUpdatesParams, UnlockLoginParams,
+    UpdateUserInfoParams, UpdateUserInfoResponse, UserInfo, VerifyCredentialsParams,
+    VerifyCredentialsResponse,
+};
+use axum::{routing::get, Json, Router};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeSet;
+use utoipa::ToSchema;
+
+/// OpenRPC version the document follows
+const OPENRPC_VERSION: &str = "1.3.2";
+/// Name of the discovery method, left out of the document it returns
+pub const DISCOVER_METHOD: &str = "rpc.discover";
+
+/// Schemas shared by the methods, by name
+type Components = Map<String, Value>;
+
+/// Builds a schema, registering the named schemas it refers to
+type SchemaFn = fn(&mut Components) -> Value;
+
+/// Reference to the schema of `T`, adding it and what it uses to `components`
+fn schema<T: ToSchema>(components: &mut Components) -> Value {
+    let mut schemas = vec![(T::name().into_owned(), T::schema())];
+    T::schemas(&mut schemas);
+    for (name, schema) in schemas {
+        components.insert(name, serde_json::to_value(schema).unwrap_or_default());
+    }
+    json!({ "$ref": format!("#/components/schemas/{}", T::name()) })
+}
+
+fn boolean(_: &mut Components) -> Value {
+    json!({ "type": "boolean" })
+}
+
+/// Ids from jsonrpsee's default `RandomIntegerIdProvider`, below 2^53
+fn subscription_id(_: &mut Components) -> Value {
+    json!({ "type": "integer", "minimum": 0 })
+}
+
+/// What the document says about one method
+struct MethodDoc {
+    name: &'static str,
+    summary: &'static str,
+    /// Schema of the single parameter object, and whether it may be left out
+    params: Option<(SchemaFn, bool)>,
+    result: SchemaFn,
+}
+
+/// Every method of `UserRpc`, in the order of the trait
+const METHODS: &[MethodDoc] = &[
+    MethodDoc {
+        name: "get_user_info",
+        summary: "Sample user profile",
+        params: None,
+        result: schema::<UserInfo>,
+    },
+    MethodDoc {
+        name: "update_user_info",
+        summary: "Change the given fields of a user",
+        params: Some((schema::<UpdateUserInfoParams>, true)),
+        result: schema::<UpdateUserInfoResponse>,
+    },
+    MethodDoc {
+        name: "list_users",
+        summary: "One page of users, mirroring the gRPC ListUsers call",
+        params: Some((schema::<ListUsersRequest>, false)),
+        result: schema::<ListUsersResponse>,
+    },
+    MethodDoc {
+        name: "verify_credentials",
+        summary: "Exchange an email and password for a token pair",
+        params: Some((schema::<VerifyCredentialsParams>, true)),
+        result: schema::<VerifyCredentialsResponse>,
+    },
+    MethodDoc {
+        name: "unlock_login",
+        summary: "Lift the login throttling of a username and/or client address",
+        params: Some((schema::<UnlockLoginParams>, true)),
+        result: schema::<UnlockLoginResponse>,
+    },
+    MethodDoc {
+        name: "refresh_token",
+        summary: "Exchange a refresh token for a new token pair",
+        params: Some((schema::<RefreshTokenParams>, true)),
+        result: schema::<TokenResponse>,
+    },
+    MethodDoc {
+        name: "set_password",
+        summary: "Change a user's password",
+        params: Some((schema::<SetPasswordParams>, true)),
+        result: schema::<SetPasswordResponse>,
+    },
+    MethodDoc {
+        name: "list_sessions",
+        summary: "Login sessions of a user, by default the caller",
+        params: Some((schema::<SessionsParams>, false)),
+        result: schema::<ListSessionsResponse>,
+    },
+    MethodDoc {
+        name: "revoke_session",
+        summary: "Revoke one login session",
+        params: Some((schema::<RevokeSessionParams>, true)),
+        result: schema::<RevokeSessionsResponse>,
+    },
+    MethodDoc {
+        name: "revoke_sessions",
+        summary: "Revoke every login session of a user, by default the caller",
+        params: Some((schema::<SessionsParams>, false)),
+        result: schema::<RevokeSessionsResponse>,
+    },
+    MethodDoc {
+        name: "create_api_key",
+        summary: "Issue an API key for the caller, or for another user for admins",
+        params: Some((schema::<CreateApiKeyParams>, true)),
+        result: schema::<ApiKeySecret>,
+    },
+    MethodDoc {
+        name: "list_api_keys",
+        summary: "API keys of a user, by default the caller",
+        params: Some((schema::<ApiKeyQuery>, false)),
+        result: schema::<ListApiKeysResponse>,
+    },
+    MethodDoc {
+        name: "rotate_api_key",
+        summary: "Give an API key a new secret",
+        params: Some((schema::<ApiKeyParams>, true)),
+        result: schema::<ApiKeySecret>,
+    },
+    MethodDoc {
+        name: "revoke_api_key",
+        summary: "Revoke an API key",
+        params: Some((schema::<ApiKeyParams>, true)),
+        result: schema::<RevokeApiKeyResponse>,
+    },
+    MethodDoc {
+        name: "subscribe_user_updates",
+        summary: "Follow changes to one user, or to every user, over WebSocket; \
+                  the result is the subscription id and updates arrive as \
+                  `subscribe_user_updates` notifications",
+        params: Some((schema::<SubscribeUserUpdatesParams>, false)),
+        result: subscription_id,
+    },
+    MethodDoc {
+        name: "unsubscribe_user_updates",
+        summary: "End a subscribe_user_updates subscription",
+        params: Some((subscription_id, true)),
+        result: boolean,
+    },
+];
+
+impl MethodDoc {
+    fn describe(&self, components: &mut Components) -> Value {
+        let params: Vec<Value> = self
+            .params
+            .iter()
+            .map(|(schema, required)| {
+                json!({
+                    "name": "params",
+                    "required": required,
+                    "schema": schema(components),
+                })
+            })
+            .collect();
+        let mut method = json!({
+            "name": self.name,
+            "summary": self.summary,
+            "paramStructure": "by-position",
+            "params": params,
+            "result": { "name": "result", "schema": (self.result)(components) },
+        });
+        if self.name == "subscribe_user_updates" {
+            method["x-notification"] = json!({
+                "name": "subscribe_user_updates",
+                "schema": schema::<UserUpdate>(components),
+            });
+        }
+        method
+    }
+}
+
+/// OpenRPC document of the methods named `registered`
+///
+/// Methods come in the order of `METHODS`; those missing from it are still
+/// listed after them, by name and without schemas.
+pub fn document<'a>(registered: impl IntoIterator<Item = &'a str>) -> Value {
+    let mut registered: BTreeSet<&str> = registered.into_iter().collect();
+    registered.remove(DISCOVER_METHOD);
+
+    let mut components = Components::new();
+    let mut methods: Vec<Value> = METHODS
+        .iter()
+        .filter(|method| registered.remove(method.name))
+        .map(|method| method.describe(&mut components))
+        .collect();
+    methods.extend(registered.into_iter().map(
+        |name| json!({ "name": name, "params": [], "result": { "name": "result", "schema": {} } }),
+    ));
+
+    json!({
+        "openrpc": OPENRPC_VERSION,
+        "info": {
+            "title": "omni-gate JSON-RPC API",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Each method takes at most one parameter object, sent as \
+                            the single positional value or as `params` itself.",
+        },
+        "methods": methods,
+        "components": { "schemas": components },
+    })
+}
+
+/// `GET /openrpc.json`
+pub fn openrpc_routes(document: Value) -> Router<AppState> {
+    Router::new().route(
+        "/openrpc.json",
+        get(move || std::future::ready(Json(document.clone()))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc_api::UserRpcClient;
+    use jsonrpsee::core::client::{Client, Error as ClientError};
+    use jsonrpsee::server::IdProvider;
+    use std::future::Future;
+
+    #[test]
+    fn test_document_describes_registered_methods() {
+        let document = document(["shutdown", "rpc.discover", "verify_credentials"]);
+        assert_eq!(document["openrpc"], OPENRPC_VERSION);
+
+        let methods = document["methods"].as_array().unwrap();
+        assert_eq!(methods.len(), 2);
+        assert_eq!(methods[0]["name"], "verify_credentials");
+        assert_eq!(
+            methods[0]["params"][0]["schema"]["$ref"],
+            "#/components/schemas/VerifyCredentialsParams"
+        );
+        assert_eq!(methods[1]["name"], "shutdown");
+
+        let schemas = &document["components"]["schemas"];
+        let params = &schemas["VerifyCredentialsParams"];
+        assert_eq!(params["required"], json!(["username", "password"]));
+        // Schemas the result refers to come along
+        assert!(schemas["VerifyCredentialsResponse"].is_object());
+        assert!(schemas["TokenResponse"].is_object());
+    }
+
+    /// Param and result of a `UserRpcClient` method as `METHODS` should
+    /// document them: the `$ref` and requiredness of the param, if any, and the
+    /// `$ref` of the result, or of the notifications of a subscription
+    ///
+    /// `call` is never run; it only pins the method's types.
+    fn signature<P, R, F>(_call: impl Fn(Client, P) -> F) -> (Option<(String, bool)>, String)
+    where
+        F: Future<Output = Result<R, ClientError>>,
+    {
+        fn reference(type_name: &str) -> String {
+            let name = type_name.trim_end_matches('>').rsplit("::").next();
+            format!("#/components/schemas/{}", name.unwrap_or_default())
+        }
+        let params = match std::any::type_name::<P>() {
+            "()" => None,
+            name => match name.strip_prefix("core::option::Option<") {
+                Some(inner) => Some((reference(inner), false)),
+                None => Some((reference(name), true)),
+            },
+        };
+        (params, reference(std::any::type_name::<R>()))
+    }
+
+    #[test]
+    fn test_methods_match_the_rpc_trait() {
+        let signatures = [
+            (
+                "get_user_info",
+                signature(|c, (): ()| async move { c.get_user_info().await }),
+            ),
+            (
+                "update_user_info",
+                signature(|c, p| async move { c.update_user_info(p).await }),
+            ),
+            (
+                "list_users",
+                signature(|c, p| async move { c.list_users(p).await }),
+            ),
+            (
+                "verify_credentials",
+                signature(|c, p| async move { c.verify_credentials(p).await }),
+            ),
+            (
+                "unlock_login",
+                signature(|c, p| async move { c.unlock_login(p).await }),
+            ),
+            (
+                "refresh_token",
+                signature(|c, p| async move { c.refresh_token(p).await }),
+            ),
+            (
+                "set_password",
+                signature(|c, p| async move { c.set_password(p).await }),
+            ),
+            (
+                "list_sessions",
+                signature(|c, p| async move { c.list_sessions(p).await }),
+            ),
+            (
+                "revoke_session",
+                signature(|c, p| async move { c.revoke_session(p).await }),
+            ),
+            (
+                "revoke_sessions",
+                signature(|c, p| async move { c.revoke_sessions(p).await }),
+            ),
+            (
+                "create_api_key",
+                signature(|c, p| async move { c.create_api_key(p).await }),
+            ),
+            (
+                "list_api_keys",
+                signature(|c, p| async move { c.list_api_keys(p).await }),
+            ),
+            (
+                "rotate_api_key",
+                signature(|c, p| async move { c.rotate_api_key(p).await }),
+            ),
+            (
+                "revoke_api_key",
+                signature(|c, p| async move { c.revoke_api_key(p).await }),
+            ),
+            (
+                "subscribe_user_updates",
+                signature(|c, p| async move { c.subscribe_user_updates(p).await }),
+            ),
+        ];
+
+        // The unsubscribe method comes with its subscription
+        let covered: BTreeSet<&str> = signatures.iter().map(|(name, _)| *name).collect();
+        for method in METHODS {
+            assert!(
+                covered.contains(method.name) || method.name == "unsubscribe_user_updates",
+                "{} is not checked against the trait",
+                method.name
+            );
+        }
+
+        let mut components = Components::new();
+        for (name, (params, result)) in signatures {
+            let method = METHODS
+                .iter()
+                .find(|method| method.name == name)
+                .unwrap_or_else(|| panic!("{name} is missing from METHODS"))
+                .describe(&mut components);
+            let documented = method["params"].get(0).map(|param| {
+                let reference = param["schema"]["$ref"].as_str().unwrap_or_default();
+                (reference.to_string(), param["required"] == true)
+            });
+            assert_eq!(documented, params, "params of {name}");
+            let documented = match method.get("x-notification") {
+                Some(notification) => &notification["schema"]["$ref"],
+                None => &method["result"]["schema"]["$ref"],
+            };
+            assert_eq!(documented, &result, "result of {name}");
+        }
+    }
+
+    #[test]
+    fn test_subscription_ids_are_documented_as_issued() {
+        let document = document(["subscribe_user_updates", "unsubscribe_user_updates"]);
+        let id = jsonrpsee::server::RandomIntegerIdProvider.next_id();
+        let id = serde_json::to_value(id).unwrap();
+        assert!(id.is_u64());
+        let methods = &document["methods"];
+        assert_eq!(methods[0]["result"]["schema"]["type"], "integer");
+        assert_eq!(methods[1]["params"][0]["schema"]["type"], "integer");
+    }
+}
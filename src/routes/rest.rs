@@ -6,12 +6,46 @@
 //! SPDX-License-Identifier: Apache-2.0
 //! Author: imshike@gmail.com
 
-use crate::handlers::grid::{create, delete_by_id, get_by_id, list, update, AppState};
-use axum::{routing::get, Router};
+use crate::handlers::api_keys::{create_api_key, list_api_keys, revoke_api_key, rotate_api_key};
+use crate::handlers::grid::{
+    create, delete_by_id, events, get_by_id, list, set_grants, update, AppState,
+};
+use crate::handlers::sessions::{list_sessions, revoke_session, revoke_sessions};
+use crate::handlers::users_rest::{
+    create_user, delete_user, get_avatar, get_user, list_users, put_avatar, search_users,
+    update_user,
+};
+use axum::{
+    extract::DefaultBodyLimit,
+    routing::{delete, get, post, put},
+    Router,
+};
 
 pub fn rest_routes() -> Router<AppState> {
-    Router::new().route("/grid", get(list).post(create)).route(
-        "/grid/{id}",
-        get(get_by_id).put(update).delete(delete_by_id),
-    )
+    Router::new()
+        .route("/grid", get(list).post(create))
+        .route("/grid/events", get(events))
+        .route(
+            "/grid/{id}",
+            get(get_by_id).put(update).delete(delete_by_id),
+        )
+        .route("/grid/{id}/grants", put(set_grants))
+        .route("/sessions", get(list_sessions).delete(revoke_sessions))
+        .route("/sessions/{id}", delete(revoke_session))
+        .route("/api-keys", get(list_api_keys).post(create_api_key))
+        .route("/api-keys/{id}", delete(revoke_api_key))
+        .route("/api-keys/{id}/rotate", post(rotate_api_key))
+        .route("/users", get(list_users).post(create_user))
+        .route("/users/search", get(search_users))
+        .route(
+            "/users/{id}",
+            get(get_user).patch(update_user).delete(delete_user),
+        )
+        // The handler enforces `avatars.max_bytes` while reading the upload
+        .route(
+            "/users/{id}/avatar",
+            get(get_avatar)
+                .put(put_avatar)
+                .layer(DefaultBodyLimit::disable()),
+        )
 }
@@ -0,0 +1,236 @@
+//! JSON-RPC API definition
+//!
+//! The methods of the JSON-RPC server as a jsonrpsee `#[rpc]` trait, with the
+//! typed parameters and results of each. The server implements
+//! `UserRpcServer`; clients get a generated `UserRpcClient` for any jsonrpsee
+//! client, such as the ones in `examples/`.
+//!
+//! Every method takes at most one parameter object, sent either by name
+//! (`"params": {...}`) or as a single positional value (`"params": [{...}]`).
+//! Parameters that do not match are rejected with `-32602` (invalid params)
+//! before the method runs.
+//!
+//! The `ToSchema` derives feed the OpenRPC document served by `rpc.discover`.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use crate::protos::user::{
+    ApiKeySecret, ListApiKeysResponse, ListSessionsResponse, ListUsersRequest, ListUsersResponse,
+    RevokeApiKeyResponse, RevokeSessionsResponse, SetPasswordResponse, TokenResponse,
+    UnlockLoginResponse, User, UserUpdate,
+};
+use jsonrpsee::core::{RpcResult, SubscriptionResult};
+use jsonrpsee::proc_macros::rpc;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use utoipa::ToSchema;
+
+/// Result of `get_user_info`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct UserInfo {
+    pub name: String,
+    pub age: i32,
+    pub email: String,
+    pub status: String,
+}
+
+/// Parameters of `update_user_info`; keys left out keep their current value
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct UpdateUserInfoParams {
+    pub user_id: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub age: Option<i32>,
+    /// Setting roles or permissions takes `users:admin`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roles: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<Vec<String>>,
+}
+
+/// Result of `update_user_info`
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UpdateUserInfoResponse {
+    pub success: bool,
+    pub message: String,
+    pub user: User,
+}
+
+/// Parameters of `verify_credentials`; `username` is the user's email
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct VerifyCredentialsParams {
+    pub username: String,
+    pub password: String,
+}
+
+/// Result of `verify_credentials`
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct VerifyCredentialsResponse {
+    pub authenticated: bool,
+    #[serde(flatten)]
+    pub tokens: TokenResponse,
+}
+
+/// Parameters of `unlock_login`; at least one key is required
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct UnlockLoginParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub ip: Option<IpAddr>,
+}
+
+/// Parameters of `refresh_token`
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RefreshTokenParams {
+    pub refresh_token: String,
+}
+
+/// Parameters of `set_password`
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SetPasswordParams {
+    pub user_id: i32,
+    /// Left out by admins resetting another user's password
+    #[serde(default)]
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// Parameters of `list_sessions` and `revoke_sessions`; no `user_id` means the caller
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct SessionsParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<i32>,
+}
+
+/// Parameters of `revoke_session`
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RevokeSessionParams {
+    pub session_id: String,
+}
+
+/// Body of `POST /api-keys` and parameters of `create_api_key`
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct CreateApiKeyParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<i32>,
+    pub name: String,
+    /// Permission names, or `*` for everything the user may do
+    pub scopes: Vec<String>,
+}
+
+/// Query of `GET /api-keys` and parameters of `list_api_keys`
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct ApiKeyQuery {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<i32>,
+}
+
+/// Parameters of `rotate_api_key` and `revoke_api_key`
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ApiKeyParams {
+    pub key_id: String,
+}
+
+/// Parameters of `subscribe_user_updates`
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct SubscribeUserUpdatesParams {
+    /// User to follow; 0 follows every user
+    #[serde(default)]
+    pub user_id: i32,
+    /// Send a heartbeat after this many idle seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval_seconds: Option<u64>,
+}
+
+/// The JSON-RPC API
+#[rpc(server, client)]
+pub trait UserRpc {
+    /// Sample user profile
+    #[method(name = "get_user_info")]
+    async fn get_user_info(&self) -> RpcResult<UserInfo>;
+
+    /// Change the given fields of a user
+    #[method(name = "update_user_info", with_extensions)]
+    async fn update_user_info(
+        &self,
+        params: UpdateUserInfoParams,
+    ) -> RpcResult<UpdateUserInfoResponse>;
+
+    /// One page of users, mirroring the gRPC `ListUsers` call
+    #[method(name = "list_users")]
+    async fn list_users(&self, params: Option<ListUsersRequest>) -> RpcResult<ListUsersResponse>;
+
+    /// Exchange an email and password for a token pair
+    #[method(name = "verify_credentials", with_extensions)]
+    async fn verify_credentials(
+        &self,
+        params: VerifyCredentialsParams,
+    ) -> RpcResult<VerifyCredentialsResponse>;
+
+    /// Lift the login throttling of a username and/or client address
+    #[method(name = "unlock_login")]
+    async fn unlock_login(&self, params: UnlockLoginParams) -> RpcResult<UnlockLoginResponse>;
+
+    /// Exchange a refresh token for a new token pair
+    #[method(name = "refresh_token", with_extensions)]
+    async fn refresh_token(&self, params: RefreshTokenParams) -> RpcResult<TokenResponse>;
+
+    /// Change a user's password
+    #[method(name = "set_password", with_extensions)]
+    async fn set_password(&self, params: SetPasswordParams) -> RpcResult<SetPasswordResponse>;
+
+    /// Login sessions of a user, by default the caller
+    #[method(name = "list_sessions", with_extensions)]
+    async fn list_sessions(
+        &self,
+        params: Option<SessionsParams>,
+    ) -> RpcResult<ListSessionsResponse>;
+
+    /// Revoke one login session
+    #[method(name = "revoke_session", with_extensions)]
+    async fn revoke_session(
+        &self,
+        params: RevokeSessionParams,
+    ) -> RpcResult<RevokeSessionsResponse>;
+
+    /// Revoke every login session of a user, by default the caller
+    #[method(name = "revoke_sessions", with_extensions)]
+    async fn revoke_sessions(
+        &self,
+        params: Option<SessionsParams>,
+    ) -> RpcResult<RevokeSessionsResponse>;
+
+    /// Issue an API key for the caller, or for another user for admins
+    #[method(name = "create_api_key", with_extensions)]
+    async fn create_api_key(&self, params: CreateApiKeyParams) -> RpcResult<ApiKeySecret>;
+
+    /// API keys of a user, by default the caller
+    #[method(name = "list_api_keys", with_extensions)]
+    async fn list_api_keys(&self, params: Option<ApiKeyQuery>) -> RpcResult<ListApiKeysResponse>;
+
+    /// Give an API key a new secret
+    #[method(name = "rotate_api_key", with_extensions)]
+    async fn rotate_api_key(&self, params: ApiKeyParams) -> RpcResult<ApiKeySecret>;
+
+    /// Revoke an API key
+    #[method(name = "revoke_api_key", with_extensions)]
+    async fn revoke_api_key(&self, params: ApiKeyParams) -> RpcResult<RevokeApiKeyResponse>;
+
+    /// Follow changes to one user, or to every user
+    #[subscription(
+        name = "subscribe_user_updates",
+        unsubscribe = "unsubscribe_user_updates",
+        item = UserUpdate
+    )]
+    async fn subscribe_user_updates(
+        &self,
+        params: Option<SubscribeUserUpdatesParams>,
+    ) -> SubscriptionResult;
+}
@@ -6,15 +6,34 @@
 //! SPDX-License-Identifier: Apache-2.0
 //! Author: imshike@gmail.com
 
+use crate::api_keys::ApiKeyStore;
+use crate::auth::{self, Authenticator};
+use crate::avatars::AvatarStore;
 use crate::config::Config;
-use crate::handlers::grid::AppState;
+use crate::errors;
+use crate::events::EventBus;
+use crate::handlers::credentials::Credentials;
+use crate::handlers::grid::{self, AppState};
 use crate::handlers::grpc_helloworld::GreeterService;
 use crate::handlers::grpc_user::UserServiceImpl;
+use crate::handlers::users::UserDirectory;
+use crate::journal::{self, GrpcDurableLayer, Journal, Snapshot};
+use crate::lockout::LoginGuard;
+use crate::multiplex;
 use crate::protos::helloworld::greeter_server::GreeterServer;
 use crate::protos::user::user_service_server::UserServiceServer;
-use jsonrpsee::server::{ServerBuilder, ServerHandle};
-use std::sync::{Arc, RwLock};
+use crate::rbac::{self, GrpcAuthLayer, Roles};
+use crate::request_id::RequestIdLayer;
+use crate::routes::json_rpc::{JsonRpcService, RpcContext};
+use crate::sessions::SessionStore;
+use crate::store::Store;
+use crate::tokens::TokenService;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tonic::service::Routes;
 use tonic::transport::Server;
+use tower::ServiceBuilder;
 use tower_http::cors::CorsLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -36,55 +55,250 @@ pub async fn start_server() -> Result<(), Box<dyn std::error::Error>> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    // Recover persisted state from the snapshot and write-ahead log
+    let (journal, recovered) = Journal::open(&config.storage)?;
+    let grid_items = Arc::new(Store::new());
+    for item in recovered.grid_items {
+        grid_items.insert(item.id, item);
+    }
+    let users = Arc::new(Store::new());
+    for user in recovered.users {
+        users.insert(user.id as u64, user);
+    }
+    let directory = UserDirectory::new(Arc::clone(&users), journal.clone());
+    directory.restore_credentials(recovered.credentials);
+    let api_keys = ApiKeyStore::new(journal.clone());
+    api_keys.restore(recovered.api_keys);
+
+    // Periodically fold the write-ahead log into a snapshot
+    if config.storage.enabled {
+        let journal = journal.clone();
+        let grid_items = Arc::clone(&grid_items);
+        let directory = directory.clone();
+        let api_keys = api_keys.clone();
+        let period = Duration::from_secs(config.storage.compaction_interval_secs.max(1));
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(period);
+            loop {
+                ticker.tick().await;
+                let journal = journal.clone();
+                let grid_items = Arc::clone(&grid_items);
+                let users = Arc::clone(&users);
+                let directory = directory.clone();
+                let api_keys = api_keys.clone();
+                let result = tokio::task::spawn_blocking(move || {
+                    journal.compact(|| Snapshot {
+                        grid_items: grid_items.snapshot(),
+                        users: users.snapshot(),
+                        credentials: directory.credentials_snapshot(),
+                        api_keys: api_keys.snapshot(),
+                    })
+                })
+                .await;
+                if let Ok(Err(e)) = result {
+                    tracing::error!("Journal compaction failed: {}", e);
+                }
+            }
+        });
+    }
+
+    // Sessions live as long as their refresh tokens
+    let sessions = SessionStore::new(config.auth.refresh_token_ttl_secs);
+
+    let tokens = TokenService::new(&config.auth);
+    let guard = LoginGuard::new(config.lockout.clone());
+    let credentials = Credentials::new(
+        directory.clone(),
+        tokens.clone(),
+        sessions.clone(),
+        api_keys.clone(),
+        guard.clone(),
+    );
+
     // Initialize application state
     let state = AppState {
-        grid_items: Arc::new(RwLock::new(Vec::new())),
+        grid_items,
+        journal: journal.clone(),
+        events: EventBus::default(),
+        sessions: sessions.clone(),
+        directory: directory.clone(),
+        api_keys: api_keys.clone(),
+        credentials: credentials.clone(),
+        avatars: AvatarStore::new(&config.avatars),
     };
+    let authenticator = Authenticator::new(
+        tokens,
+        sessions.clone(),
+        api_keys.clone(),
+        directory.clone(),
+        Roles::new(&config.rbac),
+        &config.auth,
+    );
+
+    // End sessions that were not refreshed in time
+    let expired_sessions = sessions.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            ticker.tick().await;
+            let expired = expired_sessions.prune();
+            if expired > 0 {
+                tracing::debug!("Expired {} sessions", expired);
+            }
+        }
+    });
+
+    // Forget stale failed-login records
+    let prune_period = Duration::from_secs(config.lockout.failure_window_secs.max(1));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(prune_period);
+        loop {
+            ticker.tick().await;
+            guard.prune();
+        }
+    });
+
+    // Delete grid items once their TTL has passed
+    let reaper_state = state.clone();
+    let reaper_period = Duration::from_secs(config.grid.reaper_interval_secs.max(1));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(reaper_period);
+        loop {
+            ticker.tick().await;
+            let reaped = grid::reap_expired(&reaper_state);
+            if reaped > 0 {
+                tracing::debug!("Reaped {} expired grid items", reaped);
+            }
+        }
+    });
+
+    // Build the JSON-RPC methods, which the REST API also describes
+    let rpc_module = routes::json_rpc::create_rpc_module(RpcContext {
+        directory: directory.clone(),
+        credentials: credentials.clone(),
+        sessions: sessions.clone(),
+        api_keys: api_keys.clone(),
+    });
+    let openrpc_document = routes::openrpc::document(rpc_module.method_names());
+    let rpc_service = JsonRpcService::new(rpc_module, authenticator.clone(), journal.clone());
+
+    // Build the gRPC services, served on their own port and/or the REST port
+    let grpc_routes = Routes::new(GreeterServer::new(GreeterService))
+        .add_service(UserServiceServer::new(UserServiceImpl::new(
+            directory,
+            credentials,
+            sessions,
+            api_keys,
+        )))
+        .prepare();
 
     // Build application routes
     let app = routes::app_routes()
+        .merge(routes::openrpc::openrpc_routes(openrpc_document))
+        .merge(routes::api_docs_routes(&config.api_docs))
+        .route_layer(axum::middleware::from_fn(rbac::authorize_rest))
         .with_state(state)
-        .layer(CorsLayer::permissive());
+        .layer(axum::middleware::from_fn_with_state(
+            journal.clone(),
+            journal::durable,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            authenticator.clone(),
+            auth::authenticate,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            config.errors.format,
+            errors::error_format,
+        ));
+    // JSON-RPC authenticates its own calls, rejecting bad credentials like `auth`
+    let app = if config.server.jsonrpc_mode.mounted() {
+        tracing::info!("Serving JSON-RPC at /rpc and /rpc/ws on the REST port");
+        app.merge(routes::json_rpc::rpc_routes(rpc_service.clone()))
+    } else {
+        app
+    };
+    let app = app.layer(CorsLayer::permissive()).layer(RequestIdLayer);
+    let app = if config.server.grpc_mode.mounted() {
+        tracing::info!("Serving gRPC on the REST port");
+        let grpc = ServiceBuilder::new()
+            .layer(RequestIdLayer)
+            .layer(GrpcAuthLayer::new(authenticator.clone()))
+            .layer(GrpcDurableLayer::new(journal.clone()))
+            .service(grpc_routes.clone());
+        app.layer(axum::middleware::from_fn_with_state(
+            multiplex::grpc_service(grpc),
+            multiplex::grpc,
+        ))
+    } else {
+        app
+    };
 
     // Get REST server address
     let rest_addr = config.rest_addr()?;
     tracing::info!("Starting REST API server on {}", rest_addr);
 
-    // Get JSON-RPC server address
-    let jsonrpc_addr = config.jsonrpc_addr()?;
-    tracing::info!("Starting JSON-RPC server on {}", jsonrpc_addr);
-
-    // Start JSON-RPC server
-    let jsonrpc_server = tokio::spawn(async move {
-        let server = ServerBuilder::default().build(jsonrpc_addr).await?;
-        let rpc_module = routes::json_rpc::create_rpc_module();
-        let handle: ServerHandle = server.start(rpc_module);
-        handle.stopped().await;
-        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
-    });
+    // Start the standalone JSON-RPC server
+    let jsonrpc_server = if config.server.jsonrpc_mode.standalone() {
+        let jsonrpc_addr = config.jsonrpc_addr()?;
+        tracing::info!("Starting JSON-RPC server on {}", jsonrpc_addr);
+        tokio::spawn(async move {
+            routes::json_rpc::serve(jsonrpc_addr, rpc_service).await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        })
+    } else {
+        tokio::spawn(async { Ok(()) })
+    };
 
     // Start REST API server
     let rest_server = tokio::spawn(async move {
         let listener = tokio::net::TcpListener::bind(rest_addr).await?;
-        axum::serve(listener, app).await?;
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+        )
+        .await?;
         Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
     });
 
-    // Get gRPC server address
-    let grpc_addr = config.grpc_addr()?;
-    let grpc_server = Server::builder()
-        .add_service(GreeterServer::new(GreeterService))
-        .add_service(UserServiceServer::new(UserServiceImpl::default()))
-        .serve(grpc_addr);
-
-    tracing::info!("Starting GRPC server on {}", grpc_addr);
-    let grpc_server = tokio::spawn(async move {
-        grpc_server.await?;
-        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
-    });
+    // Start the standalone gRPC server
+    let grpc_server = if config.server.grpc_mode.standalone() {
+        let grpc_addr = config.grpc_addr()?;
+        tracing::info!("Starting GRPC server on {}", grpc_addr);
+        let grpc_server = Server::builder()
+            .layer(RequestIdLayer)
+            .layer(GrpcAuthLayer::new(authenticator))
+            .layer(GrpcDurableLayer::new(journal))
+            .add_routes(grpc_routes)
+            .serve(grpc_addr);
+        tokio::spawn(async move {
+            grpc_server.await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        })
+    } else {
+        tokio::spawn(async { Ok(()) })
+    };
 
-    // Wait for all servers to complete
-    let _ = tokio::try_join!(jsonrpc_server, rest_server, grpc_server);
+    // Run until every server completes, or stop at the first one that fails
+    tokio::try_join!(
+        finished("JSON-RPC", jsonrpc_server),
+        finished("REST", rest_server),
+        finished("gRPC", grpc_server),
+    )?;
 
     Ok(())
 }
+
+/// Wait for the spawned server `name`, logging why it stopped if it failed
+async fn finished(
+    name: &str,
+    server: JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let result = match server.await {
+        Ok(result) => result,
+        Err(err) => Err(err.into()),
+    };
+    result.map_err(|err| {
+        tracing::error!("{} server stopped: {}", name, err);
+        err as Box<dyn std::error::Error>
+    })
+}
@@ -0,0 +1,315 @@
+//! Session module
+//!
+//! Every login opens a session, and the tokens issued for it name the session
+//! in their `sid` claim. A token only authenticates while its session exists,
+//! so revoking a session signs its holder out of every protocol at once,
+//! however long the tokens themselves are still valid for.
+//!
+//! Each session also hands out `Revocation` futures that resolve once it is
+//! revoked or expires; long-lived streams select on them to close promptly.
+//!
+//! Sessions are kept in memory only: a restart signs everyone out.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use crate::errors::{AppError, ErrorKind};
+use crate::protos::user::Session;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Protocol a request arrived over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Rest,
+    JsonRpc,
+    Grpc,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Rest => "rest",
+            Protocol::JsonRpc => "json-rpc",
+            Protocol::Grpc => "grpc",
+        }
+    }
+}
+
+/// Where a request came from
+#[derive(Debug, Clone, Copy)]
+pub struct ClientInfo {
+    pub addr: Option<IpAddr>,
+    pub protocol: Protocol,
+}
+
+impl ClientInfo {
+    pub fn new(addr: Option<IpAddr>, protocol: Protocol) -> Self {
+        Self { addr, protocol }
+    }
+}
+
+/// Resolves once a session, or an API key, is revoked
+pub struct Revocation(Option<watch::Receiver<bool>>);
+
+impl Revocation {
+    /// A revocation that never happens, for callers without a session
+    pub fn never() -> Self {
+        Revocation(None)
+    }
+
+    /// A revocation once `receiver` turns true or its sender is dropped
+    pub fn watching(receiver: watch::Receiver<bool>) -> Self {
+        Revocation(Some(receiver))
+    }
+
+    pub async fn revoked(self) {
+        match self.0 {
+            // The sender is dropped along with the session
+            Some(mut receiver) => {
+                let _ = receiver.wait_for(|revoked| *revoked).await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+struct Entry {
+    session: Session,
+    revoked: watch::Sender<bool>,
+}
+
+/// Live sessions by id
+#[derive(Clone)]
+pub struct SessionStore {
+    sessions: Arc<DashMap<String, Entry>>,
+    /// Lifetime of a session after its last login or refresh
+    ttl_secs: i64,
+}
+
+impl std::fmt::Debug for SessionStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionStore")
+            .field("sessions", &self.sessions.len())
+            .finish_non_exhaustive()
+    }
+}
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn new_session_id() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+impl SessionStore {
+    pub fn new(ttl_secs: u64) -> Self {
+        Self {
+            sessions: Arc::default(),
+            ttl_secs: i64::try_from(ttl_secs).unwrap_or(i64::MAX),
+        }
+    }
+
+    /// Open a session for a user who just logged in
+    pub fn create(&self, user_id: i32, client: &ClientInfo) -> Session {
+        let now = unix_now();
+        let mut session = Session {
+            id: new_session_id(),
+            user_id,
+            created_at: now,
+            expires_at: now.saturating_add(self.ttl_secs),
+            ..Default::default()
+        };
+        Self::seen(&mut session, client, now);
+        let (revoked, _) = watch::channel(false);
+        self.sessions.insert(
+            session.id.clone(),
+            Entry {
+                session: session.clone(),
+                revoked,
+            },
+        );
+        session
+    }
+
+    /// Record a request made with the session, failing if it is gone
+    pub fn touch(&self, session_id: &str, client: &ClientInfo) -> Result<Session, AppError> {
+        self.seen_live(session_id, client, false)
+    }
+
+    /// Record a token refresh, extending the session
+    pub fn refresh(&self, session_id: &str, client: &ClientInfo) -> Result<Session, AppError> {
+        self.seen_live(session_id, client, true)
+    }
+
+    pub fn get(&self, session_id: &str) -> Option<Session> {
+        self.sessions
+            .get(session_id)
+            .map(|entry| entry.session.clone())
+    }
+
+    /// Sessions of `user_id`, oldest first
+    pub fn list(&self, user_id: i32) -> Vec<Session> {
+        let mut sessions: Vec<_> = self
+            .sessions
+            .iter()
+            .filter(|entry| entry.session.user_id == user_id)
+            .map(|entry| entry.session.clone())
+            .collect();
+        sessions.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        sessions
+    }
+
+    /// End a session, waking everything waiting on its revocation
+    pub fn revoke(&self, session_id: &str) -> Option<Session> {
+        let (_, entry) = self.sessions.remove(session_id)?;
+        entry.revoked.send_replace(true);
+        Some(entry.session)
+    }
+
+    /// End every session of `user_id`, returning how many there were
+    pub fn revoke_user(&self, user_id: i32) -> usize {
+        let ids: Vec<_> = self
+            .sessions
+            .iter()
+            .filter(|entry| entry.session.user_id == user_id)
+            .map(|entry| entry.key().clone())
+            .collect();
+        ids.iter().filter(|id| self.revoke(id).is_some()).count()
+    }
+
+    /// Future resolving when the session ends; at once if it already has
+    pub fn revocation(&self, session_id: &str) -> Revocation {
+        match self.sessions.get(session_id) {
+            Some(entry) => Revocation(Some(entry.revoked.subscribe())),
+            None => Revocation(Some(watch::channel(true).1)),
+        }
+    }
+
+    /// Revoke the sessions that ran out without being refreshed
+    pub fn prune(&self) -> usize {
+        let now = unix_now();
+        let expired: Vec<_> = self
+            .sessions
+            .iter()
+            .filter(|entry| entry.session.expires_at <= now)
+            .map(|entry| entry.key().clone())
+            .collect();
+        expired
+            .iter()
+            .filter(|id| self.revoke(id).is_some())
+            .count()
+    }
+
+    fn seen_live(
+        &self,
+        session_id: &str,
+        client: &ClientInfo,
+        extend: bool,
+    ) -> Result<Session, AppError> {
+        let now = unix_now();
+        let mut entry = self
+            .sessions
+            .get_mut(session_id)
+            .filter(|entry| entry.session.expires_at > now)
+            .ok_or(ErrorKind::Unauthorized)?;
+        Self::seen(&mut entry.session, client, now);
+        if extend {
+            entry.session.expires_at = now.saturating_add(self.ttl_secs);
+        }
+        Ok(entry.session.clone())
+    }
+
+    fn seen(session: &mut Session, client: &ClientInfo, now: i64) {
+        session.last_seen_at = now;
+        session.protocol = client.protocol.as_str().to_string();
+        if let Some(addr) = client.addr {
+            session.client_addr = addr.to_string();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn client(protocol: Protocol) -> ClientInfo {
+        ClientInfo::new(Some(IpAddr::from([10, 0, 0, 1])), protocol)
+    }
+
+    #[test]
+    fn test_touch_records_the_latest_request() {
+        let sessions = SessionStore::new(3600);
+        let session = sessions.create(7, &client(Protocol::JsonRpc));
+        assert_eq!(session.protocol, "json-rpc");
+        assert_eq!(session.client_addr, "10.0.0.1");
+
+        let touched = sessions
+            .touch(&session.id, &client(Protocol::Grpc))
+            .unwrap();
+        assert_eq!(touched.protocol, "grpc");
+        assert_eq!(sessions.list(7), [touched]);
+        assert!(sessions.list(8).is_empty());
+    }
+
+    #[test]
+    fn test_revoked_and_expired_sessions_stop_authenticating() {
+        let sessions = SessionStore::new(3600);
+        let first = sessions.create(7, &client(Protocol::Rest));
+        let second = sessions.create(7, &client(Protocol::Rest));
+        let other = sessions.create(8, &client(Protocol::Rest));
+
+        assert!(sessions.revoke(&first.id).is_some());
+        assert!(sessions.touch(&first.id, &client(Protocol::Rest)).is_err());
+        assert!(sessions.touch(&second.id, &client(Protocol::Rest)).is_ok());
+
+        assert_eq!(sessions.revoke_user(7), 1);
+        assert!(sessions.touch(&second.id, &client(Protocol::Rest)).is_err());
+        assert!(sessions.touch(&other.id, &client(Protocol::Rest)).is_ok());
+
+        let expired = SessionStore::new(0);
+        let session = expired.create(7, &client(Protocol::Rest));
+        assert!(expired.touch(&session.id, &client(Protocol::Rest)).is_err());
+        assert_eq!(expired.prune(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_revocation_wakes_waiters() {
+        let sessions = SessionStore::new(3600);
+        let session = sessions.create(7, &client(Protocol::Grpc));
+
+        let waiter = tokio::spawn(sessions.revocation(&session.id).revoked());
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+
+        sessions.revoke(&session.id);
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("revocation resolves")
+            .unwrap();
+
+        // Waiting on a session that is already gone returns at once
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            sessions.revocation(&session.id).revoked(),
+        )
+        .await
+        .unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), Revocation::never().revoked())
+                .await
+                .is_err()
+        );
+    }
+}
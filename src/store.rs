@@ -0,0 +1,229 @@
+//! Concurrent in-memory store module
+//!
+//! Keyed record storage shared by the protocol handlers. Records live in a
+//! sharded map so readers never wait on writers touching other shards, and
+//! locks are never poisoned: a panicking handler cannot take the store down.
+//!
+//! Every mutation takes a fallible closure that runs while the record's shard
+//! is write-locked. Callers use it to check preconditions and journal the
+//! change before it becomes visible; an `Err` leaves the record untouched.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Sharded map of records keyed by a store-assigned numeric id
+#[derive(Debug)]
+pub struct Store<V> {
+    entries: DashMap<u64, V>,
+    next_id: AtomicU64,
+}
+
+impl<V> Default for Store<V> {
+    fn default() -> Self {
+        Self {
+            entries: DashMap::new(),
+            next_id: AtomicU64::new(1),
+        }
+    }
+}
+
+impl<V: Clone> Store<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert a record under a known id, e.g. when restoring persisted state
+    ///
+    /// Later allocations continue after the highest id seen.
+    pub fn insert(&self, id: u64, value: V) {
+        self.next_id.fetch_max(id + 1, Ordering::Relaxed);
+        self.entries.insert(id, value);
+    }
+
+    /// Allocate a fresh id, build the record for it and insert it
+    pub fn insert_with<E>(&self, build: impl FnOnce(u64) -> Result<V, E>) -> Result<V, E> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        match self.entries.entry(id) {
+            Entry::Vacant(entry) => {
+                let value = build(id)?;
+                entry.insert(value.clone());
+                Ok(value)
+            }
+            Entry::Occupied(_) => unreachable!("store id {id} allocated twice"),
+        }
+    }
+
+    /// Clone the record stored under `id`
+    pub fn get(&self, id: u64) -> Option<V> {
+        self.entries.get(&id).map(|entry| entry.value().clone())
+    }
+
+    /// Mutate the record stored under `id`, returning the closure's result
+    ///
+    /// The closure works on a copy that replaces the stored record only if it
+    /// returns `Ok`. Only the shard holding `id` is locked, and only for the
+    /// duration of `f`.
+    pub fn update<R, E>(
+        &self,
+        id: u64,
+        f: impl FnOnce(&mut V) -> Result<R, E>,
+    ) -> Option<Result<R, E>> {
+        self.entries.get_mut(&id).map(|mut entry| {
+            let mut value = entry.value().clone();
+            let result = f(&mut value)?;
+            *entry.value_mut() = value;
+            Ok(result)
+        })
+    }
+
+    /// Remove and return the record stored under `id` if `check` accepts it
+    pub fn remove<E>(
+        &self,
+        id: u64,
+        check: impl FnOnce(&V) -> Result<(), E>,
+    ) -> Option<Result<V, E>> {
+        match self.entries.entry(id) {
+            Entry::Occupied(entry) => Some(check(entry.get()).map(|_| entry.remove())),
+            Entry::Vacant(_) => None,
+        }
+    }
+
+    /// Point-in-time copy of every record, ordered by id
+    pub fn snapshot(&self) -> Vec<V> {
+        let mut entries: Vec<(u64, V)> = self
+            .entries
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().clone()))
+            .collect();
+        entries.sort_unstable_by_key(|(id, _)| *id);
+        entries.into_iter().map(|(_, value)| value).collect()
+    }
+
+    /// Up to `limit` records with ids greater than `after`, ordered by id, and
+    /// the cursor to pass as `after` for the next page
+    ///
+    /// Lets callers walk the whole store page by page while holding only one
+    /// page in memory. Ids are looked up in order, so a full walk costs one
+    /// lookup per id ever allocated, however it is paged. The cursor is the
+    /// last id looked up, or `None` once no higher id has been allocated.
+    /// Records removed mid-walk are skipped without cutting the walk short.
+    pub fn page_after(&self, after: u64, limit: usize) -> (Vec<(u64, V)>, Option<u64>) {
+        let end = self.next_id.load(Ordering::Relaxed);
+        let mut page = Vec::with_capacity(limit.min(1024));
+        let mut id = after;
+        while page.len() < limit && id + 1 < end {
+            id += 1;
+            if let Some(value) = self.get(id) {
+                page.push((id, value));
+            }
+        }
+        (page, (id + 1 < end).then_some(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_store_assigns_increasing_ids() {
+        let store = Store::new();
+        let first = store.insert_with(Ok::<_, Infallible>).unwrap();
+        let second = store.insert_with(Ok::<_, Infallible>).unwrap();
+        assert_eq!((first, second), (1, 2));
+
+        store.insert(10, 10);
+        let next = store.insert_with(Ok::<_, Infallible>).unwrap();
+        assert_eq!(next, 11);
+        assert_eq!(store.snapshot(), vec![1, 2, 10, 11]);
+    }
+
+    #[test]
+    fn test_store_update_and_remove() {
+        let store = Store::new();
+        let id = store
+            .insert_with(|id| Ok::<_, Infallible>((id, "a".to_string())))
+            .unwrap()
+            .0;
+
+        let updated = store.update(id, |value| {
+            value.1.push('b');
+            Ok::<_, Infallible>(value.1.clone())
+        });
+        assert_eq!(updated.unwrap().unwrap(), "ab");
+        assert!(store.update(id + 1, |_| Ok::<_, Infallible>(())).is_none());
+
+        let removed = store.remove(id, |_| Ok::<_, Infallible>(()));
+        assert_eq!(removed.unwrap().unwrap().1, "ab");
+        assert!(store.get(id).is_none());
+    }
+
+    #[test]
+    fn test_store_rejected_mutations_leave_record_untouched() {
+        let store = Store::new();
+        let id = store.insert_with(Ok::<_, ()>).unwrap();
+
+        let result = store.update(id, |value| {
+            *value = 99;
+            Err::<(), _>("journal unavailable")
+        });
+        assert_eq!(result, Some(Err("journal unavailable")));
+        assert_eq!(store.get(id), Some(id));
+
+        assert_eq!(store.remove(id, |_| Err("denied")), Some(Err("denied")));
+        assert_eq!(store.get(id), Some(id));
+
+        assert_eq!(
+            store.insert_with(|_| Err::<u64, _>("denied")),
+            Err("denied")
+        );
+        assert_eq!(store.snapshot(), vec![id]);
+    }
+
+    #[test]
+    fn test_store_pages_in_id_order() {
+        let store = Store::new();
+        for id in [5, 1, 9, 3, 7] {
+            store.insert(id, id * 10);
+        }
+
+        assert_eq!(store.page_after(0, 2), (vec![(1, 10), (3, 30)], Some(3)));
+        assert_eq!(store.page_after(3, 2), (vec![(5, 50), (7, 70)], Some(7)));
+        assert_eq!(store.page_after(7, 2), (vec![(9, 90)], None));
+        assert_eq!(store.page_after(9, 2), (Vec::new(), None));
+
+        // A record removed mid-walk does not end it early
+        store.remove(5, |_| Ok::<_, Infallible>(()));
+        assert_eq!(store.page_after(3, 2), (vec![(7, 70), (9, 90)], None));
+        assert_eq!(
+            store.page_after(0, 3),
+            (vec![(1, 10), (3, 30), (7, 70)], Some(7))
+        );
+    }
+
+    #[test]
+    fn test_store_survives_panicking_writer() {
+        let store = Arc::new(Store::new());
+        let id = store.insert_with(Ok::<_, Infallible>).unwrap();
+
+        let panicking = Arc::clone(&store);
+        let result = std::thread::spawn(move || {
+            panicking.update(id, |_| -> Result<(), Infallible> { panic!("handler bug") });
+        })
+        .join();
+        assert!(result.is_err());
+
+        assert_eq!(store.get(id), Some(id));
+        let incremented = store.update(id, |value| {
+            *value += 1;
+            Ok::<_, Infallible>(*value)
+        });
+        assert_eq!(incremented, Some(Ok(id + 1)));
+    }
+}
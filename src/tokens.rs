@@ -0,0 +1,224 @@
+//! Token module
+//!
+//! Issues and verifies the HS256-signed JWTs handed out at login. An access
+//! token authenticates API calls; a refresh token can only be exchanged for a
+//! new token pair. The `token_use` claim keeps one from being used as the other,
+//! and the `sid` claim ties both to the login session they were issued for.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use crate::config::AuthConfig;
+use crate::errors::{AppError, ErrorKind};
+use crate::protos::user::TokenResponse;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// What a token may be used for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenUse {
+    Access,
+    Refresh,
+}
+
+/// Claims carried by every issued token
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Claims {
+    /// Id of the user the token was issued to
+    pub sub: String,
+    pub iss: String,
+    pub aud: String,
+    pub iat: u64,
+    pub exp: u64,
+    pub token_use: TokenUse,
+    /// Session the token belongs to
+    pub sid: String,
+}
+
+/// Signs and verifies tokens with the configured key and claims
+#[derive(Clone)]
+pub struct TokenService {
+    inner: Arc<Keys>,
+}
+
+struct Keys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    validation: Validation,
+    issuer: String,
+    audience: String,
+    access_ttl: u64,
+    refresh_ttl: u64,
+}
+
+impl std::fmt::Debug for TokenService {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenService")
+            .field("issuer", &self.inner.issuer)
+            .field("audience", &self.inner.audience)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TokenService {
+    pub fn new(config: &AuthConfig) -> Self {
+        let secret = if config.jwt_secret.is_empty() {
+            tracing::warn!("No auth.jwt_secret configured; tokens will not survive a restart");
+            let mut secret = vec![0; 32];
+            OsRng.fill_bytes(&mut secret);
+            secret
+        } else {
+            config.jwt_secret.as_bytes().to_vec()
+        };
+
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&[&config.jwt_issuer]);
+        validation.set_audience(&[&config.jwt_audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        Self {
+            inner: Arc::new(Keys {
+                encoding: EncodingKey::from_secret(&secret),
+                decoding: DecodingKey::from_secret(&secret),
+                validation,
+                issuer: config.jwt_issuer.clone(),
+                audience: config.jwt_audience.clone(),
+                access_ttl: config.access_token_ttl_secs,
+                refresh_ttl: config.refresh_token_ttl_secs,
+            }),
+        }
+    }
+
+    /// Issue an access and refresh token for `subject` in session `session_id`
+    pub fn issue(&self, subject: &str, session_id: &str) -> Result<TokenResponse, AppError> {
+        Ok(TokenResponse {
+            access_token: self.sign(
+                subject,
+                session_id,
+                TokenUse::Access,
+                self.inner.access_ttl,
+            )?,
+            refresh_token: self.sign(
+                subject,
+                session_id,
+                TokenUse::Refresh,
+                self.inner.refresh_ttl,
+            )?,
+            token_type: "Bearer".to_string(),
+            expires_in: self.inner.access_ttl as i64,
+        })
+    }
+
+    /// Check the signature, expiry, issuer, audience and use of `token`
+    pub fn verify(&self, token: &str, expected: TokenUse) -> Result<Claims, AppError> {
+        let claims = decode::<Claims>(token, &self.inner.decoding, &self.inner.validation)
+            .map_err(|_| ErrorKind::Unauthorized)?
+            .claims;
+        if claims.token_use != expected {
+            return Err(ErrorKind::Unauthorized.into());
+        }
+        Ok(claims)
+    }
+
+    fn sign(
+        &self,
+        subject: &str,
+        session_id: &str,
+        token_use: TokenUse,
+        ttl: u64,
+    ) -> Result<String, AppError> {
+        let now = jsonwebtoken::get_current_timestamp();
+        let claims = Claims {
+            sub: subject.to_string(),
+            iss: self.inner.issuer.clone(),
+            aud: self.inner.audience.clone(),
+            iat: now,
+            exp: now.saturating_add(ttl),
+            token_use,
+            sid: session_id.to_string(),
+        };
+        encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &self.inner.encoding,
+        )
+        .map_err(AppError::internal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AuthConfig {
+        AuthConfig {
+            jwt_secret: "test-secret".to_string(),
+            ..AuthConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_issued_tokens_verify_for_their_use_only() {
+        let tokens = TokenService::new(&config());
+        let pair = tokens.issue("42", "s1").unwrap();
+
+        let claims = tokens.verify(&pair.access_token, TokenUse::Access).unwrap();
+        assert_eq!(claims.sub, "42");
+        assert_eq!(claims.sid, "s1");
+        assert!(tokens
+            .verify(&pair.refresh_token, TokenUse::Refresh)
+            .is_ok());
+
+        assert!(matches!(
+            tokens
+                .verify(&pair.refresh_token, TokenUse::Access)
+                .map_err(AppError::into_kind),
+            Err(ErrorKind::Unauthorized)
+        ));
+        assert!(matches!(
+            tokens
+                .verify(&pair.access_token, TokenUse::Refresh)
+                .map_err(AppError::into_kind),
+            Err(ErrorKind::Unauthorized)
+        ));
+    }
+
+    #[test]
+    fn test_tokens_from_other_issuers_are_rejected() {
+        let pair = TokenService::new(&config()).issue("42", "s1").unwrap();
+
+        let other_key = TokenService::new(&AuthConfig {
+            jwt_secret: "another-secret".to_string(),
+            ..config()
+        });
+        let other_audience = TokenService::new(&AuthConfig {
+            jwt_audience: "someone-else".to_string(),
+            ..config()
+        });
+        for tokens in [other_key, other_audience] {
+            assert!(tokens.verify(&pair.access_token, TokenUse::Access).is_err());
+        }
+    }
+
+    #[test]
+    fn test_expired_tokens_are_rejected() {
+        let tokens = TokenService::new(&config());
+        let now = jsonwebtoken::get_current_timestamp();
+        let expired = Claims {
+            sub: "42".to_string(),
+            iss: "omni-gate".to_string(),
+            aud: "omni-gate".to_string(),
+            iat: now - 3600,
+            exp: now - 600,
+            token_use: TokenUse::Access,
+            sid: "s1".to_string(),
+        };
+        let token = encode(&Header::default(), &expired, &tokens.inner.encoding).unwrap();
+
+        assert!(tokens.verify(&token, TokenUse::Access).is_err());
+    }
+}
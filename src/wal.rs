@@ -0,0 +1,279 @@
+//! Write-ahead log module
+//!
+//! Append-only file of checksummed records. Each record is framed as
+//! `len: u32 LE | crc32: u32 LE | payload[len]`, the checksum covering the
+//! length bytes and the payload. Opening a log validates every frame. A crash
+//! mid-write can only tear the last frame, so a final frame that is short or
+//! fails its checksum is dropped and the file truncated there. A bad frame with
+//! data after it, including an intact frame behind a damaged length, means the
+//! log itself is damaged, and opening fails rather than silently losing the
+//! records behind it.
+//!
+//! Appending only writes; `sync` makes the records durable.
+//!
+//! Copyright © 2025 imshike@gmail.com
+//! SPDX-License-Identifier: Apache-2.0
+//! Author: imshike@gmail.com
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+const HEADER_LEN: usize = 8;
+
+/// Upper bound on a single record, anything larger is treated as corruption
+const MAX_RECORD_LEN: usize = 64 * 1024 * 1024;
+
+/// Open append-only log file
+#[derive(Debug)]
+pub struct Wal {
+    file: File,
+    path: PathBuf,
+}
+
+impl Wal {
+    /// Open or create the log at `path`, returning it with every intact record payload
+    pub fn open(path: impl AsRef<Path>) -> io::Result<(Self, Vec<Vec<u8>>)> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let (records, valid_len) = parse(&path, &bytes)?;
+
+        if valid_len < bytes.len() {
+            tracing::warn!(
+                "Truncating torn tail of {}: discarding {} of {} bytes",
+                path.display(),
+                bytes.len() - valid_len,
+                bytes.len()
+            );
+            file.set_len(valid_len as u64)?;
+            file.sync_all()?;
+        }
+
+        Ok((Self { file, path }, records))
+    }
+
+    /// Read the intact records of the log at `path` without opening it for writing
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<Vec<u8>>> {
+        let bytes = std::fs::read(&path)?;
+        Ok(parse(path.as_ref(), &bytes)?.0)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Another handle to the log file, for syncing it without holding the log
+    pub fn handle(&self) -> io::Result<File> {
+        self.file.try_clone()
+    }
+
+    /// Flush every appended record to disk
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Append one record; it is only durable once the log is synced
+    pub fn append(&mut self, payload: &[u8]) -> io::Result<()> {
+        if payload.len() > MAX_RECORD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("WAL record of {} bytes exceeds limit", payload.len()),
+            ));
+        }
+
+        let len = (payload.len() as u32).to_le_bytes();
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.extend_from_slice(&len);
+        frame.extend_from_slice(&checksum(&len, payload).to_le_bytes());
+        frame.extend_from_slice(payload);
+
+        self.file.write_all(&frame)
+    }
+}
+
+/// Checksum of a frame, over its length bytes and payload
+fn checksum(len: &[u8], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(len);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// Decode the frame at the start of `bytes`, if it is whole and intact
+fn frame(bytes: &[u8]) -> Option<&[u8]> {
+    let header = bytes.get(..HEADER_LEN)?;
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let expected = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if len > MAX_RECORD_LEN {
+        return None;
+    }
+    let payload = bytes.get(HEADER_LEN..HEADER_LEN + len)?;
+    (checksum(&header[0..4], payload) == expected).then_some(payload)
+}
+
+/// Error for the corrupt frame at `offset` of the log at `path`, `file_len` bytes long
+fn corrupt(path: &Path, offset: usize, file_len: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "corrupt record at byte {} of {}, which is {} bytes long",
+            offset,
+            path.display(),
+            file_len
+        ),
+    )
+}
+
+/// Split the log at `path`, read into `bytes`, into record payloads, returning
+/// them with the length of the valid prefix
+///
+/// Fails if a frame that is not the last one is corrupt.
+fn parse(path: &Path, bytes: &[u8]) -> io::Result<(Vec<Vec<u8>>, usize)> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+
+    while bytes.len() - offset >= HEADER_LEN {
+        let Some(payload) = frame(&bytes[offset..]) else {
+            let len = u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+            let end = offset.saturating_add(HEADER_LEN).saturating_add(len);
+            // A frame ending before the file does has data after it. One that
+            // claims to run past the end may be torn, or may have a damaged
+            // length hiding the frames behind it; look for one.
+            let torn = end >= bytes.len()
+                && !(offset + 1..bytes.len()).any(|at| frame(&bytes[at..]).is_some());
+            if !torn {
+                return Err(corrupt(path, offset, bytes.len()));
+            }
+            break;
+        };
+
+        records.push(payload.to_vec());
+        offset += HEADER_LEN + payload.len();
+    }
+
+    Ok((records, offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("omni-gate-wal-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wal.log");
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_wal_round_trip() {
+        let path = temp_path("round-trip");
+        {
+            let (mut wal, records) = Wal::open(&path).unwrap();
+            assert!(records.is_empty());
+            wal.append(b"first").unwrap();
+            wal.append(b"second").unwrap();
+        }
+
+        let (_, records) = Wal::open(&path).unwrap();
+        assert_eq!(records, vec![b"first".to_vec(), b"second".to_vec()]);
+    }
+
+    #[test]
+    fn test_wal_truncates_torn_tail() {
+        let path = temp_path("torn-tail");
+        {
+            let (mut wal, _) = Wal::open(&path).unwrap();
+            wal.append(b"kept").unwrap();
+            wal.append(b"torn record").unwrap();
+        }
+        let full_len = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(full_len - 3).unwrap();
+
+        let (mut wal, records) = Wal::open(&path).unwrap();
+        assert_eq!(records, vec![b"kept".to_vec()]);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            (HEADER_LEN + 4) as u64
+        );
+
+        wal.append(b"after").unwrap();
+        assert_eq!(
+            Wal::read(&path).unwrap(),
+            vec![b"kept".to_vec(), b"after".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_wal_stops_at_checksum_mismatch() {
+        let path = temp_path("checksum");
+        {
+            let (mut wal, _) = Wal::open(&path).unwrap();
+            wal.append(b"good").unwrap();
+            wal.append(b"flipped").unwrap();
+        }
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let (_, records) = Wal::open(&path).unwrap();
+        assert_eq!(records, vec![b"good".to_vec()]);
+    }
+
+    #[test]
+    fn test_wal_refuses_corruption_before_its_last_record() {
+        let path = temp_path("corrupt");
+        {
+            let (mut wal, _) = Wal::open(&path).unwrap();
+            wal.append(b"flipped").unwrap();
+            wal.append(b"behind it").unwrap();
+        }
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[HEADER_LEN] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let err = Wal::open(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            Wal::read(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        // Nothing was truncated
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_wal_refuses_a_damaged_length_before_its_last_record() {
+        let path = temp_path("length");
+        {
+            let (mut wal, _) = Wal::open(&path).unwrap();
+            wal.append(b"first").unwrap();
+            wal.append(b"middle").unwrap();
+            wal.append(b"last").unwrap();
+        }
+        let original = std::fs::read(&path).unwrap();
+        let middle = HEADER_LEN + b"first".len();
+
+        // Longer or shorter, oversized, or running past the end of the file
+        for (byte, flip) in [(0, 0x01), (0, 0x80), (3, 0x80), (1, 0x01)] {
+            let mut bytes = original.clone();
+            bytes[middle + byte] ^= flip;
+            std::fs::write(&path, &bytes).unwrap();
+
+            let err = Wal::open(&path).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{byte}: {flip:#x}");
+            assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        }
+    }
+}